@@ -0,0 +1,13 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use tcpwarp::TcpWarpPortConnection;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(s) = std::str::from_utf8(data) {
+        if let Ok(connection) = s.parse::<TcpWarpPortConnection>() {
+            let reparsed: TcpWarpPortConnection = connection.to_string().parse().unwrap();
+            assert_eq!(connection, reparsed);
+            connection.expand();
+        }
+    }
+});
@@ -0,0 +1,22 @@
+#![no_main]
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use tcpwarp::{TcpWarpMessage, TcpWarpProto};
+use tokio_util::codec::{Decoder, Encoder};
+
+fuzz_target!(|data: &[u8]| {
+    // first byte selects capabilities agreed in handshake
+    let (capabilities, data) = match data.split_first() {
+        Some((capabilities, data)) => (u32::from(*capabilities), data),
+        None => return,
+    };
+    let mut codec = TcpWarpProto::new().with_max_frame_size(64 * 1024);
+    let mut buffer = BytesMut::new();
+    codec
+        .encode(TcpWarpMessage::Hello { capabilities }, &mut buffer)
+        .unwrap();
+    codec.decode(&mut buffer).unwrap();
+
+    let mut buffer = BytesMut::from(data);
+    while let Ok(Some(_)) = codec.decode(&mut buffer) {}
+});
@@ -1,5 +1,11 @@
+use std::path::PathBuf;
 use structopt::StructOpt;
+use tcpwarp::TcpWarpAuditLog;
+use tcpwarp::TcpWarpCompression;
 use tcpwarp::TcpWarpPortConnection;
+use tcpwarp::TcpWarpService;
+
+use crate::logging::LogFormat;
 
 /// An utility to create userspace tunnel between two hosts
 /// mapping ports on client machine to hosts and ports
@@ -8,6 +14,14 @@ use tcpwarp::TcpWarpPortConnection;
 pub struct Cli {
     #[structopt(flatten)]
     pub verbose: clap_verbosity_flag::Verbosity,
+    /// Format of log records: text or json
+    ///
+    /// JSON records have event, connection_id, mapping and peer fields, payloads are never
+    /// logged in either format.
+    ///
+    /// Default: text
+    #[structopt(long, global = true)]
+    pub log_format: Option<LogFormat>,
     #[structopt(flatten)]
     pub command: Command,
 }
@@ -18,7 +32,7 @@ pub enum Command {
     ///
     /// Runs on machine, to which ports are mapped.
     Client {
-        /// Address to bind
+        /// Address to bind, used for mappings without own bind address
         ///
         /// Format: IP
         ///
@@ -38,9 +52,38 @@ pub enum Command {
         tunnel: Option<String>,
         /// Connections
         ///
-        /// Format: [client_port:][host:]host_port
+        /// Format: [udp:|tcp:][[bind_address:]client_port:][host:]host_port
         ///
         /// Example: --connection 8080 --connection 18081:8081 --connection 18082:127.0.0.1:8082
+        ///
+        /// Bind address overrides --bind for single mapping, IPv6 addresses are put in brackets:
+        /// --connection 127.0.0.1:8080:80 --connection [::1]:8080:[::1]:80
+        ///
+        /// Port ranges of the same length are expanded into multiple mappings:
+        /// --connection 9000-9010:broker:9000-9010
+        ///
+        /// UDP example: --connection udp:5353:dns:53
+        ///
+        /// Unix domain socket paths start with / or . and can be used instead of ports:
+        /// --connection 2375:/var/run/docker.sock --connection /tmp/docker.sock:/var/run/docker.sock
+        ///
+        /// Services from server catalog are referred by name, client port defaults to service port:
+        /// --connection 8080:whoami --connection whoami
+        ///
+        /// Compression is disabled for single mapping with ,nocompress suffix:
+        /// --connection 8443:web:443,nocompress
+        ///
+        /// Connections are closed after idle or lifetime seconds with ,idle=SECS and
+        /// ,lifetime=SECS suffixes: --connection 5432:db:5432,idle=300,lifetime=3600
+        ///
+        /// Concurrent connections of mapping are limited with ,max=N suffix, connections over
+        /// limit are rejected or wait for free slot with ,queue: --connection 8080:whoami:80,max=10,queue
+        ///
+        /// Bytes per second in each direction of all connections of mapping are limited with
+        /// ,rate=N suffix: --connection 5000:registry:5000,rate=1048576
+        ///
+        /// Connections of mapping share tunnel by priority set with ,priority=interactive, normal
+        /// or bulk suffix: --connection 2222:bastion:22,priority=interactive
         #[structopt(long, short)]
         connection: Vec<TcpWarpPortConnection>,
         /// Retry connection on failure or disconnect
@@ -51,9 +94,64 @@ pub enum Command {
         /// Default: 5 secs
         #[structopt(long)]
         retry_interval: Option<u64>,
+        /// Interval of retrying listeners which failed to bind in seconds
+        ///
+        /// Other mappings keep serving meanwhile.
+        ///
+        /// Default: 5 secs
+        #[structopt(long)]
+        listener_retry_interval: Option<u64>,
         /// Keep connections between reconnect attempts
         #[structopt(long)]
         keep_connections: bool,
+        /// Number of parallel tunnel connections to the server
+        ///
+        /// Connections are spread across tunnels for throughput.
+        ///
+        /// Default: 1
+        #[structopt(long)]
+        tunnels: Option<usize>,
+        /// Print addresses of bound listeners as JSON to stdout
+        ///
+        /// Useful with client port 0, which lets OS choose free port:
+        /// --connection 0:whoami:80 --addresses-json
+        #[structopt(long)]
+        addresses_json: bool,
+        /// Bind all services advertised by server
+        ///
+        /// Each service is bound to client port equal to its port on server.
+        #[structopt(long)]
+        all_services: bool,
+        /// Write addresses of bound listeners as JSON to file
+        ///
+        /// File is rewritten every time listeners are bound.
+        ///
+        /// Example: --addresses-file ports.json
+        #[structopt(long, parse(from_os_str))]
+        addresses_file: Option<PathBuf>,
+        /// Compression of tunnel traffic, used if server supports it
+        ///
+        /// Format: none|deflate|zstd
+        ///
+        /// Example: --compression zstd
+        ///
+        /// Default: none
+        #[structopt(long)]
+        compression: Option<TcpWarpCompression>,
+        /// Maximal size of frame accepted from server in bytes
+        ///
+        /// Larger frames are treated as protocol error and close the tunnel.
+        ///
+        /// Default: 16777216
+        #[structopt(long)]
+        max_frame_size: Option<usize>,
+        /// Time to wait for server to connect target in seconds
+        ///
+        /// Local connection is closed if server does not answer in time.
+        ///
+        /// Default: 30 secs
+        #[structopt(long)]
+        connect_timeout: Option<u64>,
     },
     /// Server mode.
     ///
@@ -77,5 +175,83 @@ pub enum Command {
         ///
         /// Default: 0.0.0.0:18000
         listen: Option<String>,
+        /// UDP association idle timeout in seconds
+        ///
+        /// Default: 60 secs
+        #[structopt(long)]
+        udp_idle_timeout: Option<u64>,
+        /// Named services advertised to clients
+        ///
+        /// Format: NAME=[HOST:]PORT
+        ///
+        /// Example: --service whoami=whoami:80 --service dns=[::1]:53
+        #[structopt(long, short)]
+        service: Vec<TcpWarpService>,
+        /// Unix socket or directory of unix sockets clients may connect to
+        ///
+        /// Example: --allow-unix-socket /var/run/docker.sock
+        ///
+        /// Default: none
+        #[structopt(long, parse(from_os_str))]
+        allow_unix_socket: Vec<PathBuf>,
+        /// Refuse compression of tunnel traffic requested by clients
+        #[structopt(long)]
+        no_compression: bool,
+        /// Maximal size of frame accepted from clients in bytes
+        ///
+        /// Larger frames are treated as protocol error and close the tunnel.
+        ///
+        /// Default: 16777216
+        #[structopt(long)]
+        max_frame_size: Option<usize>,
+        /// Timeout of connect to target in seconds
+        ///
+        /// Client is told that connect timed out.
+        ///
+        /// Default: 10 secs
+        #[structopt(long)]
+        connect_timeout: Option<u64>,
+        /// Close connections without bytes in either direction after seconds
+        ///
+        /// Default: unlimited
+        #[structopt(long)]
+        idle_timeout: Option<u64>,
+        /// Close connections open longer than seconds
+        ///
+        /// Default: unlimited
+        #[structopt(long)]
+        max_lifetime: Option<u64>,
+        /// Maximal number of concurrent connections of all clients
+        ///
+        /// Connects over limit fail with limit exceeded reason.
+        ///
+        /// Default: unlimited
+        #[structopt(long)]
+        max_connections: Option<usize>,
+        /// Maximal number of concurrent connections of single tunnel
+        ///
+        /// Default: unlimited
+        #[structopt(long)]
+        max_tunnel_connections: Option<usize>,
+        /// Maximal number of concurrent connections of all tunnels from one client IP address
+        ///
+        /// Default: unlimited
+        #[structopt(long)]
+        max_client_connections: Option<usize>,
+        /// Bytes per second of single tunnel in each direction
+        ///
+        /// Default: unlimited
+        #[structopt(long)]
+        tunnel_rate: Option<u64>,
+        /// Bytes per second of all tunnels from one client IP address in each direction
+        ///
+        /// Default: unlimited
+        #[structopt(long)]
+        client_rate: Option<u64>,
+        /// Writes JSON record of every connection to file or to local syslog with syslog value
+        ///
+        /// Default: disabled
+        #[structopt(long)]
+        audit_log: Option<TcpWarpAuditLog>,
     },
 }
@@ -0,0 +1,216 @@
+use env_logger::Builder as LoggerBuilder;
+use log::{log, warn, Level, LevelFilter, Record, SetLoggerError};
+use std::{cell::RefCell, fmt, io::Write, str::FromStr};
+use tcpwarp::TcpWarpEvent;
+use tokio::sync::broadcast::{self, RecvError};
+
+/// Target of log records of client and server events.
+const EVENTS_TARGET: &str = "tcpwarp::events";
+
+/// Format of log records written to stderr.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format {}, expected text or json", s)),
+        }
+    }
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LogFormat::Text => write!(f, "text"),
+            LogFormat::Json => write!(f, "json"),
+        }
+    }
+}
+
+/// Structured fields of event record, set only while event is logged on this thread.
+#[derive(Default)]
+struct Fields {
+    event: Option<&'static str>,
+    connection_id: Option<String>,
+    mapping: Option<String>,
+    peer: Option<String>,
+}
+
+thread_local! {
+    static FIELDS: RefCell<Fields> = RefCell::new(Fields::default());
+}
+
+pub fn init(level: LevelFilter, format: LogFormat) -> Result<(), SetLoggerError> {
+    let mut builder = LoggerBuilder::new();
+    builder.filter(None, level);
+    match format {
+        LogFormat::Text => builder.format_timestamp_nanos(),
+        LogFormat::Json => builder.format(|buf, record| {
+            let timestamp = buf.timestamp_nanos().to_string();
+            writeln!(buf, "{}", to_json(&timestamp, record))
+        }),
+    };
+    builder.try_init()
+}
+
+/// Record as single line JSON object, fields without value are null.
+///
+/// Connection id of library records is taken from the leading id of message.
+fn to_json(timestamp: &str, record: &Record) -> String {
+    let message = record.args().to_string();
+    FIELDS.with(|fields| {
+        let fields = fields.borrow();
+        let connection_id = fields
+            .connection_id
+            .clone()
+            .or_else(|| leading_connection_id(&message).map(str::to_owned));
+        format!(
+            concat!(
+                r#"{{"timestamp":{},"level":"{}","target":{},"event":{},"#,
+                r#""connection_id":{},"mapping":{},"peer":{},"message":{}}}"#
+            ),
+            json_string(timestamp),
+            record.level(),
+            json_string(record.target()),
+            optional(fields.event),
+            optional(connection_id.as_deref()),
+            optional(fields.mapping.as_deref()),
+            optional(fields.peer.as_deref()),
+            json_string(&message),
+        )
+    })
+}
+
+fn leading_connection_id(message: &str) -> Option<&str> {
+    let id = message.split_whitespace().next()?;
+    let is_uuid = id.len() == 36
+        && id.char_indices().all(|(i, c)| match i {
+            8 | 13 | 18 | 23 => c == '-',
+            _ => c.is_ascii_hexdigit(),
+        });
+    if is_uuid {
+        Some(id)
+    } else {
+        None
+    }
+}
+
+fn optional(value: Option<&str>) -> String {
+    value.map_or("null".to_owned(), json_string)
+}
+
+/// Quoted JSON string with escaped quotes, backslashes and control characters.
+pub(crate) fn json_string(s: &str) -> String {
+    let mut json = String::with_capacity(s.len() + 2);
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+/// Logs events of client or server until publisher is dropped.
+pub async fn log_events(mut events: broadcast::Receiver<TcpWarpEvent>) {
+    loop {
+        match events.recv().await {
+            Ok(event) => log_event(event),
+            Err(RecvError::Lagged(skipped)) => warn!("{} events are not logged", skipped),
+            Err(RecvError::Closed) => break,
+        }
+    }
+}
+
+fn log_event(event: TcpWarpEvent) {
+    let mut fields = Fields::default();
+    let (level, name, message) = match event {
+        TcpWarpEvent::TunnelConnected { tunnel, peer } => {
+            fields.peer = Some(peer.to_string());
+            let message = format!("tunnel {} connected", tunnel);
+            (Level::Info, "tunnel_connected", message)
+        }
+        TcpWarpEvent::TunnelDisconnected { tunnel, peer } => {
+            fields.peer = Some(peer.to_string());
+            let message = format!("tunnel {} disconnected", tunnel);
+            (Level::Info, "tunnel_disconnected", message)
+        }
+        TcpWarpEvent::ListenerBound { mapping, address } => {
+            fields.mapping = Some(mapping.to_string());
+            let message = match address {
+                Some(address) => format!("listening on {}", address),
+                None => "listening on unix socket".to_owned(),
+            };
+            (Level::Info, "listener_bound", message)
+        }
+        TcpWarpEvent::ListenerFailed {
+            mapping,
+            error,
+            attempts,
+        } => {
+            fields.mapping = Some(mapping.to_string());
+            let message = format!("cannot listen after {} attempts: {}", attempts, error);
+            (Level::Warn, "listener_failed", message)
+        }
+        TcpWarpEvent::ConnectionOpened {
+            connection_id,
+            target,
+        } => {
+            fields.connection_id = Some(connection_id.to_string());
+            fields.mapping = Some(target);
+            let message = format!("{} connection opened", connection_id);
+            (Level::Info, "connection_opened", message)
+        }
+        TcpWarpEvent::ConnectionConnected { connection_id } => {
+            fields.connection_id = Some(connection_id.to_string());
+            let message = format!("{} connection connected", connection_id);
+            (Level::Info, "connection_connected", message)
+        }
+        TcpWarpEvent::ConnectionFailed {
+            connection_id,
+            error,
+        } => {
+            fields.connection_id = Some(connection_id.to_string());
+            let message = format!("{} connection failed: {}", connection_id, error);
+            (Level::Warn, "connection_failed", message)
+        }
+        TcpWarpEvent::ConnectionClosed {
+            connection_id,
+            bytes_sent,
+            bytes_received,
+        } => {
+            fields.connection_id = Some(connection_id.to_string());
+            let message = format!(
+                "{} connection closed, {} bytes sent, {} bytes received",
+                connection_id, bytes_sent, bytes_received
+            );
+            (Level::Info, "connection_closed", message)
+        }
+        TcpWarpEvent::RetryScheduled { tunnel, delay } => {
+            let message = match tunnel {
+                Some(tunnel) => format!("tunnel {} reconnects in {:?}", tunnel, delay),
+                None => format!("session reconnects in {:?}", delay),
+            };
+            (Level::Info, "retry_scheduled", message)
+        }
+    };
+    fields.event = Some(name);
+    FIELDS.with(|current| *current.borrow_mut() = fields);
+    log!(target: EVENTS_TARGET, level, "{}", message);
+    FIELDS.with(|current| *current.borrow_mut() = Fields::default());
+}
@@ -12,6 +12,13 @@ nc 127.0.0.1 8080
 1. A userspace tunnel to connect ports on client network with connections available on server side.
 1. Uses only single port.
 1. Client push of addresses to connect from server.
+1. Parallel tunnel connections with client connections striped across them.
+1. UDP port forwarding.
+1. Unix domain socket endpoints on client and server side.
+1. Port range mappings.
+1. Client ports chosen by OS with reporting of bound addresses.
+1. Catalog of named services advertised by server.
+1. Per-tunnel compression with per-mapping opt-out.
 
 ## Installation
 
@@ -67,6 +74,182 @@ Both client and server have address on which they listen for incoming connection
 
 Next we look at more specific example.
 
+### Parallel tunnels
+
+Single tunnel connection is limited by one congestion window. Client can open several tunnel connections to the same server with `--tunnels`:
+
+```bash
+tcp-warp client -c 8080:towel.blinkenlights.nl:23 --tunnels 4
+```
+
+New connections are placed on the least loaded tunnel. If one of the tunnels is lost, only connections placed on it are closed, others continue to work and lost tunnel is reopened in background after `--retry-interval`, or after one second without `--retry`.
+
+### UDP port forwarding
+
+Prefix mapping with `udp:` to forward datagrams, for example to reach DNS server behind the server:
+
+```bash
+tcp-warp client -c udp:5353:dns:53
+dig @127.0.0.1 -p 5353 example.com
+```
+
+Server keeps separate UDP association for every client address. Association is closed after `--udp-idle-timeout` seconds (default 60) without datagrams.
+
+### Bind address per mapping and IPv6
+
+Full mapping format is `[udp:|tcp:][[bind_address:]client_port:][host:]host_port`. Bind address overrides `--bind` for single mapping. IPv6 addresses are written in brackets:
+
+```bash
+tcp-warp client -c 127.0.0.1:8080:whoami:80 -c [::1]:8081:[fe80::1]:80
+```
+
+### Port ranges
+
+Range of ports can be mapped with single `-c`, both ranges must have the same length:
+
+```bash
+tcp-warp client -c 9000-9010:broker:9000-9010
+```
+
+### Ephemeral client ports
+
+Client port `0` lets OS choose free port. Chosen addresses are logged and can be printed as JSON with `--addresses-json` or written to file with `--addresses-file`:
+
+```bash
+$ tcp-warp client -c 0:whoami:80 --addresses-json
+{"0:whoami:80":"0.0.0.0:43567"}
+```
+
+With `--retry` client tries to keep the same port after reconnect.
+
+### Service catalog
+
+Server can advertise named services, so clients do not need to know hosts and ports behind the server:
+
+```bash
+tcp-warp server -s whoami=whoami:80 -s docker=172.18.0.1:2375
+tcp-warp client -c 8080:whoami
+```
+
+Mapping with service name only binds client port equal to service port. `--all-services` binds every advertised service:
+
+```bash
+tcp-warp client --all-services
+```
+
+### Compression
+
+Client can ask for compression of tunnel traffic with `--compression deflate` or `--compression zstd`. Server agrees unless started with `--no-compression`:
+
+```bash
+tcp-warp client -c 8080:whoami --compression zstd
+```
+
+Already compressed or encrypted traffic can skip compression with `,nocompress` suffix of mapping:
+
+```bash
+tcp-warp client -c 8443:web:443,nocompress --compression zstd
+```
+
+Bytes saved by compression are logged when tunnel closes.
+
+### Connect timeouts
+
+Server gives up connecting target after `--connect-timeout` seconds (default 10) and reports timeout to client. Client waits for answer of server `--connect-timeout` seconds (default 30) and closes local connection if none comes back:
+
+```bash
+tcp-warp server --connect-timeout 5
+tcp-warp client -c 8080:whoami:80 --connect-timeout 15
+```
+
+### Idle timeout and max lifetime
+
+Forgotten connections can be closed after seconds without bytes in either direction or after seconds since open. Client sets limits per mapping with `,idle=SECS` and `,lifetime=SECS` suffixes, server sets them for all connections with `--idle-timeout` and `--max-lifetime`:
+
+```bash
+tcp-warp client -c 5432:db:5432,idle=300,lifetime=3600
+tcp-warp server --idle-timeout 600
+```
+
+Expired connections are closed like any other and counted in metrics.
+
+### Connection limits
+
+Server caps concurrent connections with `--max-connections` for all clients, `--max-tunnel-connections` for single tunnel and `--max-client-connections` for all tunnels from one client IP address. Connects over limit fail with "limit exceeded" reason and close local connection on client:
+
+```bash
+tcp-warp server --max-client-connections 100
+```
+
+Client limits concurrent connections of mapping with `,max=N` suffix. Connections over limit are closed right away, or wait for free slot with `,queue`:
+
+```bash
+tcp-warp client -c 8080:whoami:80,max=10,queue
+```
+
+### Rate limits
+
+Server limits bytes per second in each direction with `--tunnel-rate` for single tunnel and `--client-rate` for all tunnels from one client IP address. Client limits bytes per second in each direction for connections of mapping with `,rate=N` suffix, so bulk upload does not saturate uplink shared with interactive sessions:
+
+```bash
+tcp-warp server --client-rate 10485760
+tcp-warp client -c 5000:registry:5000,rate=1048576 -c 2222:host:22
+```
+
+### Connection priorities
+
+Frames of all connections share tunnel, large payloads are split into chunks and written by weighted fair queuing, so keystrokes are not stuck behind bulk transfer. Client mapping sets priority of its connections with `,priority=interactive`, `normal` (default) or `bulk` suffix, interactive connections get 4 times the share of normal ones and normal get 4 times the share of bulk ones. Client tells priority of connection to server, which schedules frames in the other direction by it as well, servers and clients of older versions treat every connection as normal:
+
+```bash
+tcp-warp client -c 2222:bastion:22,priority=interactive -c 5000:registry:5000,priority=bulk
+```
+
+### Audit log
+
+Server writes JSON record of every connect request of clients with `--audit-log` to file or to local syslog with `--audit-log syslog`. Record is written once connection is closed or failed and contains timestamp, tunnel number and address of client, requested target, resolved target IP address, outcome with failure reason, duration and bytes in each direction:
+
+```bash
+tcp-warp server --audit-log /var/log/tcp-warp/audit.jsonl
+```
+
+```json
+{"timestamp":"2020-01-01T12:00:00.000Z","connection_id":"5b8a2fa5-8e66-4c2e-9d0e-3c3e5b2a7c11","tunnel":1,"client":"192.0.2.10:51234","target":"db:5432","resolved":"10.0.0.5","outcome":"connected","reason":null,"duration_ms":61234,"bytes_client_to_host":4096,"bytes_host_to_client":1048576}
+```
+
+### Log format
+
+Logs are written to stderr as text by default. With `--log-format json` every record is a JSON object with `timestamp`, `level`, `target`, `event`, `connection_id`, `mapping`, `peer` and `message` fields, fields which do not apply to record are `null`. Events of tunnels, listeners and connections are logged at info level with `event` name. Payloads of connections never reach logs in either format, messages show only payload length:
+
+```bash
+tcp-warp -vv --log-format json server
+```
+
+```json
+{"timestamp":"2020-01-01T12:00:00.000000000Z","level":"INFO","target":"tcpwarp::events","event":"connection_opened","connection_id":"5b8a2fa5-8e66-4c2e-9d0e-3c3e5b2a7c11","mapping":"db:5432","peer":null,"message":"5b8a2fa5-8e66-4c2e-9d0e-3c3e5b2a7c11 connection opened"}
+```
+
+### Unix domain sockets
+
+Paths starting with `/` or `.` are treated as Unix domain sockets on both sides of mapping. Server connects only to sockets allowed with `--allow-unix-socket`, which takes socket or directory of sockets and may be repeated:
+
+```bash
+tcp-warp server --allow-unix-socket /var/run/docker.sock
+```
+
+For example, Docker daemon socket can be forwarded directly, without exposing Docker over TCP:
+
+```bash
+tcp-warp client -c 2375:/var/run/docker.sock
+export DOCKER_HOST=tcp://127.0.0.1:2375
+```
+
+or to local Unix socket:
+
+```bash
+tcp-warp client -c /tmp/docker.sock:/var/run/docker.sock
+export DOCKER_HOST=unix:///tmp/docker.sock
+```
+
 ### Use case: running Docker on machine without Docker daemon installed with Docker daemon behind SSH
 
 Background:
@@ -163,32 +346,49 @@ Simple example with `whoami` service:
     ```
 
 */
-use env_logger::Builder as LoggerBuilder;
-use std::{error::Error, sync::Arc, time::Duration};
+use log::error;
+use std::{
+    error::Error,
+    fs,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
 use structopt::StructOpt;
-use tcpwarp::{TcpWarpClient, TcpWarpServer};
+use tcpwarp::{
+    TcpWarpClient, TcpWarpClientAddresses, TcpWarpClientBuilder, TcpWarpCompression, TcpWarpProto,
+    TcpWarpServer, TcpWarpServerBuilder,
+};
 
 mod cli;
+mod logging;
 
 use cli::{Cli, Command::*};
-
-const DEFAULT_CLIENT_BIND: &str = "0.0.0.0";
-const DEFAULT_CLIENT_SERVER: &str = "127.0.0.1:18000";
-const DEFAULT_SERVER_LISTEN: &str = "0.0.0.0:18000";
-const DEFAULT_SERVER_CONNECT: &str = "127.0.0.1";
+use logging::{json_string, LogFormat};
+
+const DEFAULT_CLIENT_BIND: IpAddr = TcpWarpClientBuilder::DEFAULT_BIND_ADDRESS;
+const DEFAULT_CLIENT_SERVER: SocketAddr = TcpWarpClientBuilder::DEFAULT_TUNNEL_ADDRESS;
+const DEFAULT_CLIENT_TUNNELS: usize = TcpWarpClientBuilder::DEFAULT_TUNNELS;
+const DEFAULT_CLIENT_COMPRESSION: TcpWarpCompression = TcpWarpClientBuilder::DEFAULT_COMPRESSION;
+const DEFAULT_CLIENT_CONNECT_TIMEOUT: Duration = TcpWarpClientBuilder::DEFAULT_CONNECT_TIMEOUT;
+const DEFAULT_CLIENT_LISTENER_RETRY_DELAY: Duration =
+    TcpWarpClientBuilder::DEFAULT_LISTENER_RETRY_DELAY;
+const DEFAULT_SERVER_LISTEN: SocketAddr = TcpWarpServerBuilder::DEFAULT_LISTEN_ADDRESS;
+const DEFAULT_SERVER_CONNECT: IpAddr = TcpWarpServerBuilder::DEFAULT_CONNECT_ADDRESS;
+const DEFAULT_SERVER_UDP_IDLE_TIMEOUT: Duration = TcpWarpServerBuilder::DEFAULT_UDP_IDLE_TIMEOUT;
+const DEFAULT_SERVER_CONNECT_TIMEOUT: Duration = TcpWarpServerBuilder::DEFAULT_CONNECT_TIMEOUT;
+const DEFAULT_MAX_FRAME_SIZE: usize = TcpWarpProto::DEFAULT_MAX_FRAME_SIZE;
+const DEFAULT_LOG_FORMAT: LogFormat = LogFormat::Text;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     let cli = Cli::from_args();
 
+    let log_format = cli.log_format.unwrap_or(DEFAULT_LOG_FORMAT);
     cli.verbose
         .log_level()
-        .map(|x| {
-            LoggerBuilder::new()
-                .filter(None, x.to_level_filter())
-                .format_timestamp_nanos()
-                .try_init()
-        })
+        .map(|x| logging::init(x.to_level_filter(), log_format))
         .transpose()?;
 
     match cli.command {
@@ -198,39 +398,157 @@ async fn main() -> Result<(), Box<dyn Error>> {
             connection,
             retry,
             retry_interval,
+            listener_retry_interval,
             keep_connections,
+            tunnels,
+            addresses_json,
+            all_services,
+            addresses_file,
+            compression,
+            max_frame_size,
+            connect_timeout,
         } => {
-            let client = TcpWarpClient::new(
-                bind.unwrap_or_else(|| DEFAULT_CLIENT_BIND.into()).parse()?,
-                tunnel
-                    .unwrap_or_else(|| DEFAULT_CLIENT_SERVER.into())
-                    .parse()?,
-            );
-            if retry {
-                client
-                    .connect_loop(
-                        Duration::from_secs(retry_interval.unwrap_or(5)),
-                        keep_connections,
-                        Arc::new(connection),
-                    )
-                    .await?;
-            } else {
-                client.connect(connection).await?;
+            let client = TcpWarpClient::builder()
+                .with_bind_address(
+                    bind.map(|x| x.parse())
+                        .transpose()?
+                        .unwrap_or(DEFAULT_CLIENT_BIND),
+                )
+                .with_tunnel_address(
+                    tunnel
+                        .map(|x| x.parse())
+                        .transpose()?
+                        .unwrap_or(DEFAULT_CLIENT_SERVER),
+                )
+                .with_tunnels(tunnels.unwrap_or(DEFAULT_CLIENT_TUNNELS))
+                .with_all_services(all_services)
+                .with_compression(compression.unwrap_or(DEFAULT_CLIENT_COMPRESSION))
+                .with_max_frame_size(max_frame_size.unwrap_or(DEFAULT_MAX_FRAME_SIZE))
+                .with_connect_timeout(
+                    connect_timeout
+                        .map(Duration::from_secs)
+                        .unwrap_or(DEFAULT_CLIENT_CONNECT_TIMEOUT),
+                )
+                .with_listener_retry_delay(
+                    listener_retry_interval
+                        .map(Duration::from_secs)
+                        .unwrap_or(DEFAULT_CLIENT_LISTENER_RETRY_DELAY),
+                )
+                .build();
+            tokio::spawn(logging::log_events(client.events()));
+            let session = async {
+                if retry {
+                    client
+                        .connect_loop(
+                            Duration::from_secs(retry_interval.unwrap_or(5)),
+                            keep_connections,
+                            Arc::new(connection),
+                        )
+                        .await
+                } else {
+                    client.connect(connection).await.map(|_| ())
+                }
+            };
+            let report = report_addresses(client.addresses(), addresses_json, addresses_file);
+            tokio::select! {
+                result = session => result?,
+                _ = report => (),
             }
         }
-        Server { listen, connect } => {
-            TcpWarpServer::new(
-                listen
-                    .unwrap_or_else(|| DEFAULT_SERVER_LISTEN.into())
-                    .parse()?,
-                connect
-                    .unwrap_or_else(|| DEFAULT_SERVER_CONNECT.into())
-                    .parse()?,
-            )
-            .listen()
-            .await?;
+        Server {
+            listen,
+            connect,
+            udp_idle_timeout,
+            service,
+            allow_unix_socket,
+            no_compression,
+            max_frame_size,
+            connect_timeout,
+            idle_timeout,
+            max_lifetime,
+            max_connections,
+            max_tunnel_connections,
+            max_client_connections,
+            tunnel_rate,
+            client_rate,
+            audit_log,
+        } => {
+            let server = TcpWarpServer::builder()
+                .with_listen_address(
+                    listen
+                        .map(|x| x.parse())
+                        .transpose()?
+                        .unwrap_or(DEFAULT_SERVER_LISTEN),
+                )
+                .with_connect_address(
+                    connect
+                        .map(|x| x.parse())
+                        .transpose()?
+                        .unwrap_or(DEFAULT_SERVER_CONNECT),
+                )
+                .with_udp_idle_timeout(
+                    udp_idle_timeout
+                        .map(Duration::from_secs)
+                        .unwrap_or(DEFAULT_SERVER_UDP_IDLE_TIMEOUT),
+                )
+                .with_services(service)
+                .with_unix_socket_paths(allow_unix_socket)
+                .with_compression(!no_compression)
+                .with_max_frame_size(max_frame_size.unwrap_or(DEFAULT_MAX_FRAME_SIZE))
+                .with_connect_timeout(
+                    connect_timeout
+                        .map(Duration::from_secs)
+                        .unwrap_or(DEFAULT_SERVER_CONNECT_TIMEOUT),
+                )
+                .with_idle_timeout(idle_timeout.map(Duration::from_secs))
+                .with_max_lifetime(max_lifetime.map(Duration::from_secs))
+                .with_max_connections(max_connections)
+                .with_max_tunnel_connections(max_tunnel_connections)
+                .with_max_client_connections(max_client_connections)
+                .with_tunnel_rate(tunnel_rate)
+                .with_client_rate(client_rate)
+                .with_audit_log(audit_log)
+                .build();
+            tokio::spawn(logging::log_events(server.events()));
+            server.listen().await?;
         }
     }
 
     Ok(())
 }
+
+async fn report_addresses(
+    mut addresses: tokio::sync::watch::Receiver<TcpWarpClientAddresses>,
+    print: bool,
+    file: Option<PathBuf>,
+) {
+    while let Some(addresses) = addresses.recv().await {
+        if addresses.is_empty() {
+            continue;
+        }
+        let json = addresses_to_json(&addresses);
+        if print {
+            println!("{}", json);
+        }
+        if let Some(file) = &file {
+            if let Err(err) = fs::write(file, format!("{}\n", json)) {
+                error!("cannot write addresses to {}: {}", file.display(), err);
+            }
+        }
+    }
+}
+
+fn addresses_to_json(addresses: &TcpWarpClientAddresses) -> String {
+    let mut entries: Vec<_> = addresses
+        .iter()
+        .map(|(mapping, address)| {
+            format!(
+                "{}:{}",
+                json_string(&mapping.to_string()),
+                json_string(&address.to_string())
+            )
+        })
+        .collect();
+    entries.sort();
+    format!("{{{}}}", entries.join(","))
+}
@@ -0,0 +1,303 @@
+use super::*;
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::Path,
+    sync::mpsc,
+    time::{Instant, SystemTime},
+};
+
+/// Syslog priority of audit records, facility authpriv and severity info.
+#[cfg(unix)]
+const SYSLOG_PRIORITY: u8 = 10 * 8 + 6;
+
+/// Sockets of local syslog daemon on Linux and macOS.
+#[cfg(unix)]
+const SYSLOG_SOCKETS: &[&str] = &["/dev/log", "/var/run/syslog"];
+
+/// Destination of server audit log.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TcpWarpAuditLog {
+    /// JSON lines appended to file.
+    File(PathBuf),
+    /// JSON records sent to local syslog daemon.
+    Syslog,
+}
+
+impl FromStr for TcpWarpAuditLog {
+    type Err = TcpWarpError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "" => Err(TcpWarpError::parse(s, "empty audit log path")),
+            "syslog" => Ok(TcpWarpAuditLog::Syslog),
+            path => Ok(TcpWarpAuditLog::File(path.into())),
+        }
+    }
+}
+
+impl fmt::Display for TcpWarpAuditLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TcpWarpAuditLog::File(path) => path.display().fmt(f),
+            TcpWarpAuditLog::Syslog => write!(f, "syslog"),
+        }
+    }
+}
+
+/// Opened audit log, records are written one line at a time by dedicated thread, so slow
+/// disk or syslog daemon never blocks the runtime.
+pub(crate) struct AuditWriter {
+    sender: mpsc::Sender<(Uuid, String)>,
+}
+
+impl AuditWriter {
+    pub(crate) fn open(log: &TcpWarpAuditLog) -> Result<Self, TcpWarpError> {
+        let error = |source| TcpWarpError::AuditLog {
+            log: log.to_string(),
+            source,
+        };
+        let mut sink = match log {
+            TcpWarpAuditLog::File(path) => OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map(AuditSink::File)
+                .map_err(error)?,
+            #[cfg(unix)]
+            TcpWarpAuditLog::Syslog => {
+                AuditSink::syslog(SYSLOG_SOCKETS.iter().map(Path::new)).map_err(error)?
+            }
+            #[cfg(not(unix))]
+            TcpWarpAuditLog::Syslog => return Err(TcpWarpError::Unsupported("syslog audit logs")),
+        };
+        let (sender, receiver) = mpsc::channel::<(Uuid, String)>();
+        std::thread::Builder::new()
+            .name("tcp-warp-audit".into())
+            .spawn(move || {
+                for (connection_id, json) in receiver {
+                    if let Err(err) = sink.write(&json) {
+                        error!("cannot write audit record {}: {}", connection_id, err);
+                    }
+                }
+            })
+            .map_err(error)?;
+        Ok(Self { sender })
+    }
+
+    /// Queues record for writer thread, failure is logged and does not affect connection.
+    pub(crate) fn write(&self, record: &AuditRecord) {
+        if self
+            .sender
+            .send((record.connection_id, record.to_json()))
+            .is_err()
+        {
+            error!(
+                "cannot write audit record {}: writer is stopped",
+                record.connection_id
+            );
+        }
+    }
+}
+
+/// Destination of audit records owned by writer thread.
+pub(crate) enum AuditSink {
+    File(File),
+    /// Socket connected to syslog daemon and path it is connected to.
+    #[cfg(unix)]
+    Syslog(UnixDatagram, PathBuf),
+}
+
+impl AuditSink {
+    /// Connects to the first of syslog sockets accepting connection.
+    #[cfg(unix)]
+    pub(crate) fn syslog<'a>(paths: impl IntoIterator<Item = &'a Path>) -> io::Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        let mut result = Err(io::Error::from(io::ErrorKind::NotFound));
+        for path in paths {
+            result = socket.connect(path).map(|_| path.to_owned());
+            if result.is_ok() {
+                break;
+            }
+        }
+        result.map(|path| AuditSink::Syslog(socket, path))
+    }
+
+    /// Writes JSON record, syslog socket is reconnected once when daemon went away.
+    pub(crate) fn write(&mut self, json: &str) -> io::Result<()> {
+        match self {
+            AuditSink::File(file) => file.write_all(format!("{}\n", json).as_bytes()),
+            #[cfg(unix)]
+            AuditSink::Syslog(socket, path) => {
+                let message = format!(
+                    "<{}>tcp-warp[{}]: {}",
+                    SYSLOG_PRIORITY,
+                    std::process::id(),
+                    json
+                );
+                if let Err(err) = socket.send(message.as_bytes()) {
+                    warn!("reconnecting audit log to {}: {}", path.display(), err);
+                    let reconnected = UnixDatagram::unbound()?;
+                    reconnected.connect(&path)?;
+                    *socket = reconnected;
+                    socket.send(message.as_bytes())?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Audit record of one connect request of client, written once connection is over.
+pub(crate) struct AuditRecord {
+    timestamp: SystemTime,
+    started: Instant,
+    pub(crate) connection_id: Uuid,
+    tunnel: usize,
+    client: SocketAddr,
+    target: String,
+    /// Address of connected target, `None` for unix socket or failed connect.
+    pub(crate) resolved: Option<IpAddr>,
+    pub(crate) failure: Option<TcpWarpConnectFailure>,
+    pub(crate) bytes_client_to_host: u64,
+    pub(crate) bytes_host_to_client: u64,
+}
+
+impl AuditRecord {
+    pub(crate) fn new(
+        connection_id: Uuid,
+        tunnel: usize,
+        client: SocketAddr,
+        target: String,
+    ) -> Self {
+        Self {
+            timestamp: SystemTime::now(),
+            started: Instant::now(),
+            connection_id,
+            tunnel,
+            client,
+            target,
+            resolved: None,
+            failure: None,
+            bytes_client_to_host: 0,
+            bytes_host_to_client: 0,
+        }
+    }
+
+    /// Record as single line JSON object.
+    pub(crate) fn to_json(&self) -> String {
+        let optional =
+            |value: Option<String>| value.map_or("null".to_owned(), |value| json_string(&value));
+        format!(
+            concat!(
+                r#"{{"timestamp":{},"connection_id":"{}","tunnel":{},"client":"{}","#,
+                r#""target":{},"resolved":{},"outcome":"{}","reason":{},"duration_ms":{},"#,
+                r#""bytes_client_to_host":{},"bytes_host_to_client":{}}}"#
+            ),
+            json_string(&humantime::format_rfc3339_millis(self.timestamp).to_string()),
+            self.connection_id,
+            self.tunnel,
+            self.client,
+            json_string(&self.target),
+            optional(self.resolved.map(|resolved| resolved.to_string())),
+            if self.failure.is_some() {
+                "failed"
+            } else {
+                "connected"
+            },
+            optional(self.failure.map(|failure| failure.to_string())),
+            self.started.elapsed().as_millis(),
+            self.bytes_client_to_host,
+            self.bytes_host_to_client,
+        )
+    }
+}
+
+/// Quoted JSON string, target host comes from client and may contain anything.
+pub(crate) fn json_string(s: &str) -> String {
+    let mut json = String::with_capacity(s.len() + 2);
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn audit_syslog_is_reconnected() {
+        let path =
+            std::env::temp_dir().join(format!("tcp-warp-syslog-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let daemon = UnixDatagram::bind(&path).unwrap();
+        let mut sink = AuditSink::syslog(Some(path.as_path())).unwrap();
+        let mut received = [0; 100];
+        sink.write("{}").unwrap();
+        let len = daemon.recv(&mut received).unwrap();
+        assert!(received[..len].ends_with(b": {}"));
+
+        // syslog daemon restarts and binds the same path again
+        drop(daemon);
+        std::fs::remove_file(&path).unwrap();
+        let daemon = UnixDatagram::bind(&path).unwrap();
+        sink.write("{}").unwrap();
+        let len = daemon.recv(&mut received).unwrap();
+        assert!(received[..len].ends_with(b": {}"));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn audit_record_to_json() {
+        let connection_id = Uuid::new_v4();
+        let mut record = AuditRecord::new(
+            connection_id,
+            7,
+            "192.0.2.1:50000".parse().unwrap(),
+            "db\"\n\u{1}:5432".to_owned(),
+        );
+        record.failure = Some(TcpWarpConnectFailure::Refused);
+        let json = record.to_json();
+        assert!(json.starts_with(r#"{"timestamp":""#));
+        assert!(json.ends_with("}"));
+        assert!(!json.contains('\n'));
+        for field in &[
+            format!(r#""connection_id":"{}""#, connection_id),
+            r#""tunnel":7,"client":"192.0.2.1:50000","target":"db\"\n\u0001:5432""#.to_owned(),
+            r#""resolved":null,"outcome":"failed","reason":"target refused connection""#.to_owned(),
+            r#""bytes_client_to_host":0,"bytes_host_to_client":0}"#.to_owned(),
+        ] {
+            assert!(json.contains(field.as_str()), "{} in {}", field, json);
+        }
+
+        record.failure = None;
+        record.resolved = Some(Ipv4Addr::LOCALHOST.into());
+        record.bytes_client_to_host = 3;
+        let json = record.to_json();
+        assert!(json.contains(r#""resolved":"127.0.0.1","outcome":"connected","reason":null"#));
+        assert!(json.contains(r#""bytes_client_to_host":3,"#));
+
+        assert_eq!(
+            Ok(TcpWarpAuditLog::Syslog),
+            "syslog".parse().map_err(|_| ())
+        );
+        assert_eq!(
+            Ok(TcpWarpAuditLog::File("/var/log/tcp-warp.jsonl".into())),
+            "/var/log/tcp-warp.jsonl".parse().map_err(|_| ())
+        );
+    }
+}
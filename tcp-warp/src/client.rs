@@ -3,23 +3,324 @@ use super::*;
 pub struct TcpWarpClient {
     bind_address: IpAddr,
     tunnel_address: SocketAddr,
+    tunnels: usize,
+    addresses_bound: Published<TcpWarpClientAddresses>,
+    mappings: Published<TcpWarpClientMappings>,
+    services: Published<Vec<TcpWarpService>>,
+    all_services: bool,
+    compression: TcpWarpCompression,
+    max_frame_size: usize,
+    connect_timeout: Duration,
+    listener_retry_delay: Duration,
+    metrics: Arc<TcpWarpMetrics>,
+    events: TcpWarpEvents,
+    session: Arc<Mutex<Option<Sender<TcpWarpMessage>>>>,
 }
 
+/// Delay of reopening lost tunnel of session started by [`TcpWarpClient::connect`],
+/// [`TcpWarpClient::connect_loop`] uses its retry delay instead.
+const TUNNEL_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
 pub type TcpWarpClientResult = HashMap<Uuid, TcpWarpConnection>;
 
+pub type TcpWarpClientAddresses = HashMap<TcpWarpPortConnection, SocketAddr>;
+
+pub type TcpWarpClientMappings = HashMap<TcpWarpPortConnection, TcpWarpMappingState>;
+
+/// State of client listener for single port mapping.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TcpWarpMappingState {
+    /// Listener is about to be bound.
+    Pending,
+    /// Listener accepts connections.
+    Listening,
+    /// Listener could not be bound, bind is retried in background.
+    Failed { error: String, attempts: usize },
+    /// Listener is stopped because session is over.
+    Stopped,
+}
+
+/// Delay after failed accept, so errors like too many open files do not spin listener.
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Client state published to library users with watch channel.
+#[derive(Clone)]
+struct Published<T> {
+    sender: Arc<Mutex<watch::Sender<T>>>,
+    receiver: watch::Receiver<T>,
+}
+
+impl<T: Clone + Default> Published<T> {
+    fn new() -> Self {
+        let (sender, receiver) = watch::channel(T::default());
+        Self {
+            sender: Arc::new(Mutex::new(sender)),
+            receiver,
+        }
+    }
+
+    fn get(&self) -> T {
+        self.receiver.borrow().clone()
+    }
+
+    fn update<F: FnOnce(&mut T) -> R, R>(&self, f: F) {
+        let sender = match self.sender.lock() {
+            Ok(sender) => sender,
+            Err(err) => err.into_inner(),
+        };
+        let mut value = self.get();
+        f(&mut value);
+        if sender.broadcast(value).is_err() {
+            debug!("no receivers for client state");
+        }
+    }
+}
+
+/// Configuration of [`TcpWarpClient`].
+///
+/// Defaults are the same as defaults of `tcp-warp client` command line.
+#[derive(Debug, Clone)]
+pub struct TcpWarpClientBuilder {
+    bind_address: IpAddr,
+    tunnel_address: SocketAddr,
+    tunnels: usize,
+    all_services: bool,
+    compression: TcpWarpCompression,
+    max_frame_size: usize,
+    connect_timeout: Duration,
+    listener_retry_delay: Duration,
+}
+
+impl TcpWarpClientBuilder {
+    pub const DEFAULT_BIND_ADDRESS: IpAddr = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+    pub const DEFAULT_TUNNEL_ADDRESS: SocketAddr =
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 18000);
+    pub const DEFAULT_TUNNELS: usize = 1;
+    pub const DEFAULT_COMPRESSION: TcpWarpCompression = TcpWarpCompression::None;
+    pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+    pub const DEFAULT_LISTENER_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets address on which listeners of mappings are bound.
+    pub fn with_bind_address(mut self, bind_address: IpAddr) -> Self {
+        self.bind_address = bind_address;
+        self
+    }
+
+    /// Sets address of tunnel server.
+    pub fn with_tunnel_address(mut self, tunnel_address: SocketAddr) -> Self {
+        self.tunnel_address = tunnel_address;
+        self
+    }
+
+    /// Sets number of parallel tunnel connections in one session.
+    ///
+    /// New connections are striped to the least loaded tunnel.
+    /// Lost tunnel closes only connections striped to it and is reopened in background.
+    pub fn with_tunnels(mut self, tunnels: usize) -> Self {
+        self.tunnels = tunnels.max(1);
+        self
+    }
+
+    /// Binds all services advertised by server in addition to configured mappings.
+    ///
+    /// Service is bound to client port equal to its port on the server.
+    pub fn with_all_services(mut self, all_services: bool) -> Self {
+        self.all_services = all_services;
+        self
+    }
+
+    /// Requests compression of tunnel payloads, used if server supports it.
+    ///
+    /// Mappings with `nocompress` option are not compressed.
+    pub fn with_compression(mut self, compression: TcpWarpCompression) -> Self {
+        warn_unsupported_compression(compression);
+        self.compression = compression;
+        self
+    }
+
+    /// Sets maximal size of frame accepted from server, larger frame closes the tunnel.
+    pub fn with_max_frame_size(mut self, max_frame_size: usize) -> Self {
+        self.max_frame_size = max_frame_size;
+        self
+    }
+
+    /// Sets how long connection waits for server to connect target.
+    ///
+    /// Local socket is closed if server does not answer in time.
+    pub fn with_connect_timeout(mut self, connect_timeout: Duration) -> Self {
+        self.connect_timeout = connect_timeout;
+        self
+    }
+
+    /// Sets delay between attempts to bind listener of mapping which failed to bind.
+    pub fn with_listener_retry_delay(mut self, listener_retry_delay: Duration) -> Self {
+        self.listener_retry_delay = listener_retry_delay;
+        self
+    }
+
+    pub fn build(self) -> TcpWarpClient {
+        TcpWarpClient {
+            bind_address: self.bind_address,
+            tunnel_address: self.tunnel_address,
+            tunnels: self.tunnels,
+            addresses_bound: Published::new(),
+            mappings: Published::new(),
+            services: Published::new(),
+            all_services: self.all_services,
+            compression: self.compression,
+            max_frame_size: self.max_frame_size,
+            connect_timeout: self.connect_timeout,
+            listener_retry_delay: self.listener_retry_delay,
+            metrics: Arc::new(TcpWarpMetrics::default()),
+            events: TcpWarpEvents::new(),
+            session: Arc::new(Mutex::new(None)),
+        }
+    }
+}
+
+impl Default for TcpWarpClientBuilder {
+    fn default() -> Self {
+        Self {
+            bind_address: Self::DEFAULT_BIND_ADDRESS,
+            tunnel_address: Self::DEFAULT_TUNNEL_ADDRESS,
+            tunnels: Self::DEFAULT_TUNNELS,
+            all_services: false,
+            compression: Self::DEFAULT_COMPRESSION,
+            max_frame_size: TcpWarpProto::DEFAULT_MAX_FRAME_SIZE,
+            connect_timeout: Self::DEFAULT_CONNECT_TIMEOUT,
+            listener_retry_delay: Self::DEFAULT_LISTENER_RETRY_DELAY,
+        }
+    }
+}
+
+fn warn_unsupported_compression(compression: TcpWarpCompression) {
+    if compression.capability() & TcpWarpProto::CAPABILITIES != compression.capability() {
+        warn!("compression {} is not supported by this build", compression);
+    }
+}
+
 impl TcpWarpClient {
     pub fn new(bind_address: IpAddr, tunnel_address: SocketAddr) -> Self {
-        Self {
-            bind_address,
-            tunnel_address,
+        Self::builder()
+            .with_bind_address(bind_address)
+            .with_tunnel_address(tunnel_address)
+            .build()
+    }
+
+    pub fn builder() -> TcpWarpClientBuilder {
+        TcpWarpClientBuilder::default()
+    }
+
+    /// Metrics of all sessions of this client.
+    pub fn metrics(&self) -> Arc<TcpWarpMetrics> {
+        self.metrics.clone()
+    }
+
+    /// Subscribes to events of tunnels, listeners and connections of this client.
+    ///
+    /// Only events published after subscription are received.
+    pub fn events(&self) -> broadcast::Receiver<TcpWarpEvent> {
+        self.events.subscribe()
+    }
+
+    /// Services advertised by server in the last handshake.
+    pub fn services(&self) -> watch::Receiver<Vec<TcpWarpService>> {
+        self.services.receiver.clone()
+    }
+
+    /// Local addresses of bound listeners keyed by port mapping.
+    ///
+    /// Receiver is updated every time listeners are bound. Use it to find out ports
+    /// chosen by OS for mappings with client port 0.
+    pub fn addresses(&self) -> watch::Receiver<TcpWarpClientAddresses> {
+        self.addresses_bound.receiver.clone()
+    }
+
+    /// Listener states keyed by port mapping.
+    ///
+    /// Mapping which cannot be bound is marked as failed and retried in background,
+    /// while other mappings keep serving.
+    pub fn mappings(&self) -> watch::Receiver<TcpWarpClientMappings> {
+        self.mappings.receiver.clone()
+    }
+
+    /// Opens connection to host and port reachable from server through current session.
+    ///
+    /// Connection is striped across tunnels the same way as connections accepted by
+    /// listeners of mappings. Fails if client has no session.
+    pub async fn open_stream(&self, host: &str, port: u16) -> Result<TcpWarpStream, TcpWarpError> {
+        let mut host_sender = match self.session.lock() {
+            Ok(session) => session.clone(),
+            Err(err) => err.into_inner().clone(),
+        }
+        .ok_or(TcpWarpError::SessionClosed)?;
+        let connection_id = Uuid::new_v4();
+        let (client_sender, client_receiver) = channel(100);
+        let (connected_sender, connected_receiver) = oneshot::channel();
+
+        self.events.emit(TcpWarpEvent::ConnectionOpened {
+            connection_id,
+            target: format!("{}:{}", host, port),
+        });
+        host_sender
+            .send(TcpWarpMessage::Connect {
+                connection_id,
+                connection: TcpWarpPortConnection {
+                    host: Some(host.to_owned()),
+                    port,
+                    ..Default::default()
+                },
+                sender: client_sender,
+                connected_sender,
+            })
+            .await?;
+
+        let connected = match timeout(self.connect_timeout, connected_receiver).await {
+            Ok(Ok(connected)) => connected,
+            Ok(Err(err)) => Err(io::Error::other(err)),
+            Err(_) => Err(TcpWarpConnectFailure::TimedOut.into()),
+        };
+        if let Err(err) = connected {
+            self.events.emit(TcpWarpEvent::ConnectionFailed {
+                connection_id,
+                error: err.to_string(),
+            });
+            return Err(err.into());
+        }
+        self.events
+            .emit(TcpWarpEvent::ConnectionConnected { connection_id });
+
+        Ok(TcpWarpStream::new(
+            connection_id,
+            host_sender,
+            client_receiver,
+            self.events.clone(),
+        ))
+    }
+
+    fn set_session(&self, sender: Option<Sender<TcpWarpMessage>>) {
+        match self.session.lock() {
+            Ok(mut session) => *session = sender,
+            Err(err) => *err.into_inner() = sender,
         }
     }
 
     pub async fn connect(
         &self,
         addresses: Vec<TcpWarpPortConnection>,
-    ) -> Result<(TcpWarpClientResult, Arc<Vec<TcpWarpPortConnection>>), Box<dyn Error>> {
-        self.connect_with(HashMap::new(), Arc::new(addresses)).await
+    ) -> Result<(TcpWarpClientResult, Arc<Vec<TcpWarpPortConnection>>), TcpWarpError> {
+        let streams = self.connect_tunnels().await?;
+        self.connect_with(
+            streams,
+            HashMap::new(),
+            Arc::new(addresses),
+            TUNNEL_RECONNECT_DELAY,
+        )
+        .await
     }
 
     pub async fn connect_loop(
@@ -27,43 +328,107 @@ impl TcpWarpClient {
         retry_delay: Duration,
         keep_connections: bool,
         mut addresses: Arc<Vec<TcpWarpPortConnection>>,
-    ) -> Result<(), Box<dyn Error>> {
+    ) -> Result<(), TcpWarpError> {
         let mut connections = HashMap::new();
 
-        while let Ok((data, addrs)) = self.connect_with(connections, addresses).await {
-            connections = if keep_connections {
-                data
-            } else {
-                HashMap::new()
-            };
-            addresses = addrs;
+        loop {
+            match self.connect_tunnels().await {
+                Ok(streams) => {
+                    let (data, addrs) = self
+                        .connect_with(streams, connections, addresses, retry_delay)
+                        .await?;
+                    connections = if keep_connections {
+                        data
+                    } else {
+                        HashMap::new()
+                    };
+                    addresses = addrs;
+                }
+                Err(err @ TcpWarpError::TunnelUnreachable { .. }) => error!("{}", err),
+                Err(err) => return Err(err),
+            }
             warn!("retrying in {:?}", retry_delay);
+            self.events.emit(TcpWarpEvent::RetryScheduled {
+                tunnel: None,
+                delay: retry_delay,
+            });
             delay_for(retry_delay).await;
         }
+    }
 
-        Ok(())
+    /// Opens tunnel streams, fails only if none of them can be opened.
+    async fn connect_tunnels(&self) -> Result<Vec<Option<TcpStream>>, TcpWarpError> {
+        let mut streams = vec![];
+        for _ in 0..self.tunnels {
+            match TcpStream::connect(&self.tunnel_address).await {
+                Ok(stream) => streams.push(Some(stream)),
+                Err(source) if streams.is_empty() => {
+                    return Err(TcpWarpError::TunnelUnreachable {
+                        address: self.tunnel_address,
+                        source,
+                    })
+                }
+                Err(err) => {
+                    error!("cannot connect to tunnel: {}", err);
+                    break;
+                }
+            }
+        }
+        streams.resize_with(self.tunnels, || None);
+        Ok(streams)
     }
 
     async fn connect_with(
         &self,
+        streams: Vec<Option<TcpStream>>,
         mut connections: TcpWarpClientResult,
         addresses: Arc<Vec<TcpWarpPortConnection>>,
-    ) -> Result<(TcpWarpClientResult, Arc<Vec<TcpWarpPortConnection>>), Box<dyn Error>> {
-        let stream = match TcpStream::connect(&self.tunnel_address).await {
-            Ok(stream) => stream,
-            Err(err) => {
-                error!("cannot connect to tunnel: {}", err);
-                return Ok((connections, addresses));
-            }
+        retry_delay: Duration,
+    ) -> Result<(TcpWarpClientResult, Arc<Vec<TcpWarpPortConnection>>), TcpWarpError> {
+        let (mut sender, mut receiver) = channel(100);
+        self.set_session(Some(sender.clone()));
+
+        let session = TunnelSession {
+            addresses: addresses.clone(),
+            bind_address: self.bind_address,
+            listening: Arc::new(AtomicBool::new(false)),
+            addresses_bound: self.addresses_bound.clone(),
+            mappings: self.mappings.clone(),
+            services: self.services.clone(),
+            all_services: self.all_services,
+            capabilities: TcpWarpProto::CAPABILITY_COMPACT_IDS
+                | TcpWarpProto::CAPABILITY_FAILURE_REASONS
+                | TcpWarpProto::CAPABILITY_PRIORITIES
+                | self.compression.capability(),
+            max_frame_size: self.max_frame_size,
+            connect_timeout: self.connect_timeout,
+            listener_retry_delay: self.listener_retry_delay,
+            retry_delay,
+            metrics: self.metrics.clone(),
+            events: self.events.clone(),
         };
-        let (mut wtransport, mut rtransport) = Framed::new(stream, TcpWarpProto).split();
 
-        let (mut sender, mut receiver) = channel(100);
+        for (tunnel, stream) in streams.into_iter().enumerate() {
+            let (tunnel_task, abort_handler) = abortable(tunnel_loop(
+                tunnel,
+                stream,
+                self.tunnel_address,
+                sender.clone(),
+                session.clone(),
+            ));
+            sender.send(TcpWarpMessage::Listener(abort_handler)).await?;
+            spawn(tunnel_task);
+        }
+        drop(sender);
+
+        let tunnels_count = self.tunnels;
 
         let forward_task = async move {
             debug!("in receiver task");
 
-            let mut listeners = vec![];
+            let mut listeners: Vec<AbortHandle> = vec![];
+            let mut tunnels = HashMap::new();
+            let mut round = 0usize;
 
             while let Some(message) = receiver.next().await {
                 debug!("just received a message connect: {:?}", message);
@@ -74,19 +439,118 @@ impl TcpWarpClient {
                         sender,
                         connected_sender,
                     } => {
-                        debug!("adding connection: {}", connection_id);
+                        round = round.wrapping_add(1);
+                        let tunnel = match least_loaded_tunnel(
+                            &tunnels,
+                            &connections,
+                            round % tunnels_count,
+                        ) {
+                            Some(tunnel) => tunnel,
+                            None => {
+                                if let Err(err) = connected_sender
+                                    .send(Err(io::Error::other("no tunnel available")))
+                                {
+                                    error!("cannot send to oneshot channel: {:?}", err);
+                                }
+                                continue;
+                            }
+                        };
+                        debug!("adding connection: {} to tunnel {}", connection_id, tunnel);
                         connections.insert(
-                            connection_id.clone(),
+                            connection_id,
                             TcpWarpConnection {
                                 sender,
                                 connected_sender: Some(connected_sender),
+                                tunnel,
+                                priority: connection.priority,
+                                client_closed: false,
+                                host_closed: false,
+                            },
+                        );
+                        match connection.path {
+                            Some(path) => TcpWarpMessage::HostConnectPath {
+                                connection_id,
+                                path,
+                                compress: !connection.no_compression,
+                                priority: connection.priority,
+                            },
+                            None => TcpWarpMessage::HostConnect {
+                                connection_id,
+                                host: connection.host,
+                                port: connection.port,
+                                compress: !connection.no_compression,
+                                priority: connection.priority,
                             },
+                        }
+                    }
+                    TcpWarpMessage::DatagramConnect {
+                        connection_id,
+                        priority,
+                        mut sender,
+                    } => {
+                        round = round.wrapping_add(1);
+                        let tunnel = match least_loaded_tunnel(
+                            &tunnels,
+                            &connections,
+                            round % tunnels_count,
+                        ) {
+                            Some(tunnel) => tunnel,
+                            None => {
+                                error!("no tunnel available for datagrams: {}", connection_id);
+                                let message = TcpWarpMessage::DisconnectHost { connection_id };
+                                if let Err(err) = sender.send(message).await {
+                                    error!("cannot send to channel: {}", err);
+                                }
+                                continue;
+                            }
+                        };
+                        debug!(
+                            "adding datagram association: {} to tunnel {}",
+                            connection_id, tunnel
                         );
-                        TcpWarpMessage::HostConnect {
+                        connections.insert(
                             connection_id,
-                            host: connection.host,
-                            port: connection.port,
+                            TcpWarpConnection {
+                                sender,
+                                connected_sender: None,
+                                tunnel,
+                                priority,
+                                client_closed: false,
+                                host_closed: false,
+                            },
+                        );
+                        continue;
+                    }
+                    TcpWarpMessage::DatagramHost {
+                        ref connection_id, ..
+                    } => {
+                        if let Some(connection) = connections.get_mut(connection_id) {
+                            if let Err(err) = connection.sender.send(message).await {
+                                error!("cannot send to channel: {}", err);
+                            }
+                        } else {
+                            error!("datagram association not found: {}", connection_id);
+                        }
+                        continue;
+                    }
+                    TcpWarpMessage::TunnelConnected { tunnel, sender } => {
+                        debug!("tunnel {} connected", tunnel);
+                        tunnels.insert(tunnel, sender);
+                        continue;
+                    }
+                    TcpWarpMessage::TunnelDisconnected { tunnel } => {
+                        debug!("tunnel {} disconnected", tunnel);
+                        tunnels.remove(&tunnel);
+                        disconnect_tunnel_connections(tunnel, &mut connections).await;
+                        if !tunnels.is_empty() {
+                            continue;
                         }
+                        debug!("no tunnels left, stopping lesteners...");
+                        for listener in listeners {
+                            listener.abort();
+                        }
+                        debug!("stopped listeners");
+                        break;
                     }
                     TcpWarpMessage::Listener(abort_handler) => {
                         listeners.push(abort_handler);
@@ -100,24 +564,59 @@ impl TcpWarpClient {
                         debug!("stopped listeners");
                         break;
                     }
-                    TcpWarpMessage::DisconnectHost { ref connection_id } => {
-                        if let Some(mut connection) = connections.remove(connection_id) {
+                    TcpWarpMessage::DisconnectHost { connection_id } => {
+                        if let Some(connection) = connections.get_mut(&connection_id) {
+                            connection.host_closed = true;
                             if let Err(err) = connection.sender.send(message).await {
                                 error!("cannot send to channel: {}", err);
                             }
+                            if connection.client_closed {
+                                connections.remove(&connection_id);
+                            }
                         } else {
                             error!("connection not found: {}", connection_id);
                         }
                         debug!("connections in pool: {}", connections.len());
                         continue;
                     }
-                    TcpWarpMessage::ConnectFailure { ref connection_id } => {
+                    TcpWarpMessage::DisconnectClient { connection_id } => {
+                        let (tunnel, priority, host_closed) =
+                            match connections.get_mut(&connection_id) {
+                                Some(connection) => {
+                                    connection.client_closed = true;
+                                    (
+                                        connection.tunnel,
+                                        connection.priority,
+                                        connection.host_closed,
+                                    )
+                                }
+                                None => {
+                                    error!("connection not found: {}", connection_id);
+                                    continue;
+                                }
+                            };
+                        // server keeps connection until it receives client close, even when
+                        // host side is already closed
+                        if host_closed {
+                            connections.remove(&connection_id);
+                        }
+                        debug!("connections in pool: {}", connections.len());
+                        if let Some(tunnel_sender) = tunnels.get_mut(&tunnel) {
+                            if let Err(err) = tunnel_sender.send((message, priority)).await {
+                                error!("cannot send to tunnel {}: {}", tunnel, err);
+                            }
+                        } else {
+                            error!("tunnel not found: {}", tunnel);
+                        }
+                        continue;
+                    }
+                    TcpWarpMessage::ConnectFailure {
+                        ref connection_id,
+                        reason,
+                    } => {
                         if let Some(mut connection) = connections.remove(connection_id) {
                             if let Some(connection_sender) = connection.connected_sender.take() {
-                                if let Err(err) = connection_sender.send(Err(io::Error::new(
-                                    io::ErrorKind::Other,
-                                    "disonnect propagated",
-                                ))) {
+                                if let Err(err) = connection_sender.send(Err(reason.into())) {
                                     error!("cannot send to oneshot channel: {:?}", err);
                                 }
                             }
@@ -131,11 +630,24 @@ impl TcpWarpClient {
                         continue;
                     }
                     TcpWarpMessage::Connected { ref connection_id } => {
-                        if let Some(connection) = connections.get_mut(&connection_id) {
+                        if let Some(connection) = connections.get_mut(connection_id) {
                             debug!("start connected loop: {}", connection_id);
                             if let Some(connection_sender) = connection.connected_sender.take() {
-                                if let Err(err) = connection_sender.send(Ok(())) {
-                                    error!("cannot send to oneshot channel: {:?}", err);
+                                if connection_sender.send(Ok(())).is_err() {
+                                    warn!("{} connected after deadline, closing", connection_id);
+                                    let (tunnel, priority) =
+                                        (connection.tunnel, connection.priority);
+                                    connections.remove(connection_id);
+                                    let message = TcpWarpMessage::DisconnectClient {
+                                        connection_id: *connection_id,
+                                    };
+                                    if let Some(tunnel_sender) = tunnels.get_mut(&tunnel) {
+                                        if let Err(err) =
+                                            tunnel_sender.send((message, priority)).await
+                                        {
+                                            error!("cannot send to tunnel {}: {}", tunnel, err);
+                                        }
+                                    }
                                 }
                             }
                         } else {
@@ -166,92 +678,303 @@ impl TcpWarpClient {
                     }
                     regular_message => regular_message,
                 };
-                debug!("sending message {:?} from client to tunnel server", message);
-                wtransport.send(message).await?;
+                let (tunnel, priority) = match message
+                    .connection_id()
+                    .and_then(|connection_id| connections.get(&connection_id))
+                {
+                    Some(connection) => (connection.tunnel, connection.priority),
+                    None => {
+                        error!("connection not found for message: {:?}", message);
+                        continue;
+                    }
+                };
+                if let Some(tunnel_sender) = tunnels.get_mut(&tunnel) {
+                    debug!("sending message {:?} to tunnel {}", message, tunnel);
+                    if let Err(err) = tunnel_sender.send((message, priority)).await {
+                        error!("cannot send to tunnel {}: {}", tunnel, err);
+                    }
+                } else {
+                    error!("tunnel not found: {}", tunnel);
+                }
             }
 
             debug!("no more messages, closing forward task");
 
-            wtransport.close().await?;
             receiver.close();
 
             Ok::<TcpWarpClientResult, io::Error>(connections)
         };
 
-        let bind_address = self.bind_address;
-
-        let _addresses = addresses.clone();
-        let processing_task = async move {
-            while let Some(Ok(message)) = rtransport.next().await {
-                process_host_to_client_message(
-                    message,
-                    sender.clone(),
-                    addresses.clone(),
-                    bind_address,
-                )
-                .await?;
+        let connections = forward_task.await;
+        self.set_session(None);
+
+        Ok((connections?, addresses))
+    }
+}
+
+/// Picks the tunnel with the least number of connections striped to it.
+///
+/// Ties are broken in round robin manner starting from `round` tunnel.
+fn least_loaded_tunnel(
+    tunnels: &HashMap<usize, Sender<(TcpWarpMessage, TcpWarpPriority)>>,
+    connections: &TcpWarpClientResult,
+    round: usize,
+) -> Option<usize> {
+    tunnels.keys().copied().min_by_key(|tunnel| {
+        let load = connections
+            .values()
+            .filter(|connection| connection.tunnel == *tunnel)
+            .count();
+        (load, tunnel.wrapping_sub(round))
+    })
+}
+
+/// Closes local connections which were striped to the lost tunnel.
+async fn disconnect_tunnel_connections(tunnel: usize, connections: &mut TcpWarpClientResult) {
+    let lost: Vec<Uuid> = connections
+        .iter()
+        .filter(|(_, connection)| connection.tunnel == tunnel)
+        .map(|(connection_id, _)| *connection_id)
+        .collect();
+
+    for connection_id in lost {
+        if let Some(mut connection) = connections.remove(&connection_id) {
+            debug!("{} closing, tunnel {} is lost", connection_id, tunnel);
+            if let Some(connection_sender) = connection.connected_sender.take() {
+                if let Err(err) = connection_sender.send(Err(io::Error::other("tunnel lost"))) {
+                    error!("cannot send to oneshot channel: {:?}", err);
+                }
+            }
+            if let Err(err) = connection
+                .sender
+                .send(TcpWarpMessage::DisconnectHost { connection_id })
+                .await
+            {
+                error!("cannot send to channel: {}", err);
             }
+        }
+    }
+}
 
-            debug!("processing task for host to client finished");
+/// State shared by all tunnels of the session.
+#[derive(Clone)]
+struct TunnelSession {
+    addresses: Arc<Vec<TcpWarpPortConnection>>,
+    bind_address: IpAddr,
+    listening: Arc<AtomicBool>,
+    addresses_bound: Published<TcpWarpClientAddresses>,
+    mappings: Published<TcpWarpClientMappings>,
+    services: Published<Vec<TcpWarpService>>,
+    all_services: bool,
+    capabilities: u32,
+    max_frame_size: usize,
+    connect_timeout: Duration,
+    listener_retry_delay: Duration,
+    /// Delay of reopening lost tunnel.
+    retry_delay: Duration,
+    metrics: Arc<TcpWarpMetrics>,
+    events: TcpWarpEvents,
+}
+
+/// Keeps single tunnel of the session alive, reopening it while session lasts.
+async fn tunnel_loop(
+    tunnel: usize,
+    mut stream: Option<TcpStream>,
+    tunnel_address: SocketAddr,
+    sender: Sender<TcpWarpMessage>,
+    session: TunnelSession,
+) {
+    loop {
+        if let Some(stream) = stream.take() {
+            let session_alive = run_tunnel(
+                tunnel,
+                stream,
+                tunnel_address,
+                sender.clone(),
+                session.clone(),
+            )
+            .await;
 
-            if let Err(err) = sender.send(TcpWarpMessage::Disconnect).await {
-                error!("could not send disconnect message {}", err);
+            if !session_alive {
+                debug!("session is over for tunnel {}", tunnel);
+                break;
             }
+        }
 
-            Ok::<(), io::Error>(())
-        };
+        session.events.emit(TcpWarpEvent::RetryScheduled {
+            tunnel: Some(tunnel),
+            delay: session.retry_delay,
+        });
+        delay_for(session.retry_delay).await;
+
+        debug!("reconnecting tunnel {}", tunnel);
+        match TcpStream::connect(&tunnel_address).await {
+            Ok(reconnected) => stream = Some(reconnected),
+            Err(err) => warn!("cannot reconnect tunnel {}: {}", tunnel, err),
+        }
+    }
+}
+
+async fn run_tunnel(
+    tunnel: usize,
+    stream: TcpStream,
+    tunnel_address: SocketAddr,
+    mut sender: Sender<TcpWarpMessage>,
+    session: TunnelSession,
+) -> bool {
+    let metrics = session.metrics.clone();
+    let events = session.events.clone();
+    let mut connected = false;
+    let codec = TcpWarpProto::with_capabilities(session.capabilities)
+        .with_max_frame_size(session.max_frame_size)
+        .with_metrics(metrics.clone());
+    let (mut wtransport, mut rtransport) = Framed::new(stream, codec).split();
+
+    let (tunnel_sender, mut tunnel_receiver) = channel(100);
+
+    let forward_task = async move {
+        debug!("{} in tunnel sender task", tunnel);
+
+        let mut scheduler = FrameScheduler::default();
+        while let Some(next) = scheduler.next(&mut tunnel_receiver).await {
+            match next {
+                Scheduled::Received((message, priority)) => scheduler.push(message, priority),
+                Scheduled::Write(message) => {
+                    debug!(
+                        "{} sending message {:?} from client to tunnel server",
+                        tunnel, message
+                    );
+                    wtransport.send(message).await?;
+                }
+            }
+        }
+
+        debug!("{} no more messages, closing tunnel sender task", tunnel);
+
+        wtransport.close().await?;
+        tunnel_receiver.close();
 
-        let (connections, _) = try_join!(forward_task, processing_task)?;
+        Ok::<(), io::Error>(())
+    };
 
-        Ok((connections, _addresses))
+    let processing_task = async move {
+        let mut tunnel_sender = Some(tunnel_sender);
+
+        while let Some(message) = rtransport.next().await {
+            let message = match message {
+                Ok(message) => message,
+                Err(err) => {
+                    error!("{} tunnel read error: {}", tunnel, err);
+                    break;
+                }
+            };
+            if let TcpWarpMessage::AddPorts(ref handshake) = message {
+                if let (Some(handshake), Some(tunnel_sender)) = (handshake, tunnel_sender.as_mut())
+                {
+                    let capabilities = handshake.capabilities & session.capabilities;
+                    debug!(
+                        "{} server handshake, capabilities: {}",
+                        tunnel, capabilities
+                    );
+                    if let Err(err) = tunnel_sender
+                        .send((
+                            TcpWarpMessage::Hello { capabilities },
+                            TcpWarpPriority::Normal,
+                        ))
+                        .await
+                    {
+                        error!("cannot send message Hello: {}", err);
+                    }
+                    debug!("server services: {:?}", handshake.services);
+                    let services = handshake.services.clone();
+                    session.services.update(|x| *x = services);
+                }
+                if let Some(tunnel_sender) = tunnel_sender.take() {
+                    connected = true;
+                    session.events.emit(TcpWarpEvent::TunnelConnected {
+                        tunnel,
+                        peer: tunnel_address,
+                    });
+                    if let Err(err) = sender
+                        .send(TcpWarpMessage::TunnelConnected {
+                            tunnel,
+                            sender: tunnel_sender,
+                        })
+                        .await
+                    {
+                        error!("cannot send message TunnelConnected: {}", err);
+                    }
+                }
+                if session.listening.swap(true, Ordering::SeqCst) {
+                    continue;
+                }
+            }
+            if let Err(err) =
+                process_host_to_client_message(message, sender.clone(), session.clone()).await
+            {
+                error!("{} stopping session: {}", tunnel, err);
+                if let Err(err) = sender.send(TcpWarpMessage::Disconnect).await {
+                    error!("could not send disconnect message {}", err);
+                }
+                break;
+            }
+        }
+
+        debug!("{} processing task for host to client finished", tunnel);
+
+        drop(tunnel_sender);
+        let session_alive = sender
+            .send(TcpWarpMessage::TunnelDisconnected { tunnel })
+            .await
+            .is_ok();
+        (session_alive, connected)
+    };
+
+    let (result, (session_alive, connected)) = join!(forward_task, processing_task);
+    if let Err(err) = result {
+        error!("{} tunnel transport error: {}", tunnel, err);
+    }
+    metrics.log_compression();
+    if connected {
+        events.emit(TcpWarpEvent::TunnelDisconnected {
+            tunnel,
+            peer: tunnel_address,
+        });
     }
+
+    session_alive
 }
 
 // async fn publish
 async fn process_host_to_client_message(
     message: TcpWarpMessage,
     mut sender: Sender<TcpWarpMessage>,
-    addresses: Arc<Vec<TcpWarpPortConnection>>,
-    bind_address: IpAddr,
+    session: TunnelSession,
 ) -> Result<(), io::Error> {
-    debug!("{} host to client: {:?}", bind_address, message);
+    debug!("{} host to client: {:?}", session.bind_address, message);
 
     match message {
         TcpWarpMessage::AddPorts(_) => {
-            for address in addresses.iter().cloned() {
-                let bind_address =
-                    SocketAddr::new(bind_address, address.client_port.unwrap_or(address.port));
-                let sender_ = sender.clone();
-
-                let mut listener = match TcpListener::bind(bind_address).await {
-                    Ok(listener) => listener,
-                    Err(err) => {
-                        error!("could not start listen {}: {}", bind_address, err);
-                        return Err(err);
-                    }
-                };
-
-                debug!("listen: {:?}", bind_address);
-
-                let abortable_feature = async move {
-                    let mut incoming = listener.incoming();
-
-                    while let Some(Ok(stream)) = incoming.next().await {
-                        let sender__ = sender_.clone();
-
-                        let _address = address.clone();
-                        spawn(async move {
-                            if let Err(e) = process(stream, sender__, _address).await {
-                                error!("failed to process connection; error = {}", e);
-                            }
-                        });
+            let mut mappings: Vec<_> = session
+                .addresses
+                .iter()
+                .flat_map(TcpWarpPortConnection::expand)
+                .collect();
+            if session.all_services {
+                for service in session.services.get() {
+                    if !mappings.iter().any(|x| x.service() == Some(&service.name)) {
+                        mappings.push(TcpWarpPortConnection::from_service(&service.name));
                     }
+                }
+            }
 
-                    debug!("done listen: {:?}", bind_address);
+            for address in mappings {
+                session.mappings.update(|mappings| {
+                    mappings.insert(address.clone(), TcpWarpMappingState::Pending)
+                });
 
-                    Ok::<(), io::Error>(())
-                };
-                let (abortable_listener, abort_handler) = abortable(abortable_feature);
+                let (abortable_listener, abort_handler) =
+                    abortable(listen_mapping(address, sender.clone(), session.clone()));
                 if let Err(err) = sender.send(TcpWarpMessage::Listener(abort_handler)).await {
                     error!("cannot send message Listener to forward channel: {}", err);
                 }
@@ -263,6 +986,14 @@ async fn process_host_to_client_message(
                 error!("cannot send message BytesHost to forward channel: {}", err);
             }
         }
+        TcpWarpMessage::DatagramHost { .. } => {
+            if let Err(err) = sender.send(message).await {
+                error!(
+                    "cannot send message DatagramHost to forward channel: {}",
+                    err
+                );
+            }
+        }
         TcpWarpMessage::Connected { .. } => {
             if let Err(err) = sender.send(message).await {
                 error!("cannot send message Connected to forward channel: {}", err);
@@ -289,15 +1020,329 @@ async fn process_host_to_client_message(
     Ok(())
 }
 
-async fn process(
-    stream: TcpStream,
+/// Listens for single mapping until session ends.
+///
+/// Failed bind is reported in mapping state and retried in background,
+/// so other mappings of the session keep serving.
+async fn listen_mapping(
+    address: TcpWarpPortConnection,
+    sender: Sender<TcpWarpMessage>,
+    session: TunnelSession,
+) {
+    let _guard = MappingGuard(address.clone(), session.mappings.clone());
+    let mut attempts = 0;
+
+    loop {
+        attempts += 1;
+        match bind_mapping(&address, sender.clone(), &session).await {
+            Ok(listener) => {
+                session.mappings.update(|mappings| {
+                    mappings.insert(address.clone(), TcpWarpMappingState::Listening)
+                });
+                session.events.emit(TcpWarpEvent::ListenerBound {
+                    mapping: address.clone(),
+                    address: session.addresses_bound.get().get(&address).copied(),
+                });
+                listener.await;
+                break;
+            }
+            Err(err) => {
+                error!(
+                    "could not start listen {}: {}, retrying in {:?}",
+                    address, err, session.listener_retry_delay
+                );
+                session.mappings.update(|mappings| {
+                    mappings.insert(
+                        address.clone(),
+                        TcpWarpMappingState::Failed {
+                            error: err.to_string(),
+                            attempts,
+                        },
+                    )
+                });
+                session.events.emit(TcpWarpEvent::ListenerFailed {
+                    mapping: address.clone(),
+                    error: err.to_string(),
+                    attempts,
+                });
+            }
+        }
+        delay_for(session.listener_retry_delay).await;
+    }
+}
+
+/// Marks mapping as stopped once its listener is gone.
+struct MappingGuard(TcpWarpPortConnection, Published<TcpWarpClientMappings>);
+
+impl Drop for MappingGuard {
+    fn drop(&mut self) {
+        let address = self.0.clone();
+        self.1
+            .update(|mappings| mappings.insert(address, TcpWarpMappingState::Stopped));
+    }
+}
+
+/// Binds listener for mapping, returning future serving accepted connections.
+async fn bind_mapping(
+    address: &TcpWarpPortConnection,
+    sender: Sender<TcpWarpMessage>,
+    session: &TunnelSession,
+) -> Result<future::BoxFuture<'static, ()>, TcpWarpError> {
+    let mapping = address.clone();
+    let address = resolve_service(address, session)?;
+
+    if let Some(client_path) = address.client_path.clone() {
+        return Ok(listen_unix(client_path, sender, address, session.clone())?.boxed());
+    }
+
+    let client_port = address.client_port.unwrap_or(address.port);
+    let reused_port = session
+        .addresses_bound
+        .get()
+        .get(&mapping)
+        .filter(|_| client_port == 0)
+        .map(SocketAddr::port);
+    let bind_address = SocketAddr::new(
+        address.bind.unwrap_or(session.bind_address),
+        reused_port.unwrap_or(client_port),
+    );
+    let ephemeral_address = SocketAddr::new(bind_address.ip(), 0);
+    let bind_error = |source| TcpWarpError::Bind {
+        address: bind_address.to_string(),
+        source,
+    };
+
+    if address.protocol == TcpWarpPortProtocol::Udp {
+        let socket = match UdpSocket::bind(bind_address).await {
+            Err(err) if reused_port.is_some() => {
+                warn!("cannot reuse port {}: {}", bind_address, err);
+                UdpSocket::bind(ephemeral_address)
+                    .await
+                    .map_err(bind_error)?
+            }
+            result => result.map_err(bind_error)?,
+        };
+        let bind_address = socket.local_addr()?;
+
+        info!("listen {} on {}", mapping, bind_address);
+        session
+            .addresses_bound
+            .update(|addresses| addresses.insert(mapping, bind_address));
+
+        return Ok(async move {
+            if let Err(e) = process_datagrams(socket, sender, address).await {
+                error!("failed to process datagrams; error = {}", e);
+            }
+            debug!("done listen udp: {:?}", bind_address);
+        }
+        .boxed());
+    }
+
+    let mut listener = match TcpListener::bind(bind_address).await {
+        Err(err) if reused_port.is_some() => {
+            warn!("cannot reuse port {}: {}", bind_address, err);
+            TcpListener::bind(ephemeral_address)
+                .await
+                .map_err(bind_error)?
+        }
+        result => result.map_err(bind_error)?,
+    };
+    let bind_address = listener.local_addr()?;
+
+    info!("listen {} on {}", mapping, bind_address);
+    session
+        .addresses_bound
+        .update(|addresses| addresses.insert(mapping, bind_address));
+
+    let session = session.clone();
+    let limit = MappingLimit::new(&address);
+    let rates = RateLimits::mapping(&address);
+
+    Ok(async move {
+        let mut incoming = listener.incoming();
+
+        while let Some(stream) = incoming.next().await {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    error!("cannot accept connection on {}: {}", bind_address, err);
+                    delay_for(ACCEPT_RETRY_DELAY).await;
+                    continue;
+                }
+            };
+            spawn(process_accepted(
+                stream,
+                sender.clone(),
+                address.clone(),
+                session.clone(),
+                limit.clone(),
+                rates.clone(),
+            ));
+        }
+
+        debug!("done listen: {:?}", bind_address);
+    }
+    .boxed())
+}
+
+/// Replaces service name of mapping with host and port from server catalog.
+fn resolve_service(
+    address: &TcpWarpPortConnection,
+    session: &TunnelSession,
+) -> Result<TcpWarpPortConnection, TcpWarpError> {
+    let name = match address.service() {
+        Some(name) => name,
+        None => return Ok(address.clone()),
+    };
+    match session.services.get().into_iter().find(|x| x.name == name) {
+        Some(service) => Ok(TcpWarpPortConnection {
+            client_port: address.client_port.or(Some(service.port)),
+            host: service.host,
+            port: service.port,
+            service: None,
+            ..address.clone()
+        }),
+        None => Err(TcpWarpError::UnknownService(name.to_owned())),
+    }
+}
+
+/// Removes unix socket file once listener is stopped.
+#[cfg(unix)]
+struct UnixSocketGuard(PathBuf);
+
+#[cfg(unix)]
+impl Drop for UnixSocketGuard {
+    fn drop(&mut self) {
+        if let Err(err) = std::fs::remove_file(&self.0) {
+            warn!("cannot remove unix socket {:?}: {}", self.0, err);
+        }
+    }
+}
+
+#[cfg(unix)]
+fn listen_unix(
+    client_path: PathBuf,
+    sender: Sender<TcpWarpMessage>,
+    address: TcpWarpPortConnection,
+    session: TunnelSession,
+) -> Result<impl Future<Output = ()>, TcpWarpError> {
+    let bind_error = |source| TcpWarpError::Bind {
+        address: client_path.display().to_string(),
+        source,
+    };
+    let mut listener = match UnixListener::bind(&client_path) {
+        Err(err)
+            if err.kind() == io::ErrorKind::AddrInUse
+                && is_socket(&client_path)
+                && std::os::unix::net::UnixStream::connect(&client_path).is_err() =>
+        {
+            warn!("removing stale unix socket {:?}", client_path);
+            std::fs::remove_file(&client_path)?;
+            UnixListener::bind(&client_path).map_err(bind_error)?
+        }
+        result => result.map_err(bind_error)?,
+    };
+    let guard = UnixSocketGuard(client_path);
+    let limit = MappingLimit::new(&address);
+    let rates = RateLimits::mapping(&address);
+
+    debug!("listen: {:?}", guard.0);
+
+    Ok(async move {
+        let mut incoming = listener.incoming();
+
+        while let Some(stream) = incoming.next().await {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    error!("cannot accept connection on {:?}: {}", guard.0, err);
+                    delay_for(ACCEPT_RETRY_DELAY).await;
+                    continue;
+                }
+            };
+            spawn(process_accepted(
+                stream,
+                sender.clone(),
+                address.clone(),
+                session.clone(),
+                limit.clone(),
+                rates.clone(),
+            ));
+        }
+
+        debug!("done listen: {:?}", guard.0);
+    })
+}
+
+/// Only socket files are removed as stale, other files in the way fail the bind.
+#[cfg(unix)]
+fn is_socket(path: &std::path::Path) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+    std::fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket())
+}
+
+#[cfg(not(unix))]
+fn listen_unix(
+    _client_path: PathBuf,
+    _sender: Sender<TcpWarpMessage>,
+    _address: TcpWarpPortConnection,
+    _session: TunnelSession,
+) -> Result<future::Pending<()>, TcpWarpError> {
+    Err(TcpWarpError::Unsupported("unix domain sockets"))
+}
+
+/// Processes accepted connection once connection limit of mapping lets it in.
+async fn process_accepted<S>(
+    stream: S,
+    sender: Sender<TcpWarpMessage>,
+    address: TcpWarpPortConnection,
+    session: TunnelSession,
+    limit: Option<MappingLimit>,
+    rates: RateLimits,
+) where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let _permit = match &limit {
+        Some(limit) => match limit.acquire().await {
+            Some(permit) => Some(permit),
+            None => {
+                warn!("connection limit of {} exceeded, rejecting", address);
+                return;
+            }
+        },
+        None => None,
+    };
+    if let Err(e) = process(stream, sender, address, session, rates).await {
+        error!("failed to process connection; error = {}", e);
+    }
+}
+
+async fn process<S>(
+    stream: S,
     mut host_sender: Sender<TcpWarpMessage>,
     address: TcpWarpPortConnection,
-) -> Result<(), Box<dyn Error>> {
+    session: TunnelSession,
+    rates: RateLimits,
+) -> Result<(), TcpWarpError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
     let connection_id = Uuid::new_v4();
+    let events = &session.events;
 
     debug!("new connection: {}", connection_id);
 
+    events.emit(TcpWarpEvent::ConnectionOpened {
+        connection_id,
+        target: address.to_string(),
+    });
+    let bytes_sent = AtomicU64::new(0);
+    let bytes_received = AtomicU64::new(0);
+    let (bytes_sent, bytes_received) = (&bytes_sent, &bytes_received);
+    let expiry = ConnectionExpiry::new(address.idle_timeout, address.max_lifetime);
+    let expiry = &expiry;
+    let rates = &rates;
+
     let (mut wtransport, mut rtransport) =
         Framed::new(stream, TcpWarpProtoClient { connection_id }).split();
 
@@ -313,7 +1358,12 @@ async fn process(
             match message {
                 TcpWarpMessage::ConnectFailure { .. } => break,
                 TcpWarpMessage::DisconnectHost { .. } => break,
-                TcpWarpMessage::BytesServer { data } => wtransport.send(data).await?,
+                TcpWarpMessage::BytesServer { data } => {
+                    bytes_received.fetch_add(data.len() as u64, Ordering::Relaxed);
+                    expiry.touch();
+                    rates.download(data.len()).await;
+                    wtransport.send(data).await?
+                }
                 _ => (),
             }
         }
@@ -341,20 +1391,36 @@ async fn process(
         })
         .await?;
 
-    let processing_task = async move {
-        match connected_receiver.await {
-            Err(err) => {
-                error!("{} connection error: {}", connection_id, err);
-                return Ok(());
-            }
-            Ok(Err(err)) => {
-                error!("{} connection error: {}", connection_id, err);
-                return Ok(());
+    let processing_task = async {
+        let connected = match timeout(session.connect_timeout, connected_receiver).await {
+            Ok(Ok(connected)) => connected,
+            Ok(Err(err)) => Err(io::Error::other(err)),
+            Err(_) => {
+                let err = TcpWarpConnectFailure::TimedOut;
+                warn!("{} {}, closing local connection", connection_id, err);
+                events.emit(TcpWarpEvent::ConnectionFailed {
+                    connection_id,
+                    error: err.to_string(),
+                });
+                return Err(err.into());
             }
-            _ => (),
+        };
+        if let Err(err) = connected {
+            error!("{} connection error: {}", connection_id, err);
+            events.emit(TcpWarpEvent::ConnectionFailed {
+                connection_id,
+                error: err.to_string(),
+            });
+            return Ok(());
         }
+        events.emit(TcpWarpEvent::ConnectionConnected { connection_id });
 
         while let Some(Ok(message)) = rtransport.next().await {
+            if let TcpWarpMessage::BytesClient { data, .. } = &message {
+                bytes_sent.fetch_add(data.len() as u64, Ordering::Relaxed);
+                expiry.touch();
+                rates.upload(data.len()).await;
+            }
             if let Err(err) = host_sender.send(message).await {
                 error!("{} {}", connection_id, err);
             }
@@ -375,9 +1441,119 @@ async fn process(
         Ok::<(), io::Error>(())
     };
 
-    try_join!(forward_task, processing_task)?;
+    let result = select! {
+        result = async { try_join!(forward_task, processing_task) } => result.map(|_| ()),
+        reason = expiry.expired() => {
+            info!("{} closing local connection after {}", connection_id, reason);
+            session.metrics.add_expired(reason);
+            host_sender
+                .send(TcpWarpMessage::DisconnectClient { connection_id })
+                .await
+                .map_err(io::Error::other)
+        }
+    };
+
+    events.emit(TcpWarpEvent::ConnectionClosed {
+        connection_id,
+        bytes_sent: bytes_sent.load(Ordering::Relaxed),
+        bytes_received: bytes_received.load(Ordering::Relaxed),
+    });
+    result?;
 
     debug!("{} full complete process", connection_id);
 
     Ok(())
 }
+
+async fn process_datagrams(
+    mut socket: UdpSocket,
+    mut host_sender: Sender<TcpWarpMessage>,
+    address: TcpWarpPortConnection,
+) -> Result<(), TcpWarpError> {
+    let (client_sender, mut client_receiver) = channel(100);
+
+    let mut peers = HashMap::new();
+    let mut associations = HashMap::new();
+    let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+
+    loop {
+        select! {
+            received = socket.recv_from(&mut buf) => {
+                let (len, peer) = match received {
+                    Ok(received) => received,
+                    Err(err) => {
+                        debug!("cannot receive datagram: {}", err);
+                        continue;
+                    }
+                };
+                let connection_id = match peers.get(&peer) {
+                    Some(connection_id) => *connection_id,
+                    None => {
+                        let connection_id = Uuid::new_v4();
+                        debug!("{} new datagram association: {}", connection_id, peer);
+                        peers.insert(peer, connection_id);
+                        associations.insert(connection_id, peer);
+                        host_sender
+                            .send(TcpWarpMessage::DatagramConnect {
+                                connection_id,
+                                priority: address.priority,
+                                sender: client_sender.clone(),
+                            })
+                            .await?;
+                        connection_id
+                    }
+                };
+                host_sender
+                    .send(TcpWarpMessage::DatagramClient {
+                        connection_id,
+                        host: address.host.clone(),
+                        port: address.port,
+                        data: BytesMut::from(&buf[..len]),
+                    })
+                    .await?;
+            }
+            message = client_receiver.next() => match message {
+                Some(TcpWarpMessage::DatagramHost { connection_id, data }) => {
+                    if let Some(peer) = associations.get(&connection_id) {
+                        if let Err(err) = socket.send_to(&data, peer).await {
+                            debug!("{} cannot send datagram: {}", connection_id, err);
+                        }
+                    }
+                }
+                Some(TcpWarpMessage::DisconnectHost { connection_id }) => {
+                    debug!("{} datagram association closed", connection_id);
+                    if let Some(peer) = associations.remove(&connection_id) {
+                        peers.remove(&peer);
+                    }
+                }
+                Some(_) => (),
+                None => break,
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builder_defaults() {
+        assert_eq!(
+            "0.0.0.0".parse::<IpAddr>().unwrap(),
+            TcpWarpClientBuilder::DEFAULT_BIND_ADDRESS
+        );
+        assert_eq!(
+            "127.0.0.1:18000".parse::<SocketAddr>().unwrap(),
+            TcpWarpClientBuilder::DEFAULT_TUNNEL_ADDRESS
+        );
+        assert_eq!(1, TcpWarpClientBuilder::DEFAULT_TUNNELS);
+        assert_eq!(
+            TcpWarpCompression::None,
+            TcpWarpClientBuilder::DEFAULT_COMPRESSION
+        );
+        assert_eq!(16 * 1024 * 1024, TcpWarpProto::DEFAULT_MAX_FRAME_SIZE);
+    }
+}
@@ -0,0 +1,304 @@
+use super::*;
+
+/// Compression of tunnel payloads.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TcpWarpCompression {
+    #[default]
+    None,
+    Deflate,
+    Zstd,
+}
+
+impl TcpWarpCompression {
+    /// Handshake capability of compression algorithm.
+    pub fn capability(self) -> u32 {
+        match self {
+            TcpWarpCompression::None => 0,
+            TcpWarpCompression::Deflate => TcpWarpProto::CAPABILITY_DEFLATE,
+            TcpWarpCompression::Zstd => TcpWarpProto::CAPABILITY_ZSTD,
+        }
+    }
+
+    /// Preferred compression of agreed capabilities.
+    pub fn from_capabilities(capabilities: u32) -> Self {
+        if capabilities & TcpWarpProto::CAPABILITY_ZSTD != 0 {
+            TcpWarpCompression::Zstd
+        } else if capabilities & TcpWarpProto::CAPABILITY_DEFLATE != 0 {
+            TcpWarpCompression::Deflate
+        } else {
+            TcpWarpCompression::None
+        }
+    }
+}
+
+impl FromStr for TcpWarpCompression {
+    type Err = TcpWarpError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(TcpWarpCompression::None),
+            "deflate" => Ok(TcpWarpCompression::Deflate),
+            "zstd" => Ok(TcpWarpCompression::Zstd),
+            _ => Err(TcpWarpError::parse(
+                s,
+                "unknown compression, expected none, deflate or zstd",
+            )),
+        }
+    }
+}
+
+impl fmt::Display for TcpWarpCompression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TcpWarpCompression::None => write!(f, "none"),
+            TcpWarpCompression::Deflate => write!(f, "deflate"),
+            TcpWarpCompression::Zstd => write!(f, "zstd"),
+        }
+    }
+}
+
+#[cfg(feature = "zstd")]
+const SCRATCH_SIZE: usize = 64 * 1024;
+
+/// Streaming compressor of one tunnel direction, flushed after every frame.
+pub(crate) enum Compressor {
+    #[cfg(feature = "deflate")]
+    Deflate(flate2::Compress),
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::stream::raw::Encoder<'static>, Vec<u8>),
+}
+
+/// Streaming decompressor of one tunnel direction.
+pub(crate) enum Decompressor {
+    #[cfg(feature = "deflate")]
+    Deflate(flate2::Decompress),
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::stream::raw::Decoder<'static>, Vec<u8>),
+}
+
+impl Compressor {
+    pub(crate) fn new(compression: TcpWarpCompression) -> io::Result<Option<Self>> {
+        Ok(match compression {
+            #[cfg(feature = "deflate")]
+            TcpWarpCompression::Deflate => Some(Compressor::Deflate(flate2::Compress::new(
+                flate2::Compression::default(),
+                false,
+            ))),
+            #[cfg(feature = "zstd")]
+            TcpWarpCompression::Zstd => Some(Compressor::Zstd(
+                zstd::stream::raw::Encoder::new(zstd::DEFAULT_COMPRESSION_LEVEL)?,
+                vec![0; SCRATCH_SIZE],
+            )),
+            _ => None,
+        })
+    }
+
+    /// Appends compressed data to `dst` so that peer can decompress it entirely.
+    #[cfg_attr(
+        not(any(feature = "deflate", feature = "zstd")),
+        allow(unused_variables, clippy::ptr_arg)
+    )]
+    pub(crate) fn compress(&mut self, data: &[u8], dst: &mut Vec<u8>) -> io::Result<()> {
+        match *self {
+            #[cfg(feature = "deflate")]
+            Compressor::Deflate(ref mut compress) => {
+                let mut consumed = 0;
+                loop {
+                    dst.reserve(data.len() - consumed + 64);
+                    let before = compress.total_in();
+                    compress
+                        .compress_vec(&data[consumed..], dst, flate2::FlushCompress::Sync)
+                        .map_err(io::Error::other)?;
+                    consumed += (compress.total_in() - before) as usize;
+                    if consumed == data.len() && dst.len() < dst.capacity() {
+                        return Ok(());
+                    }
+                }
+            }
+            #[cfg(feature = "zstd")]
+            Compressor::Zstd(ref mut encoder, ref mut scratch) => {
+                use zstd::stream::raw::{InBuffer, Operation, OutBuffer};
+
+                let mut input = InBuffer::around(data);
+                while input.pos() < data.len() {
+                    let mut output = OutBuffer::around(&mut scratch[..]);
+                    encoder.run(&mut input, &mut output)?;
+                    let written = output.pos();
+                    dst.extend_from_slice(&scratch[..written]);
+                }
+                loop {
+                    let mut output = OutBuffer::around(&mut scratch[..]);
+                    let remaining = encoder.flush(&mut output)?;
+                    let written = output.pos();
+                    dst.extend_from_slice(&scratch[..written]);
+                    if remaining == 0 {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Decompressor {
+    pub(crate) fn new(compression: TcpWarpCompression) -> io::Result<Option<Self>> {
+        Ok(match compression {
+            #[cfg(feature = "deflate")]
+            TcpWarpCompression::Deflate => {
+                Some(Decompressor::Deflate(flate2::Decompress::new(false)))
+            }
+            #[cfg(feature = "zstd")]
+            TcpWarpCompression::Zstd => Some(Decompressor::Zstd(
+                zstd::stream::raw::Decoder::new()?,
+                vec![0; SCRATCH_SIZE],
+            )),
+            _ => None,
+        })
+    }
+
+    /// Decompresses single frame, fails if payload grows over `max_size`.
+    #[cfg_attr(
+        not(any(feature = "deflate", feature = "zstd")),
+        allow(unused_variables)
+    )]
+    pub(crate) fn decompress(&mut self, data: &[u8], max_size: usize) -> io::Result<BytesMut> {
+        match *self {
+            #[cfg(feature = "deflate")]
+            Decompressor::Deflate(ref mut decompress) => {
+                let mut output = Vec::with_capacity(data.len() * 4 + 64);
+                let mut consumed = 0;
+                loop {
+                    let before = decompress.total_in();
+                    decompress
+                        .decompress_vec(
+                            &data[consumed..],
+                            &mut output,
+                            flate2::FlushDecompress::Sync,
+                        )
+                        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+                    consumed += (decompress.total_in() - before) as usize;
+                    if consumed == data.len() && output.len() < output.capacity() {
+                        break;
+                    }
+                    if output.len() > max_size {
+                        return Err(TcpWarpProtoError::FrameTooLarge {
+                            size: output.len(),
+                            max: max_size,
+                        }
+                        .into());
+                    }
+                    output.reserve(output.capacity());
+                }
+                Ok(BytesMut::from(&output[..]))
+            }
+            #[cfg(feature = "zstd")]
+            Decompressor::Zstd(ref mut decoder, ref mut scratch) => {
+                use zstd::stream::raw::{InBuffer, Operation, OutBuffer};
+
+                let mut result = BytesMut::new();
+                let mut input = InBuffer::around(data);
+                loop {
+                    let mut output = OutBuffer::around(&mut scratch[..]);
+                    decoder.run(&mut input, &mut output)?;
+                    let written = output.pos();
+                    result.extend_from_slice(&scratch[..written]);
+                    if input.pos() == data.len() && written < scratch.len() {
+                        break;
+                    }
+                    if result.len() > max_size {
+                        return Err(TcpWarpProtoError::FrameTooLarge {
+                            size: result.len(),
+                            max: max_size,
+                        }
+                        .into());
+                    }
+                }
+                Ok(result)
+            }
+        }
+    }
+}
+
+#[cfg(all(test, any(feature = "deflate", feature = "zstd")))]
+mod tests {
+    use super::*;
+    use crate::proto::tests::transfer;
+
+    fn compressed_transfer(compression: TcpWarpCompression) {
+        let metrics = Arc::new(TcpWarpMetrics::default());
+        let capabilities = TcpWarpProto::CAPABILITY_COMPACT_IDS | compression.capability();
+        let mut client = TcpWarpProto::with_capabilities(capabilities);
+        let mut server = TcpWarpProto::new().with_metrics(metrics.clone());
+        transfer(
+            &mut server,
+            &mut client,
+            TcpWarpMessage::Hello {
+                capabilities: TcpWarpProto::CAPABILITIES,
+            },
+        );
+        transfer(
+            &mut client,
+            &mut server,
+            TcpWarpMessage::Hello { capabilities },
+        );
+
+        let compressed_id = Uuid::new_v4();
+        let raw_id = Uuid::new_v4();
+        for (connection_id, compress) in &[(compressed_id, true), (raw_id, false)] {
+            transfer(
+                &mut client,
+                &mut server,
+                TcpWarpMessage::HostConnectPath {
+                    connection_id: *connection_id,
+                    path: "/var/run/docker.sock".into(),
+                    compress: *compress,
+                    priority: TcpWarpPriority::Normal,
+                },
+            );
+        }
+
+        let json = br#"{"Id":"4fa6e0f0c678","Names":["/whoami"],"Image":"containous/whoami"}"#;
+        let mut compressed_len = 0;
+        for _ in 0..10 {
+            for connection_id in &[compressed_id, raw_id] {
+                let (len, message) = transfer(
+                    &mut server,
+                    &mut client,
+                    TcpWarpMessage::BytesHost {
+                        connection_id: *connection_id,
+                        data: BytesMut::from(&json[..]),
+                    },
+                );
+                match message {
+                    TcpWarpMessage::BytesHost {
+                        connection_id: id,
+                        data,
+                    } => assert_eq!((*connection_id, &json[..]), (id, &data[..])),
+                    other => panic!("unexpected message: {:?}", other),
+                }
+                if *connection_id == compressed_id {
+                    compressed_len = len;
+                } else {
+                    assert_eq!(1 + 1 + 1 + json.len(), len);
+                }
+            }
+        }
+        assert!(compressed_len < json.len() / 2);
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(10 * json.len() as u64, snapshot.sent_uncompressed);
+        assert!(snapshot.compression_savings() > 0);
+    }
+
+    #[cfg(feature = "deflate")]
+    #[test]
+    fn deflate_compression() {
+        compressed_transfer(TcpWarpCompression::Deflate);
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn zstd_compression() {
+        compressed_transfer(TcpWarpCompression::Zstd);
+    }
+}
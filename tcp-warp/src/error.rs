@@ -0,0 +1,144 @@
+use super::*;
+use std::io;
+
+/// Error of tunnel client, server and parsers.
+#[derive(Debug)]
+pub enum TcpWarpError {
+    /// Tunnel server cannot be connected.
+    TunnelUnreachable {
+        address: SocketAddr,
+        source: io::Error,
+    },
+    /// Listener cannot be bound, for example address is already in use.
+    Bind { address: String, source: io::Error },
+    /// Audit log cannot be opened.
+    AuditLog { log: String, source: io::Error },
+    /// Peer does not follow tunnel protocol.
+    Protocol(TcpWarpProtoError),
+    /// Target of connection cannot be connected.
+    ConnectFailure(TcpWarpConnectFailure),
+    /// Port mapping, service or option cannot be parsed.
+    Parse { input: String, reason: &'static str },
+    /// Mapping refers to service which is not advertised by server.
+    UnknownService(String),
+    /// Feature is not available on this platform or in this build.
+    Unsupported(&'static str),
+    /// Session is over while message was sent to it.
+    SessionClosed,
+    /// Other I/O error.
+    Io(io::Error),
+}
+
+impl TcpWarpError {
+    pub(crate) fn parse(input: &str, reason: &'static str) -> Self {
+        TcpWarpError::Parse {
+            input: input.to_owned(),
+            reason,
+        }
+    }
+}
+
+impl fmt::Display for TcpWarpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TcpWarpError::TunnelUnreachable { address, source } => {
+                write!(f, "cannot connect to tunnel {}: {}", address, source)
+            }
+            TcpWarpError::Bind { address, source } => {
+                write!(f, "cannot bind {}: {}", address, source)
+            }
+            TcpWarpError::AuditLog { log, source } => {
+                write!(f, "cannot open audit log {}: {}", log, source)
+            }
+            TcpWarpError::Protocol(err) => write!(f, "protocol error: {}", err),
+            TcpWarpError::ConnectFailure(reason) => reason.fmt(f),
+            TcpWarpError::Parse { input, reason } => write!(f, "{}: {}", reason, input),
+            TcpWarpError::UnknownService(name) => write!(f, "unknown service {}", name),
+            TcpWarpError::Unsupported(feature) => write!(f, "{} are not supported", feature),
+            TcpWarpError::SessionClosed => write!(f, "session is closed"),
+            TcpWarpError::Io(err) => err.fmt(f),
+        }
+    }
+}
+
+impl Error for TcpWarpError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            TcpWarpError::TunnelUnreachable { source, .. }
+            | TcpWarpError::Bind { source, .. }
+            | TcpWarpError::AuditLog { source, .. } => Some(source),
+            TcpWarpError::Protocol(err) => Some(err),
+            TcpWarpError::ConnectFailure(reason) => Some(reason),
+            TcpWarpError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// Protocol errors and connect failures come wrapped in I/O errors and are unwrapped back.
+impl From<io::Error> for TcpWarpError {
+    fn from(err: io::Error) -> Self {
+        if let Some(inner) = err.get_ref() {
+            if let Some(protocol) = inner.downcast_ref::<TcpWarpProtoError>() {
+                return TcpWarpError::Protocol(protocol.clone());
+            }
+            if let Some(reason) = inner.downcast_ref::<TcpWarpConnectFailure>() {
+                return TcpWarpError::ConnectFailure(*reason);
+            }
+        }
+        TcpWarpError::Io(err)
+    }
+}
+
+impl From<TcpWarpProtoError> for TcpWarpError {
+    fn from(err: TcpWarpProtoError) -> Self {
+        TcpWarpError::Protocol(err)
+    }
+}
+
+impl<T> From<SendError<T>> for TcpWarpError {
+    fn from(_: SendError<T>) -> Self {
+        TcpWarpError::SessionClosed
+    }
+}
+
+impl From<oneshot::error::RecvError> for TcpWarpError {
+    fn from(_: oneshot::error::RecvError) -> Self {
+        TcpWarpError::SessionClosed
+    }
+}
+
+impl From<TcpWarpError> for io::Error {
+    fn from(err: TcpWarpError) -> Self {
+        match err {
+            TcpWarpError::Io(err) => err,
+            TcpWarpError::Protocol(err) => err.into(),
+            TcpWarpError::ConnectFailure(reason) => reason.into(),
+            err => io::Error::other(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn protocol_error_is_unwrapped() {
+        let mut codec = TcpWarpProto::new();
+        let err: TcpWarpError = codec
+            .decode(&mut BytesMut::from(&[42][..]))
+            .unwrap_err()
+            .into();
+        match err {
+            TcpWarpError::Protocol(TcpWarpProtoError::UnknownOpcode(42)) => {}
+            other => panic!("unexpected error: {:?}", other),
+        }
+
+        let err: TcpWarpError = io::Error::from(io::ErrorKind::ConnectionReset).into();
+        match err {
+            TcpWarpError::Io(err) => assert_eq!(io::ErrorKind::ConnectionReset, err.kind()),
+            other => panic!("unexpected error: {:?}", other),
+        }
+    }
+}
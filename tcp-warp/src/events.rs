@@ -0,0 +1,66 @@
+use super::*;
+
+/// Number of events kept for slow subscribers, older events are dropped for them.
+const EVENTS_CAPACITY: usize = 1024;
+
+/// Event of client or server published to subscribers.
+///
+/// Subscribe with [`TcpWarpClient::events`] or [`TcpWarpServer::events`].
+/// Byte totals count payload forwarded through tunnel, before compression.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TcpWarpEvent {
+    /// Tunnel handshake is done, peer is server on client side and client on server side.
+    TunnelConnected { tunnel: usize, peer: SocketAddr },
+    /// Tunnel connection is closed.
+    TunnelDisconnected { tunnel: usize, peer: SocketAddr },
+    /// Client listener of mapping is bound, address is `None` for unix socket.
+    ListenerBound {
+        mapping: TcpWarpPortConnection,
+        address: Option<SocketAddr>,
+    },
+    /// Client listener of mapping cannot be bound, bind is retried in background.
+    ListenerFailed {
+        mapping: TcpWarpPortConnection,
+        error: String,
+        attempts: usize,
+    },
+    /// Connection is accepted on client or connect to target is started on server.
+    ConnectionOpened { connection_id: Uuid, target: String },
+    /// Target of connection is connected.
+    ConnectionConnected { connection_id: Uuid },
+    /// Target of connection cannot be connected.
+    ConnectionFailed { connection_id: Uuid, error: String },
+    /// Connection is closed.
+    ConnectionClosed {
+        connection_id: Uuid,
+        bytes_sent: u64,
+        bytes_received: u64,
+    },
+    /// Client reconnects after delay, `tunnel` is `None` when whole session is reconnected.
+    RetryScheduled {
+        tunnel: Option<usize>,
+        delay: Duration,
+    },
+}
+
+/// Publisher of events shared by all tasks of client or server.
+#[derive(Clone)]
+pub(crate) struct TcpWarpEvents {
+    sender: broadcast::Sender<TcpWarpEvent>,
+}
+
+impl TcpWarpEvents {
+    pub(crate) fn new() -> Self {
+        let (sender, _) = broadcast::channel(EVENTS_CAPACITY);
+        Self { sender }
+    }
+
+    pub(crate) fn subscribe(&self) -> broadcast::Receiver<TcpWarpEvent> {
+        self.sender.subscribe()
+    }
+
+    /// Publishes event, events without subscribers are dropped.
+    pub(crate) fn emit(&self, event: TcpWarpEvent) {
+        let _ = self.sender.send(event);
+    }
+}
@@ -0,0 +1,67 @@
+use super::*;
+use std::time::Instant;
+
+/// Why connection was closed by [`ConnectionExpiry`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ExpiryReason {
+    Idle,
+    Lifetime,
+}
+
+impl fmt::Display for ExpiryReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExpiryReason::Idle => write!(f, "idle timeout"),
+            ExpiryReason::Lifetime => write!(f, "max lifetime"),
+        }
+    }
+}
+
+/// Idle timeout and max lifetime of single connection.
+///
+/// Forwarding tasks call [`ConnectionExpiry::touch`] for every chunk in either direction.
+pub(crate) struct ConnectionExpiry {
+    idle_timeout: Option<Duration>,
+    max_lifetime: Option<Duration>,
+    started: Instant,
+    /// Milliseconds from start to the last forwarded chunk.
+    last_active: AtomicU64,
+}
+
+impl ConnectionExpiry {
+    pub(crate) fn new(idle_timeout: Option<Duration>, max_lifetime: Option<Duration>) -> Self {
+        Self {
+            idle_timeout,
+            max_lifetime,
+            started: Instant::now(),
+            last_active: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn touch(&self) {
+        self.last_active
+            .store(self.started.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// Resolves once connection is expired, never resolves without limits.
+    pub(crate) async fn expired(&self) -> ExpiryReason {
+        loop {
+            let idle_deadline = self.idle_timeout.map(|idle_timeout| {
+                Duration::from_millis(self.last_active.load(Ordering::Relaxed)) + idle_timeout
+            });
+            let deadline = match (idle_deadline, self.max_lifetime) {
+                (Some(idle), Some(lifetime)) => idle.min(lifetime),
+                (Some(deadline), None) | (None, Some(deadline)) => deadline,
+                (None, None) => return future::pending().await,
+            };
+            let elapsed = self.started.elapsed();
+            if elapsed >= deadline {
+                return match self.max_lifetime {
+                    Some(lifetime) if elapsed >= lifetime => ExpiryReason::Lifetime,
+                    _ => ExpiryReason::Idle,
+                };
+            }
+            delay_for(deadline - elapsed).await;
+        }
+    }
+}
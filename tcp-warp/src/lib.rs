@@ -12,6 +12,13 @@ nc 127.0.0.1 8080
 1. A userspace tunnel to connect ports on client network with connections available on server side.
 1. Uses only single port.
 1. Client push of addresses to connect from server.
+1. Parallel tunnel connections with client connections striped across them.
+1. UDP port forwarding.
+1. Unix domain socket endpoints on client and server side.
+1. Port range mappings.
+1. Client ports chosen by OS with reporting of bound addresses.
+1. Catalog of named services advertised by server.
+1. Per-tunnel compression with per-mapping opt-out.
 
 ## Installation
 
@@ -67,6 +74,182 @@ Both client and server have address on which they listen for incoming connection
 
 Next we look at more specific example.
 
+### Parallel tunnels
+
+Single tunnel connection is limited by one congestion window. Client can open several tunnel connections to the same server with `--tunnels`:
+
+```bash
+tcp-warp client -c 8080:towel.blinkenlights.nl:23 --tunnels 4
+```
+
+New connections are placed on the least loaded tunnel. If one of the tunnels is lost, only connections placed on it are closed, others continue to work and lost tunnel is reopened in background after `--retry-interval`, or after one second without `--retry`.
+
+### UDP port forwarding
+
+Prefix mapping with `udp:` to forward datagrams, for example to reach DNS server behind the server:
+
+```bash
+tcp-warp client -c udp:5353:dns:53
+dig @127.0.0.1 -p 5353 example.com
+```
+
+Server keeps separate UDP association for every client address. Association is closed after `--udp-idle-timeout` seconds (default 60) without datagrams.
+
+### Bind address per mapping and IPv6
+
+Full mapping format is `[udp:|tcp:][[bind_address:]client_port:][host:]host_port`. Bind address overrides `--bind` for single mapping. IPv6 addresses are written in brackets:
+
+```bash
+tcp-warp client -c 127.0.0.1:8080:whoami:80 -c [::1]:8081:[fe80::1]:80
+```
+
+### Port ranges
+
+Range of ports can be mapped with single `-c`, both ranges must have the same length:
+
+```bash
+tcp-warp client -c 9000-9010:broker:9000-9010
+```
+
+### Ephemeral client ports
+
+Client port `0` lets OS choose free port. Chosen addresses are logged and can be printed as JSON with `--addresses-json` or written to file with `--addresses-file`:
+
+```bash
+$ tcp-warp client -c 0:whoami:80 --addresses-json
+{"0:whoami:80":"0.0.0.0:43567"}
+```
+
+With `--retry` client tries to keep the same port after reconnect.
+
+### Service catalog
+
+Server can advertise named services, so clients do not need to know hosts and ports behind the server:
+
+```bash
+tcp-warp server -s whoami=whoami:80 -s docker=172.18.0.1:2375
+tcp-warp client -c 8080:whoami
+```
+
+Mapping with service name only binds client port equal to service port. `--all-services` binds every advertised service:
+
+```bash
+tcp-warp client --all-services
+```
+
+### Compression
+
+Client can ask for compression of tunnel traffic with `--compression deflate` or `--compression zstd`. Server agrees unless started with `--no-compression`:
+
+```bash
+tcp-warp client -c 8080:whoami --compression zstd
+```
+
+Already compressed or encrypted traffic can skip compression with `,nocompress` suffix of mapping:
+
+```bash
+tcp-warp client -c 8443:web:443,nocompress --compression zstd
+```
+
+Bytes saved by compression are logged when tunnel closes.
+
+### Connect timeouts
+
+Server gives up connecting target after `--connect-timeout` seconds (default 10) and reports timeout to client. Client waits for answer of server `--connect-timeout` seconds (default 30) and closes local connection if none comes back:
+
+```bash
+tcp-warp server --connect-timeout 5
+tcp-warp client -c 8080:whoami:80 --connect-timeout 15
+```
+
+### Idle timeout and max lifetime
+
+Forgotten connections can be closed after seconds without bytes in either direction or after seconds since open. Client sets limits per mapping with `,idle=SECS` and `,lifetime=SECS` suffixes, server sets them for all connections with `--idle-timeout` and `--max-lifetime`:
+
+```bash
+tcp-warp client -c 5432:db:5432,idle=300,lifetime=3600
+tcp-warp server --idle-timeout 600
+```
+
+Expired connections are closed like any other and counted in metrics.
+
+### Connection limits
+
+Server caps concurrent connections with `--max-connections` for all clients, `--max-tunnel-connections` for single tunnel and `--max-client-connections` for all tunnels from one client IP address. Connects over limit fail with "limit exceeded" reason and close local connection on client:
+
+```bash
+tcp-warp server --max-client-connections 100
+```
+
+Client limits concurrent connections of mapping with `,max=N` suffix. Connections over limit are closed right away, or wait for free slot with `,queue`:
+
+```bash
+tcp-warp client -c 8080:whoami:80,max=10,queue
+```
+
+### Rate limits
+
+Server limits bytes per second in each direction with `--tunnel-rate` for single tunnel and `--client-rate` for all tunnels from one client IP address. Client limits bytes per second in each direction for connections of mapping with `,rate=N` suffix, so bulk upload does not saturate uplink shared with interactive sessions:
+
+```bash
+tcp-warp server --client-rate 10485760
+tcp-warp client -c 5000:registry:5000,rate=1048576 -c 2222:host:22
+```
+
+### Connection priorities
+
+Frames of all connections share tunnel, large payloads are split into chunks and written by weighted fair queuing, so keystrokes are not stuck behind bulk transfer. Client mapping sets priority of its connections with `,priority=interactive`, `normal` (default) or `bulk` suffix, interactive connections get 4 times the share of normal ones and normal get 4 times the share of bulk ones. Client tells priority of connection to server, which schedules frames in the other direction by it as well, servers and clients of older versions treat every connection as normal:
+
+```bash
+tcp-warp client -c 2222:bastion:22,priority=interactive -c 5000:registry:5000,priority=bulk
+```
+
+### Audit log
+
+Server writes JSON record of every connect request of clients with `--audit-log` to file or to local syslog with `--audit-log syslog`. Record is written once connection is closed or failed and contains timestamp, tunnel number and address of client, requested target, resolved target IP address, outcome with failure reason, duration and bytes in each direction:
+
+```bash
+tcp-warp server --audit-log /var/log/tcp-warp/audit.jsonl
+```
+
+```json
+{"timestamp":"2020-01-01T12:00:00.000Z","connection_id":"5b8a2fa5-8e66-4c2e-9d0e-3c3e5b2a7c11","tunnel":1,"client":"192.0.2.10:51234","target":"db:5432","resolved":"10.0.0.5","outcome":"connected","reason":null,"duration_ms":61234,"bytes_client_to_host":4096,"bytes_host_to_client":1048576}
+```
+
+### Log format
+
+Logs are written to stderr as text by default. With `--log-format json` every record is a JSON object with `timestamp`, `level`, `target`, `event`, `connection_id`, `mapping`, `peer` and `message` fields, fields which do not apply to record are `null`. Events of tunnels, listeners and connections are logged at info level with `event` name. Payloads of connections never reach logs in either format, messages show only payload length:
+
+```bash
+tcp-warp -vv --log-format json server
+```
+
+```json
+{"timestamp":"2020-01-01T12:00:00.000000000Z","level":"INFO","target":"tcpwarp::events","event":"connection_opened","connection_id":"5b8a2fa5-8e66-4c2e-9d0e-3c3e5b2a7c11","mapping":"db:5432","peer":null,"message":"5b8a2fa5-8e66-4c2e-9d0e-3c3e5b2a7c11 connection opened"}
+```
+
+### Unix domain sockets
+
+Paths starting with `/` or `.` are treated as Unix domain sockets on both sides of mapping. Server connects only to sockets allowed with `--allow-unix-socket`, which takes socket or directory of sockets and may be repeated:
+
+```bash
+tcp-warp server --allow-unix-socket /var/run/docker.sock
+```
+
+For example, Docker daemon socket can be forwarded directly, without exposing Docker over TCP:
+
+```bash
+tcp-warp client -c 2375:/var/run/docker.sock
+export DOCKER_HOST=tcp://127.0.0.1:2375
+```
+
+or to local Unix socket:
+
+```bash
+tcp-warp client -c /tmp/docker.sock:/var/run/docker.sock
+export DOCKER_HOST=unix:///tmp/docker.sock
+```
+
 ### Use case: running Docker on machine without Docker daemon installed with Docker daemon behind SSH
 
 Background:
@@ -166,6 +349,7 @@ Simple example with `whoami` service:
 use bytes::{Buf, BufMut, BytesMut};
 use futures::{
     future::{abortable, AbortHandle},
+    join,
     prelude::*,
     try_join,
 };
@@ -174,143 +358,733 @@ use std::{
     collections::HashMap,
     convert::TryInto,
     error::Error,
-    net::{IpAddr, SocketAddr},
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::PathBuf,
     str::FromStr,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
     time::Duration,
 };
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
 use tokio::{
-    net::{TcpListener, TcpStream, ToSocketAddrs},
+    io::{AsyncRead, AsyncWrite},
+    net::{lookup_host, TcpListener, TcpStream, UdpSocket},
     prelude::*,
-    spawn,
+    select, spawn,
     sync::{
-        mpsc::{channel, Sender},
-        oneshot,
+        broadcast,
+        mpsc::{channel, error::SendError, Receiver, Sender},
+        oneshot, watch,
     },
-    time::delay_for,
+    time::{delay_for, timeout},
 };
 use tokio_util::codec::{Decoder, Encoder, Framed};
 use uuid::Uuid;
 
+/// Largest payload of UDP datagram.
+const MAX_DATAGRAM_SIZE: usize = 65536;
+
+mod audit;
 mod client;
+mod compression;
+mod error;
+mod events;
+mod expiry;
+mod limits;
+mod metrics;
 mod proto;
+mod rate;
+mod scheduler;
 mod server;
+mod stream;
+#[cfg(feature = "test-support")]
+pub mod test_support;
+
+use audit::{AuditRecord, AuditWriter};
+pub use client::{
+    TcpWarpClient, TcpWarpClientAddresses, TcpWarpClientBuilder, TcpWarpClientMappings,
+    TcpWarpMappingState,
+};
+use compression::{Compressor, Decompressor};
+use events::TcpWarpEvents;
+use expiry::{ConnectionExpiry, ExpiryReason};
+use limits::{MappingLimit, ServerLimits};
+use rate::{ClientRates, RateLimit, RateLimits};
+use scheduler::{FrameScheduler, Scheduled};
+
+pub use audit::TcpWarpAuditLog;
+pub use compression::TcpWarpCompression;
+pub use error::TcpWarpError;
+pub use events::TcpWarpEvent;
+pub use metrics::{TcpWarpMetrics, TcpWarpMetricsSnapshot};
+pub use proto::{
+    TcpWarpConnectFailure, TcpWarpHandshake, TcpWarpMessage, TcpWarpProto, TcpWarpProtoClient,
+    TcpWarpProtoError, TcpWarpProtoHost,
+};
+pub use scheduler::TcpWarpPriority;
+pub use server::{TcpWarpServer, TcpWarpServerBuilder};
+pub use stream::TcpWarpStream;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum TcpWarpPortProtocol {
+    #[default]
+    Tcp,
+    Udp,
+}
 
-pub use client::TcpWarpClient;
-pub use proto::{TcpWarpMessage, TcpWarpProto, TcpWarpProtoClient, TcpWarpProtoHost};
-pub use server::TcpWarpServer;
-
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
 pub struct TcpWarpPortConnection {
+    protocol: TcpWarpPortProtocol,
+    bind: Option<IpAddr>,
     client_port: Option<u16>,
+    client_path: Option<PathBuf>,
     host: Option<String>,
     port: u16,
+    port_end: Option<u16>,
+    path: Option<String>,
+    service: Option<String>,
+    no_compression: bool,
+    idle_timeout: Option<Duration>,
+    max_lifetime: Option<Duration>,
+    max_connections: Option<usize>,
+    queue: bool,
+    rate: Option<u64>,
+    priority: TcpWarpPriority,
+}
+
+/// Named service from server catalog, advertised to clients in handshake.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct TcpWarpService {
+    pub name: String,
+    pub host: Option<String>,
+    pub port: u16,
 }
 
 impl FromStr for TcpWarpPortConnection {
-    type Err = io::Error;
+    type Err = TcpWarpError;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let error = |reason| Err(TcpWarpError::parse(input, reason));
+        let (s, options) = input.split_once(',').unwrap_or((input, ""));
+        let mut no_compression = false;
+        let mut idle_timeout = None;
+        let mut max_lifetime = None;
+        let mut max_connections = None;
+        let mut queue = false;
+        let mut rate = None;
+        let mut priority = TcpWarpPriority::default();
+        for option in options.split(',').filter(|x| !x.is_empty()) {
+            let seconds = |value: &str| value.parse().map(Duration::from_secs).ok();
+            match option.split_once('=') {
+                None if option == "nocompress" => no_compression = true,
+                None if option == "queue" => queue = true,
+                Some(("idle", value)) => match seconds(value) {
+                    Some(value) => idle_timeout = Some(value),
+                    None => return error("cannot parse idle timeout of mapping"),
+                },
+                Some(("lifetime", value)) => match seconds(value) {
+                    Some(value) => max_lifetime = Some(value),
+                    None => return error("cannot parse max lifetime of mapping"),
+                },
+                Some(("max", value)) => match value.parse() {
+                    Ok(value) => max_connections = Some(value),
+                    Err(_) => return error("cannot parse connection limit of mapping"),
+                },
+                Some(("rate", value)) => match value.parse() {
+                    Ok(value) => rate = Some(value),
+                    Err(_) => return error("cannot parse rate limit of mapping"),
+                },
+                Some(("priority", value)) => match value.parse() {
+                    Ok(value) => priority = value,
+                    Err(_) => return error("cannot parse priority of mapping"),
+                },
+                _ => return error("unknown port mapping option"),
+            }
+        }
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let mut parts = s.split(':');
-
-        match (parts.next(), parts.next(), parts.next(), parts.next()) {
-            (Some(client_port), host, Some(port), None) => {
-                match (client_port.parse(), port.parse()) {
-                    (Ok(client_port), Ok(port)) => Ok(TcpWarpPortConnection {
-                        client_port: Some(client_port),
-                        host: host.map(str::to_owned),
-                        port,
-                    }),
-                    _ => Err(io::Error::new(
-                        io::ErrorKind::Other,
-                        "cannot parse port mapping",
-                    )),
-                }
+        let (protocol, s) = if let Some(s) = s.strip_prefix("udp:") {
+            (TcpWarpPortProtocol::Udp, s)
+        } else {
+            (
+                TcpWarpPortProtocol::Tcp,
+                s.strip_prefix("tcp:").unwrap_or(s),
+            )
+        };
+
+        let parts = match split_mapping(s) {
+            Some(parts) => parts,
+            None => return error("cannot parse port mapping"),
+        };
+
+        let (bind, client, host, target) = match parts.as_slice() {
+            [target] => (None, None, None, *target),
+            [client, target] => (None, Some(*client), None, *target),
+            [bind, client, target] if parse_ip(bind).is_some() => {
+                (parse_ip(bind), Some(*client), None, *target)
+            }
+            [client, host, target] => (None, Some(*client), Some(*host), *target),
+            [bind, client, host, target] => match parse_ip(bind) {
+                Some(bind) => (Some(bind), Some(*client), Some(*host), *target),
+                None => return error("cannot parse bind address"),
+            },
+            _ => return error("cannot parse port mapping"),
+        };
+
+        let mut connection = TcpWarpPortConnection {
+            protocol,
+            bind,
+            no_compression,
+            idle_timeout,
+            max_lifetime,
+            max_connections,
+            queue,
+            rate,
+            priority,
+            host: host.map(|host| unbracket(host).to_owned()),
+            ..Default::default()
+        };
+
+        if is_socket_path(target) {
+            if host.is_some() || protocol == TcpWarpPortProtocol::Udp {
+                return error("cannot parse port mapping");
             }
-            (Some(client_port_or_host), Some(port), None, None) => {
-                let port = match port.parse() {
-                    Ok(port) => port,
-                    Err(_) => {
-                        return Err(io::Error::new(
-                            io::ErrorKind::Other,
-                            "cannot parse port mapping",
-                        ))
-                    }
-                };
-                let client_port: Result<u16, _> = client_port_or_host.parse();
-                if let Ok(client_port) = client_port {
-                    Ok(TcpWarpPortConnection {
-                        client_port: Some(client_port),
-                        host: None,
-                        port,
-                    })
-                } else {
-                    Ok(TcpWarpPortConnection {
-                        client_port: None,
-                        host: Some(client_port_or_host.to_owned()),
-                        port,
-                    })
+            connection.path = Some(target.to_owned());
+        } else if host.is_none() && is_service_name(target) {
+            connection.service = Some(target.to_owned());
+        } else {
+            let (port, port_end) = match parse_port_range(target) {
+                Some(range) => range,
+                None => return error("cannot parse port mapping"),
+            };
+            connection.port = port;
+            if port_end > port {
+                connection.port_end = Some(port_end);
+            }
+        }
+
+        match client {
+            Some(client) if is_socket_path(client) => {
+                if protocol == TcpWarpPortProtocol::Udp
+                    || connection.port_end.is_some()
+                    || bind.is_some()
+                {
+                    return error("cannot parse port mapping");
                 }
+                connection.client_path = Some(client.into());
             }
-            (Some(port), None, None, None) => {
-                let port = match port.parse() {
-                    Ok(port) => port,
-                    Err(_) => {
-                        return Err(io::Error::new(
-                            io::ErrorKind::Other,
-                            "cannot parse port mapping",
-                        ))
-                    }
-                };
-                Ok(TcpWarpPortConnection {
-                    client_port: None,
-                    host: None,
+            Some(client) => match parse_port_range(client) {
+                Some((client_port, client_port_end))
+                    if client_port_end - client_port
+                        == connection.port_end.unwrap_or(connection.port) - connection.port =>
+                {
+                    connection.client_port = Some(client_port)
+                }
+                Some(_) => return error("port ranges of mapping have different length"),
+                None if host.is_none()
+                    && connection.path.is_none()
+                    && connection.service.is_none() =>
+                {
+                    connection.host = Some(unbracket(client).to_owned())
+                }
+                None => return error("cannot parse port mapping"),
+            },
+            None => connection.client_path = connection.path.as_ref().map(PathBuf::from),
+        }
+
+        Ok(connection)
+    }
+}
+
+impl fmt::Display for TcpWarpPortConnection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.protocol == TcpWarpPortProtocol::Udp {
+            write!(f, "udp:")?;
+        }
+        match self.bind {
+            Some(IpAddr::V6(bind)) => write!(f, "[{}]:", bind)?,
+            Some(bind) => write!(f, "{}:", bind)?,
+            None => (),
+        }
+        match (&self.client_path, self.client_port, self.port_end) {
+            (Some(client_path), ..) => write!(f, "{}:", client_path.display())?,
+            (None, Some(client_port), Some(port_end)) => write!(
+                f,
+                "{}-{}:",
+                client_port,
+                client_port + (port_end - self.port)
+            )?,
+            (None, Some(client_port), None) => write!(f, "{}:", client_port)?,
+            (None, None, _) => (),
+        }
+        match &self.host {
+            Some(host) if host.contains(':') => write!(f, "[{}]:", host)?,
+            Some(host) => write!(f, "{}:", host)?,
+            None => (),
+        }
+        match (&self.service, &self.path, self.port_end) {
+            (Some(service), ..) => write!(f, "{}", service)?,
+            (None, Some(path), _) => write!(f, "{}", path)?,
+            (None, None, Some(port_end)) => write!(f, "{}-{}", self.port, port_end)?,
+            (None, None, None) => write!(f, "{}", self.port)?,
+        }
+        if self.no_compression {
+            write!(f, ",nocompress")?;
+        }
+        if let Some(idle_timeout) = self.idle_timeout {
+            write!(f, ",idle={}", idle_timeout.as_secs())?;
+        }
+        if let Some(max_lifetime) = self.max_lifetime {
+            write!(f, ",lifetime={}", max_lifetime.as_secs())?;
+        }
+        if let Some(max_connections) = self.max_connections {
+            write!(f, ",max={}", max_connections)?;
+        }
+        if self.queue {
+            write!(f, ",queue")?;
+        }
+        if let Some(rate) = self.rate {
+            write!(f, ",rate={}", rate)?;
+        }
+        if self.priority != TcpWarpPriority::Normal {
+            write!(f, ",priority={}", self.priority)?;
+        }
+        Ok(())
+    }
+}
+
+impl TcpWarpPortConnection {
+    /// Mapping of server catalog service to the same client port.
+    pub fn from_service(name: &str) -> Self {
+        TcpWarpPortConnection {
+            service: Some(name.to_owned()),
+            ..Default::default()
+        }
+    }
+
+    /// Name of server catalog service this mapping connects to.
+    pub fn service(&self) -> Option<&str> {
+        self.service.as_deref()
+    }
+
+    /// Expands port range mapping into mappings of single ports.
+    pub fn expand(&self) -> Vec<TcpWarpPortConnection> {
+        match self.port_end {
+            None => vec![self.clone()],
+            Some(port_end) => (self.port..=port_end)
+                .map(|port| TcpWarpPortConnection {
+                    client_port: self
+                        .client_port
+                        .map(|client_port| client_port + (port - self.port)),
                     port,
+                    port_end: None,
+                    ..self.clone()
                 })
-            }
-            _ => Err(io::Error::new(
-                io::ErrorKind::Other,
-                "cannot parse port mapping",
-            )),
+                .collect(),
+        }
+    }
+}
+
+/// Parses single port or inclusive range of ports like `9000-9010`.
+fn parse_port_range(s: &str) -> Option<(u16, u16)> {
+    match s.split_once('-') {
+        Some((start, end)) => match (start.parse(), end.parse()) {
+            (Ok(start), Ok(end)) if start <= end => Some((start, end)),
+            _ => None,
+        },
+        None => s.parse().ok().map(|port| (port, port)),
+    }
+}
+
+/// Splits mapping by `:` keeping bracketed IPv6 literals like `[::1]` in single part.
+fn split_mapping(s: &str) -> Option<Vec<&str>> {
+    let mut parts = vec![];
+    let mut rest = s;
+
+    loop {
+        let end = if rest.starts_with('[') {
+            rest.find(']')? + 1
+        } else {
+            rest.find(':').unwrap_or(rest.len())
+        };
+        parts.push(&rest[..end]);
+        rest = &rest[end..];
+        if rest.is_empty() {
+            break;
+        }
+        rest = rest.strip_prefix(':')?;
+    }
+
+    Some(parts)
+}
+
+/// Strips brackets of IPv6 literal.
+fn unbracket(s: &str) -> &str {
+    s.strip_prefix('[')
+        .and_then(|s| s.strip_suffix(']'))
+        .unwrap_or(s)
+}
+
+/// Parses IPv4 address or bracketed IPv6 address.
+fn parse_ip(s: &str) -> Option<IpAddr> {
+    match s.parse() {
+        Ok(IpAddr::V4(ip)) => Some(IpAddr::V4(ip)),
+        _ => s
+            .strip_prefix('[')
+            .and_then(|s| s.strip_suffix(']'))
+            .and_then(|s| s.parse().ok())
+            .map(IpAddr::V6),
+    }
+}
+
+/// Service names start with letter and contain no characters used in mappings.
+fn is_service_name(s: &str) -> bool {
+    s.starts_with(|c: char| c.is_ascii_alphabetic())
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+impl FromStr for TcpWarpService {
+    type Err = TcpWarpError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = |reason| Err(TcpWarpError::parse(s, reason));
+        let (name, target) = match s.split_once('=') {
+            Some((name, target)) if is_service_name(name) => (name, target),
+            _ => return error("cannot parse service"),
+        };
+        let (host, port) = match split_mapping(target).as_deref() {
+            Some([port]) => (None, port.parse()),
+            Some([host, port]) => (Some(unbracket(host).to_owned()), port.parse()),
+            _ => return error("cannot parse service"),
+        };
+        match port {
+            Ok(port) => Ok(TcpWarpService {
+                name: name.to_owned(),
+                host,
+                port,
+            }),
+            Err(_) => error("cannot parse service port"),
         }
     }
 }
 
+impl fmt::Display for TcpWarpService {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.host {
+            Some(host) if host.contains(':') => write!(f, "{}=[{}]:{}", self.name, host, self.port),
+            Some(host) => write!(f, "{}={}:{}", self.name, host, self.port),
+            None => write!(f, "{}={}", self.name, self.port),
+        }
+    }
+}
+
+/// Unix domain socket paths are absolute or explicitly relative.
+fn is_socket_path(s: &str) -> bool {
+    s.starts_with('/') || s.starts_with('.')
+}
+
 pub struct TcpWarpConnection {
     sender: Sender<TcpWarpMessage>,
     connected_sender: Option<oneshot::Sender<Result<(), io::Error>>>,
+    tunnel: usize,
+    priority: TcpWarpPriority,
+    /// Close of each side, entry is kept until both sides are closed.
+    client_closed: bool,
+    host_closed: bool,
 }
 
 #[cfg(test)]
 mod tests {
-
     use super::*;
 
     #[test]
     fn connection_from_str() {
         assert_eq!(
             Ok(TcpWarpPortConnection {
+                protocol: TcpWarpPortProtocol::Tcp,
                 client_port: None,
                 host: None,
-                port: 8080
+                port: 8080,
+                ..Default::default()
             }),
             "8080".parse().map_err(|_| ())
         );
         assert_eq!(
             Ok(TcpWarpPortConnection {
+                protocol: TcpWarpPortProtocol::Tcp,
                 client_port: Some(8081),
                 host: None,
-                port: 8080
+                port: 8080,
+                ..Default::default()
             }),
             "8081:8080".parse().map_err(|_| ())
         );
         assert_eq!(
             Ok(TcpWarpPortConnection {
+                protocol: TcpWarpPortProtocol::Tcp,
                 client_port: Some(8081),
                 host: Some("localhost".into()),
-                port: 8080
+                port: 8080,
+                ..Default::default()
             }),
             "8081:localhost:8080".parse().map_err(|_| ())
         );
+        assert_eq!(
+            Ok(TcpWarpPortConnection {
+                protocol: TcpWarpPortProtocol::Tcp,
+                client_port: Some(8081),
+                host: Some("localhost".into()),
+                port: 8080,
+                ..Default::default()
+            }),
+            "tcp:8081:localhost:8080".parse().map_err(|_| ())
+        );
+        assert_eq!(
+            Ok(TcpWarpPortConnection {
+                protocol: TcpWarpPortProtocol::Udp,
+                client_port: Some(5353),
+                host: Some("dns".into()),
+                port: 53,
+                ..Default::default()
+            }),
+            "udp:5353:dns:53".parse().map_err(|_| ())
+        );
+        assert_eq!(
+            Ok(TcpWarpPortConnection {
+                client_port: Some(2375),
+                path: Some("/var/run/docker.sock".into()),
+                ..Default::default()
+            }),
+            "2375:/var/run/docker.sock".parse().map_err(|_| ())
+        );
+        assert_eq!(
+            Ok(TcpWarpPortConnection {
+                client_path: Some("/tmp/docker.sock".into()),
+                path: Some("/var/run/docker.sock".into()),
+                ..Default::default()
+            }),
+            "/tmp/docker.sock:/var/run/docker.sock"
+                .parse()
+                .map_err(|_| ())
+        );
+        assert_eq!(
+            Ok(TcpWarpPortConnection {
+                client_path: Some("./whoami.sock".into()),
+                host: Some("whoami".into()),
+                port: 80,
+                ..Default::default()
+            }),
+            "./whoami.sock:whoami:80".parse().map_err(|_| ())
+        );
+        assert_eq!(
+            Ok(TcpWarpPortConnection {
+                client_port: Some(9000),
+                host: Some("broker".into()),
+                port: 9000,
+                port_end: Some(9010),
+                ..Default::default()
+            }),
+            "9000-9010:broker:9000-9010".parse().map_err(|_| ())
+        );
+        assert_eq!(
+            Ok(TcpWarpPortConnection {
+                host: Some("my-host".into()),
+                port: 9000,
+                port_end: Some(9002),
+                ..Default::default()
+            }),
+            "my-host:9000-9002".parse().map_err(|_| ())
+        );
+        assert_eq!(
+            Ok(TcpWarpPortConnection {
+                bind: Some([127, 0, 0, 2].into()),
+                client_port: Some(8081),
+                port: 8080,
+                ..Default::default()
+            }),
+            "127.0.0.2:8081:8080".parse().map_err(|_| ())
+        );
+        assert_eq!(
+            Ok(TcpWarpPortConnection {
+                bind: Some(Ipv6Addr::LOCALHOST.into()),
+                client_port: Some(8081),
+                host: Some("::1".into()),
+                port: 80,
+                ..Default::default()
+            }),
+            "[::1]:8081:[::1]:80".parse().map_err(|_| ())
+        );
+        assert_eq!(
+            Ok(TcpWarpPortConnection {
+                client_port: Some(8081),
+                host: Some("fe80::1".into()),
+                port: 80,
+                ..Default::default()
+            }),
+            "8081:[fe80::1]:80".parse().map_err(|_| ())
+        );
+        assert_eq!(
+            Ok(TcpWarpPortConnection {
+                host: Some("::1".into()),
+                port: 80,
+                ..Default::default()
+            }),
+            "[::1]:80".parse().map_err(|_| ())
+        );
+        assert!("[::1:8081:80".parse::<TcpWarpPortConnection>().is_err());
+        assert!("[::1]8081:80".parse::<TcpWarpPortConnection>().is_err());
+        assert!("localhost:8081:host:80"
+            .parse::<TcpWarpPortConnection>()
+            .is_err());
+        assert!("9000-9010:broker:9000-9011"
+            .parse::<TcpWarpPortConnection>()
+            .is_err());
+        assert!("9000:broker:9000-9010"
+            .parse::<TcpWarpPortConnection>()
+            .is_err());
+        assert!("9010-9000".parse::<TcpWarpPortConnection>().is_err());
+        assert!("udp:2375:/var/run/docker.sock"
+            .parse::<TcpWarpPortConnection>()
+            .is_err());
+        assert!("2375:docker:/var/run/docker.sock"
+            .parse::<TcpWarpPortConnection>()
+            .is_err());
+        assert_eq!(
+            Ok(TcpWarpPortConnection {
+                client_port: Some(8080),
+                service: Some("whoami".into()),
+                ..Default::default()
+            }),
+            "8080:whoami".parse().map_err(|_| ())
+        );
+        assert_eq!(
+            Ok(TcpWarpPortConnection::from_service("whoami")),
+            "whoami".parse().map_err(|_| ())
+        );
+        assert!("8080:docker:whoami"
+            .parse::<TcpWarpPortConnection>()
+            .is_err());
+    }
+
+    #[test]
+    fn service_from_str() {
+        assert_eq!(
+            Ok(TcpWarpService {
+                name: "whoami".into(),
+                host: Some("whoami".into()),
+                port: 80,
+            }),
+            "whoami=whoami:80".parse().map_err(|_| ())
+        );
+        assert_eq!(
+            Ok(TcpWarpService {
+                name: "dns".into(),
+                host: None,
+                port: 53,
+            }),
+            "dns=53".parse().map_err(|_| ())
+        );
+        for service in &["web=[::1]:8080", "web=localhost:8080", "web=8080"] {
+            assert_eq!(
+                *service,
+                service.parse::<TcpWarpService>().unwrap().to_string()
+            );
+        }
+        assert!("whoami".parse::<TcpWarpService>().is_err());
+        assert!("8080=whoami:80".parse::<TcpWarpService>().is_err());
+        assert!("whoami=whoami".parse::<TcpWarpService>().is_err());
+    }
+
+    #[test]
+    fn parse_error_keeps_input() {
+        for (input, expected) in &[
+            ("8080:host:80,fast", "unknown port mapping option"),
+            (
+                "8080:host:80,idle=soon",
+                "cannot parse idle timeout of mapping",
+            ),
+            (
+                "1-3:host:1-2",
+                "port ranges of mapping have different length",
+            ),
+        ] {
+            match input.parse::<TcpWarpPortConnection>() {
+                Err(TcpWarpError::Parse {
+                    input: parsed,
+                    reason,
+                }) => {
+                    assert_eq!(input, &parsed);
+                    assert_eq!(expected, &reason);
+                }
+                other => panic!("unexpected result: {:?}", other),
+            }
+        }
+        assert!(matches!(
+            "db=host".parse::<TcpWarpService>(),
+            Err(TcpWarpError::Parse { .. })
+        ));
+        assert!(matches!(
+            "lz4".parse::<TcpWarpCompression>(),
+            Err(TcpWarpError::Parse { .. })
+        ));
+    }
+
+    #[test]
+    fn connection_expand() {
+        let connection: TcpWarpPortConnection = "19000-19002:broker:9000-9002".parse().unwrap();
+        assert_eq!(
+            vec![
+                (Some(19000), 9000),
+                (Some(19001), 9001),
+                (Some(19002), 9002)
+            ],
+            connection
+                .expand()
+                .iter()
+                .map(|x| (x.client_port, x.port))
+                .collect::<Vec<_>>()
+        );
+        let connection: TcpWarpPortConnection = "8080".parse().unwrap();
+        assert_eq!(vec![connection.clone()], connection.expand());
+    }
+
+    #[test]
+    fn connection_to_string() {
+        for mapping in &[
+            "8080",
+            "8081:8080",
+            "localhost:8080",
+            "8081:localhost:8080",
+            "udp:5353:dns:53",
+            "/tmp/docker.sock:/var/run/docker.sock",
+            "2375:/var/run/docker.sock",
+            "19000-19010:broker:9000-9010",
+            "127.0.0.1:0:whoami:80",
+            "[::1]:8081:[::1]:80",
+            "8080:whoami",
+            "udp:dns",
+            "8443:web:443,nocompress",
+            "5432:db:5432,idle=300,lifetime=3600",
+            "8080:whoami:80,max=10,queue",
+            "5000:registry:5000,rate=1048576",
+            "2222:bastion:22,priority=interactive",
+        ] {
+            let connection: TcpWarpPortConnection = mapping.parse().unwrap();
+            assert_eq!(*mapping, connection.to_string());
+        }
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn connection_parse_never_panics(mapping in "\\PC{0,40}") {
+            if let Ok(connection) = mapping.parse::<TcpWarpPortConnection>() {
+                let reparsed: TcpWarpPortConnection = connection.to_string().parse().unwrap();
+                proptest::prop_assert_eq!(connection, reparsed);
+            }
+        }
     }
 }
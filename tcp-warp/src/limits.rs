@@ -0,0 +1,155 @@
+use super::*;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Counts of active host connections checked against server limits, `None` is unlimited.
+pub(crate) struct ServerLimits {
+    max_connections: Option<usize>,
+    max_tunnel_connections: Option<usize>,
+    max_client_connections: Option<usize>,
+    counts: Mutex<LimitCounts>,
+}
+
+#[derive(Default)]
+struct LimitCounts {
+    total: usize,
+    tunnels: HashMap<usize, usize>,
+    clients: HashMap<IpAddr, usize>,
+}
+
+impl ServerLimits {
+    pub(crate) fn new(
+        max_connections: Option<usize>,
+        max_tunnel_connections: Option<usize>,
+        max_client_connections: Option<usize>,
+    ) -> Self {
+        Self {
+            max_connections,
+            max_tunnel_connections,
+            max_client_connections,
+            counts: Mutex::new(LimitCounts::default()),
+        }
+    }
+
+    fn counts(&self) -> std::sync::MutexGuard<'_, LimitCounts> {
+        match self.counts.lock() {
+            Ok(counts) => counts,
+            Err(err) => err.into_inner(),
+        }
+    }
+
+    /// Takes slot of connection, `None` if any limit is reached.
+    pub(crate) fn acquire(self: &Arc<Self>, tunnel: usize, client: IpAddr) -> Option<LimitPermit> {
+        let mut counts = self.counts();
+        let exceeded = |max: Option<usize>, count: usize| max.is_some_and(|max| count >= max);
+        if exceeded(self.max_connections, counts.total)
+            || exceeded(
+                self.max_tunnel_connections,
+                counts.tunnels.get(&tunnel).copied().unwrap_or(0),
+            )
+            || exceeded(
+                self.max_client_connections,
+                counts.clients.get(&client).copied().unwrap_or(0),
+            )
+        {
+            return None;
+        }
+        counts.total += 1;
+        *counts.tunnels.entry(tunnel).or_insert(0) += 1;
+        *counts.clients.entry(client).or_insert(0) += 1;
+        Some(LimitPermit {
+            limits: self.clone(),
+            tunnel,
+            client,
+        })
+    }
+}
+
+/// Slot of active connection, released on drop.
+pub(crate) struct LimitPermit {
+    limits: Arc<ServerLimits>,
+    tunnel: usize,
+    client: IpAddr,
+}
+
+impl Drop for LimitPermit {
+    fn drop(&mut self) {
+        let mut counts = self.limits.counts();
+        counts.total -= 1;
+        release(&mut counts.tunnels, self.tunnel);
+        release(&mut counts.clients, self.client);
+    }
+}
+
+fn release<K: std::hash::Hash + Eq>(counts: &mut HashMap<K, usize>, key: K) {
+    if let Some(count) = counts.get_mut(&key) {
+        *count -= 1;
+        if *count == 0 {
+            counts.remove(&key);
+        }
+    }
+}
+
+/// Limit of concurrent connections accepted by listener of mapping.
+#[derive(Clone)]
+pub(crate) struct MappingLimit {
+    semaphore: Arc<Semaphore>,
+    queue: bool,
+}
+
+impl MappingLimit {
+    pub(crate) fn new(mapping: &TcpWarpPortConnection) -> Option<Self> {
+        mapping.max_connections.map(|max| Self {
+            semaphore: Arc::new(Semaphore::new(max)),
+            queue: mapping.queue,
+        })
+    }
+
+    /// Takes slot of connection, waiting for it in queue mode, `None` if connection is rejected.
+    pub(crate) async fn acquire(&self) -> Option<OwnedSemaphorePermit> {
+        if self.queue {
+            Some(self.semaphore.clone().acquire_owned().await)
+        } else {
+            self.semaphore.clone().try_acquire_owned().ok()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn server_limits_release_slots() {
+        let limits = Arc::new(ServerLimits::new(Some(3), Some(2), Some(1)));
+        let client = |x| IpAddr::from(Ipv4Addr::new(192, 0, 2, x));
+        let first = limits.acquire(0, client(1)).unwrap();
+        assert!(limits.acquire(1, client(1)).is_none());
+        let _second = limits.acquire(0, client(2)).unwrap();
+        assert!(limits.acquire(0, client(3)).is_none());
+        let _third = limits.acquire(1, client(3)).unwrap();
+        assert!(limits.acquire(1, client(4)).is_none());
+
+        drop(first);
+        let _fourth = limits.acquire(1, client(4)).unwrap();
+        assert!(!limits.counts().clients.contains_key(&client(1)));
+    }
+
+    #[tokio::test]
+    async fn mapping_limit_rejects_or_queues() {
+        assert!(MappingLimit::new(&"8080".parse().unwrap()).is_none());
+
+        let limit = MappingLimit::new(&"8080:whoami:80,max=1".parse().unwrap()).unwrap();
+        let permit = limit.acquire().await.unwrap();
+        assert!(limit.acquire().await.is_none());
+        drop(permit);
+        assert!(limit.acquire().await.is_some());
+
+        let limit = MappingLimit::new(&"8080:whoami:80,max=1,queue".parse().unwrap()).unwrap();
+        let permit = limit.acquire().await.unwrap();
+        assert!(timeout(Duration::from_millis(50), limit.acquire())
+            .await
+            .is_err());
+        drop(permit);
+        assert!(limit.acquire().await.is_some());
+    }
+}
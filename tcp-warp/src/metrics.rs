@@ -0,0 +1,87 @@
+use super::*;
+
+/// Counters shared by all tunnels of client or server.
+#[derive(Debug, Default)]
+pub struct TcpWarpMetrics {
+    sent_uncompressed: AtomicU64,
+    sent_compressed: AtomicU64,
+    received_compressed: AtomicU64,
+    received_uncompressed: AtomicU64,
+    expired_idle: AtomicU64,
+    expired_lifetime: AtomicU64,
+}
+
+/// Point in time copy of [`TcpWarpMetrics`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TcpWarpMetricsSnapshot {
+    /// Payload bytes sent compressed, counted before compression.
+    pub sent_uncompressed: u64,
+    /// Bytes of compressed payloads sent to tunnel.
+    pub sent_compressed: u64,
+    /// Bytes of compressed payloads received from tunnel.
+    pub received_compressed: u64,
+    /// Payload bytes received compressed, counted after decompression.
+    pub received_uncompressed: u64,
+    /// Connections closed after idle timeout.
+    pub expired_idle: u64,
+    /// Connections closed after max lifetime.
+    pub expired_lifetime: u64,
+}
+
+impl TcpWarpMetrics {
+    pub fn snapshot(&self) -> TcpWarpMetricsSnapshot {
+        TcpWarpMetricsSnapshot {
+            sent_uncompressed: self.sent_uncompressed.load(Ordering::Relaxed),
+            sent_compressed: self.sent_compressed.load(Ordering::Relaxed),
+            received_compressed: self.received_compressed.load(Ordering::Relaxed),
+            received_uncompressed: self.received_uncompressed.load(Ordering::Relaxed),
+            expired_idle: self.expired_idle.load(Ordering::Relaxed),
+            expired_lifetime: self.expired_lifetime.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Logs compression savings once tunnel is closed.
+    pub(crate) fn log_compression(&self) {
+        let snapshot = self.snapshot();
+        if snapshot.sent_compressed + snapshot.received_compressed > 0 {
+            info!(
+                "compression saved {} bytes: sent {} of {}, received {} of {}",
+                snapshot.compression_savings(),
+                snapshot.sent_compressed,
+                snapshot.sent_uncompressed,
+                snapshot.received_compressed,
+                snapshot.received_uncompressed,
+            );
+        }
+    }
+
+    pub(crate) fn add_sent_compressed(&self, uncompressed: usize, compressed: usize) {
+        self.sent_uncompressed
+            .fetch_add(uncompressed as u64, Ordering::Relaxed);
+        self.sent_compressed
+            .fetch_add(compressed as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_received_compressed(&self, compressed: usize, uncompressed: usize) {
+        self.received_compressed
+            .fetch_add(compressed as u64, Ordering::Relaxed);
+        self.received_uncompressed
+            .fetch_add(uncompressed as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_expired(&self, reason: ExpiryReason) {
+        match reason {
+            ExpiryReason::Idle => &self.expired_idle,
+            ExpiryReason::Lifetime => &self.expired_lifetime,
+        }
+        .fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl TcpWarpMetricsSnapshot {
+    /// Bytes saved by compression in both directions, negative if compression did not pay off.
+    pub fn compression_savings(&self) -> i64 {
+        (self.sent_uncompressed + self.received_uncompressed) as i64
+            - (self.sent_compressed + self.received_compressed) as i64
+    }
+}
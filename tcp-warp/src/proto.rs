@@ -1,26 +1,861 @@
 use super::*;
 use std::io;
 
-pub struct TcpWarpProto;
+/// Tunnel codec.
+///
+/// Server advertises its capabilities in [`TcpWarpHandshake`] of the first `AddPorts` frame,
+/// which baseline clients ignore, and client answers with `Hello` carrying agreed capabilities.
+/// Both sides switch codec once the handshake frame of the other side is decoded.
+///
+/// After both sides agreed on [`TcpWarpProto::CAPABILITY_COMPACT_IDS`] in handshake,
+/// connection frames refer to connections by compact stream ids instead of 128-bit ids.
+/// Stream id is allocated by the side opening connection and declared together with
+/// connection id in the first frame, so decoded messages still carry connection ids.
+///
+/// Agreed compression algorithm compresses data frames of connections which requested it,
+/// compression context lives for the whole tunnel.
+///
+/// After both sides agreed on [`TcpWarpProto::CAPABILITY_FAILURE_REASONS`], failed connect
+/// carries [`TcpWarpConnectFailure`] reason, otherwise reason is lost in transit.
+///
+/// After both sides agreed on [`TcpWarpProto::CAPABILITY_PRIORITIES`], connect frames carry
+/// [`TcpWarpPriority`] of connection, so server schedules frames of connection by it as well.
+///
+/// Frames with unknown opcode or larger than maximal frame size fail decoding with
+/// [`TcpWarpProtoError`], so tunnel is closed instead of buffering garbage.
+pub struct TcpWarpProto {
+    capabilities: u32,
+    max_frame_size: usize,
+    compact: bool,
+    failure_reasons: bool,
+    priorities: bool,
+    streams: StreamTable,
+    compressor: Option<Compressor>,
+    decompressor: Option<Decompressor>,
+    scratch: Vec<u8>,
+    metrics: Arc<TcpWarpMetrics>,
+}
+
+/// Opcode flag of frames with compact stream reference.
+const COMPACT_FLAG: u8 = 0x80;
+
+/// Compact opcode flag of compressed data frames and connect frames of compressed connections.
+const COMPRESSED_FLAG: u8 = 0x40;
+
+/// Largest stream id, stream reference is `id << 1 | declared` varint u32.
+const MAX_STREAM_ID: u32 = u32::MAX >> 1;
+
+/// First port of `AddPorts` frame carrying handshake, baseline servers send no ports.
+const HANDSHAKE_MARKER: u16 = 0x7477;
+
+impl TcpWarpProto {
+    /// Compact varint stream ids instead of 128-bit connection ids in every frame.
+    pub const CAPABILITY_COMPACT_IDS: u32 = 1;
+
+    /// Deflate compression of data frames, requires compact stream ids.
+    pub const CAPABILITY_DEFLATE: u32 = 2;
+
+    /// Zstandard compression of data frames, requires compact stream ids.
+    pub const CAPABILITY_ZSTD: u32 = 4;
+
+    /// Reason of failed connect, requires compact stream ids.
+    pub const CAPABILITY_FAILURE_REASONS: u32 = 8;
+
+    /// Priority of connection in connect frames, requires compact stream ids.
+    pub const CAPABILITY_PRIORITIES: u32 = 16;
+
+    /// Capabilities supported by this implementation.
+    pub const CAPABILITIES: u32 = Self::CAPABILITY_COMPACT_IDS
+        | Self::CAPABILITY_FAILURE_REASONS
+        | Self::CAPABILITY_PRIORITIES
+        | if cfg!(feature = "deflate") {
+            Self::CAPABILITY_DEFLATE
+        } else {
+            0
+        }
+        | if cfg!(feature = "zstd") {
+            Self::CAPABILITY_ZSTD
+        } else {
+            0
+        };
+
+    /// Default maximal size of single frame, also limits decompressed payload.
+    pub const DEFAULT_MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+    pub fn new() -> Self {
+        Self::with_capabilities(Self::CAPABILITIES)
+    }
+
+    /// Codec accepting only given capabilities in handshake.
+    pub fn with_capabilities(capabilities: u32) -> Self {
+        Self {
+            capabilities: capabilities & Self::CAPABILITIES,
+            max_frame_size: Self::DEFAULT_MAX_FRAME_SIZE,
+            compact: false,
+            failure_reasons: false,
+            priorities: false,
+            streams: StreamTable::default(),
+            compressor: None,
+            decompressor: None,
+            scratch: vec![],
+            metrics: Arc::new(TcpWarpMetrics::default()),
+        }
+    }
+
+    /// Capabilities accepted by this codec.
+    pub fn capabilities(&self) -> u32 {
+        self.capabilities
+    }
+
+    /// Sets maximal size of decoded frame, larger frames are protocol error.
+    pub fn with_max_frame_size(mut self, max_frame_size: usize) -> Self {
+        self.max_frame_size = max_frame_size;
+        self
+    }
+
+    /// Counts compression savings in given metrics.
+    pub fn with_metrics(mut self, metrics: Arc<TcpWarpMetrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    fn negotiate(&mut self, capabilities: u32) -> io::Result<()> {
+        let capabilities = capabilities & self.capabilities;
+        self.compact = capabilities & Self::CAPABILITY_COMPACT_IDS != 0;
+        self.failure_reasons = self.compact && capabilities & Self::CAPABILITY_FAILURE_REASONS != 0;
+        self.priorities = self.compact && capabilities & Self::CAPABILITY_PRIORITIES != 0;
+        let compression = if self.compact {
+            TcpWarpCompression::from_capabilities(capabilities)
+        } else {
+            TcpWarpCompression::None
+        };
+        self.compressor = Compressor::new(compression)?;
+        self.decompressor = Decompressor::new(compression)?;
+        debug!(
+            "negotiated capabilities {}, compact: {}, compression: {}",
+            capabilities, self.compact, compression
+        );
+        Ok(())
+    }
+
+    fn encode_compact(&mut self, item: TcpWarpMessage, dst: &mut BytesMut) -> io::Result<()> {
+        let connection_id = match item.connection_id() {
+            Some(connection_id) => connection_id,
+            None => return Err(io::Error::other("message has no connection")),
+        };
+        let compression = self.compressor.is_some();
+        let (id, declare) = match item {
+            TcpWarpMessage::HostConnect { compress, .. }
+            | TcpWarpMessage::HostConnectPath { compress, .. } => {
+                self.streams
+                    .outgoing(connection_id, true, false, compress && compression)
+            }
+            TcpWarpMessage::DatagramClient { .. } => {
+                self.streams.outgoing(connection_id, true, true, false)
+            }
+            _ => self.streams.outgoing(connection_id, false, false, false),
+        };
+        let compressed = compression && self.streams.compressed(&connection_id);
+        self.streams.track(&item);
+
+        let opcode = match item {
+            TcpWarpMessage::HostConnect { .. } => 2,
+            TcpWarpMessage::BytesClient { .. } => 3,
+            TcpWarpMessage::BytesHost { .. } => 4,
+            TcpWarpMessage::Connected { .. } => 5,
+            TcpWarpMessage::DisconnectHost { .. } => 6,
+            TcpWarpMessage::DisconnectClient { .. } => 7,
+            TcpWarpMessage::ConnectFailure { .. } if self.failure_reasons => 12,
+            TcpWarpMessage::ConnectFailure { .. } => 8,
+            TcpWarpMessage::DatagramClient { .. } => 9,
+            TcpWarpMessage::DatagramHost { .. } => 10,
+            TcpWarpMessage::HostConnectPath { .. } => 11,
+            other => return Err(io::Error::other(format!("unknown message: {:?}", other))),
+        };
+        let flags = if compressed {
+            COMPACT_FLAG | COMPRESSED_FLAG
+        } else {
+            COMPACT_FLAG
+        };
+        dst.reserve(1 + 5 + 16);
+        dst.put_u8(opcode | flags);
+        put_varint(dst, id << 1 | declare as u32);
+        if declare {
+            dst.put_u128(connection_id.as_u128());
+        }
+
+        match item {
+            TcpWarpMessage::HostConnect {
+                host,
+                port,
+                priority,
+                ..
+            } => {
+                let host = host.unwrap_or_default();
+                dst.reserve(2 + 1 + 5 + host.len());
+                dst.put_u16(port);
+                if self.priorities {
+                    dst.put_u8(priority as u8);
+                }
+                put_varint(dst, host.len() as u32);
+                dst.put_slice(host.as_bytes());
+            }
+            TcpWarpMessage::BytesClient { data, .. } | TcpWarpMessage::BytesHost { data, .. }
+                if compressed =>
+            {
+                let mut scratch = std::mem::take(&mut self.scratch);
+                scratch.clear();
+                if let Some(compressor) = self.compressor.as_mut() {
+                    compressor.compress(&data, &mut scratch)?;
+                }
+                self.metrics.add_sent_compressed(data.len(), scratch.len());
+                dst.reserve(5 + scratch.len());
+                put_varint(dst, scratch.len() as u32);
+                dst.put_slice(&scratch);
+                self.scratch = scratch;
+            }
+            TcpWarpMessage::BytesClient { data, .. }
+            | TcpWarpMessage::BytesHost { data, .. }
+            | TcpWarpMessage::DatagramHost { data, .. } => {
+                dst.reserve(5 + data.len());
+                put_varint(dst, data.len() as u32);
+                dst.put_slice(&data);
+            }
+            TcpWarpMessage::DatagramClient {
+                host, port, data, ..
+            } => {
+                let host = host.unwrap_or_default();
+                dst.reserve(2 + 5 + 5 + host.len() + data.len());
+                dst.put_u16(port);
+                put_varint(dst, host.len() as u32);
+                put_varint(dst, data.len() as u32);
+                dst.put_slice(host.as_bytes());
+                dst.put_slice(&data);
+            }
+            TcpWarpMessage::HostConnectPath { path, priority, .. } => {
+                dst.reserve(1 + 5 + path.len());
+                if self.priorities {
+                    dst.put_u8(priority as u8);
+                }
+                put_varint(dst, path.len() as u32);
+                dst.put_slice(path.as_bytes());
+            }
+            TcpWarpMessage::ConnectFailure { reason, .. } if self.failure_reasons => {
+                dst.reserve(1);
+                dst.put_u8(reason as u8);
+            }
+            _ => (),
+        }
+
+        Ok(())
+    }
+
+    /// Decodes frame with compact stream reference, inner `None` is frame of unknown stream.
+    fn decode_compact(&mut self, src: &mut BytesMut) -> io::Result<Option<Option<TcpWarpMessage>>> {
+        let mut reader = Reader { src, pos: 0 };
+        let frame = match CompactFrame::read(&mut reader, self.max_frame_size, self.priorities)? {
+            Some(frame) => frame,
+            None => return Ok(None),
+        };
+
+        src.advance(frame.header_len);
+        let mut first = src.split_to(frame.first_len);
+        let second = src.split_to(frame.second_len);
+
+        let compressed = frame.flags & COMPRESSED_FLAG != 0;
+        if compressed && (frame.opcode == 3 || frame.opcode == 4) {
+            let decompressor = match self.decompressor.as_mut() {
+                Some(decompressor) => decompressor,
+                None => {
+                    return Err(TcpWarpProtoError::Malformed(
+                        "compressed frame without negotiated compression",
+                    )
+                    .into())
+                }
+            };
+            let data = decompressor.decompress(&first, self.max_frame_size)?;
+            self.metrics
+                .add_received_compressed(first.len(), data.len());
+            first = data;
+        }
+
+        let connection_id =
+            match self
+                .streams
+                .incoming(frame.id, frame.declared, frame.opcode == 9, compressed)
+            {
+                Some(connection_id) => connection_id,
+                None => {
+                    debug!("dropping frame of unknown stream {}", frame.id);
+                    return Ok(Some(None));
+                }
+            };
+
+        Ok(Some(Some(match frame.opcode {
+            2 => TcpWarpMessage::HostConnect {
+                connection_id,
+                host: decode_host(&first)?,
+                port: frame.port,
+                compress: compressed,
+                priority: frame.priority,
+            },
+            3 => TcpWarpMessage::BytesClient {
+                connection_id,
+                data: first,
+            },
+            4 => TcpWarpMessage::BytesHost {
+                connection_id,
+                data: first,
+            },
+            5 => TcpWarpMessage::Connected { connection_id },
+            6 => TcpWarpMessage::DisconnectHost { connection_id },
+            7 => TcpWarpMessage::DisconnectClient { connection_id },
+            8 => TcpWarpMessage::ConnectFailure {
+                connection_id,
+                reason: TcpWarpConnectFailure::Other,
+            },
+            9 => TcpWarpMessage::DatagramClient {
+                connection_id,
+                host: decode_host(&first)?,
+                port: frame.port,
+                data: second,
+            },
+            10 => TcpWarpMessage::DatagramHost {
+                connection_id,
+                data: first,
+            },
+            12 => TcpWarpMessage::ConnectFailure {
+                connection_id,
+                reason: TcpWarpConnectFailure::from_code(first[0]),
+            },
+            _ => TcpWarpMessage::HostConnectPath {
+                connection_id,
+                path: decode_text(&first, "path is not valid UTF-8")?,
+                compress: compressed,
+                priority: frame.priority,
+            },
+        })))
+    }
+}
+
+impl Default for TcpWarpProto {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Protocol violation by tunnel peer.
+///
+/// Decoder reports it as [`io::ErrorKind::InvalidData`] error wrapping this type.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TcpWarpProtoError {
+    /// Frame starts with unknown opcode.
+    UnknownOpcode(u8),
+    /// Frame or its decompressed payload is larger than maximal frame size.
+    FrameTooLarge { size: usize, max: usize },
+    /// Host of connect frame is not valid UTF-8.
+    InvalidHost,
+    /// Frame content does not match its header.
+    Malformed(&'static str),
+}
+
+impl fmt::Display for TcpWarpProtoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TcpWarpProtoError::UnknownOpcode(opcode) => write!(f, "unknown opcode {}", opcode),
+            TcpWarpProtoError::FrameTooLarge { size, max } => {
+                write!(f, "frame of {} bytes exceeds maximal size {}", size, max)
+            }
+            TcpWarpProtoError::InvalidHost => write!(f, "host is not valid UTF-8"),
+            TcpWarpProtoError::Malformed(reason) => write!(f, "malformed frame: {}", reason),
+        }
+    }
+}
+
+impl Error for TcpWarpProtoError {}
+
+impl From<TcpWarpProtoError> for io::Error {
+    fn from(err: TcpWarpProtoError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
+}
+
+/// Why server could not connect to target of connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TcpWarpConnectFailure {
+    /// Reason is unknown, for example peer does not report reasons.
+    Other = 0,
+    /// Target refused connection.
+    Refused = 1,
+    /// Connect did not complete within connect timeout.
+    TimedOut = 2,
+    /// Server refused connection because connection limit is reached.
+    LimitExceeded = 3,
+    /// Server does not allow target, like unix socket outside of allowed paths.
+    Forbidden = 4,
+}
+
+impl TcpWarpConnectFailure {
+    /// Reason of wire code, codes of newer peers are unknown reasons.
+    pub(crate) fn from_code(code: u8) -> Self {
+        match code {
+            1 => TcpWarpConnectFailure::Refused,
+            2 => TcpWarpConnectFailure::TimedOut,
+            3 => TcpWarpConnectFailure::LimitExceeded,
+            4 => TcpWarpConnectFailure::Forbidden,
+            _ => TcpWarpConnectFailure::Other,
+        }
+    }
+
+    /// Reason of failed connect to target.
+    pub fn from_error(err: &io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::ConnectionRefused => TcpWarpConnectFailure::Refused,
+            io::ErrorKind::TimedOut => TcpWarpConnectFailure::TimedOut,
+            io::ErrorKind::PermissionDenied => TcpWarpConnectFailure::Forbidden,
+            _ => TcpWarpConnectFailure::Other,
+        }
+    }
+}
+
+impl fmt::Display for TcpWarpConnectFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TcpWarpConnectFailure::Other => write!(f, "cannot connect to target"),
+            TcpWarpConnectFailure::Refused => write!(f, "target refused connection"),
+            TcpWarpConnectFailure::TimedOut => write!(f, "connect to target timed out"),
+            TcpWarpConnectFailure::LimitExceeded => write!(f, "connection limit exceeded"),
+            TcpWarpConnectFailure::Forbidden => write!(f, "target is not allowed"),
+        }
+    }
+}
+
+impl Error for TcpWarpConnectFailure {}
+
+impl From<TcpWarpConnectFailure> for io::Error {
+    fn from(reason: TcpWarpConnectFailure) -> Self {
+        let kind = match reason {
+            TcpWarpConnectFailure::Other | TcpWarpConnectFailure::LimitExceeded => {
+                io::ErrorKind::Other
+            }
+            TcpWarpConnectFailure::Refused => io::ErrorKind::ConnectionRefused,
+            TcpWarpConnectFailure::TimedOut => io::ErrorKind::TimedOut,
+            TcpWarpConnectFailure::Forbidden => io::ErrorKind::PermissionDenied,
+        };
+        io::Error::new(kind, reason)
+    }
+}
+
+/// Handshake of server, sent in ports of `AddPorts` frame.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TcpWarpHandshake {
+    /// Capabilities supported by server.
+    pub capabilities: u32,
+    /// Service catalog of server.
+    pub services: Vec<TcpWarpService>,
+}
+
+/// Ports of `AddPorts` frame carrying handshake.
+fn encode_handshake(handshake: TcpWarpHandshake) -> BytesMut {
+    let mut ports = BytesMut::new();
+    ports.put_u16(HANDSHAKE_MARKER);
+    ports.put_u32(handshake.capabilities);
+    ports.put_u16(handshake.services.len() as u16);
+    for service in handshake.services {
+        let host = service.host.unwrap_or_default();
+        ports.reserve(2 + 2 + 2 + service.name.len() + host.len());
+        ports.put_u16(service.name.len() as u16);
+        ports.put_u16(host.len() as u16);
+        ports.put_u16(service.port);
+        ports.put_slice(service.name.as_bytes());
+        ports.put_slice(host.as_bytes());
+    }
+    if ports.len() % 2 == 1 {
+        ports.put_u8(0);
+    }
+    ports
+}
+
+/// Decodes handshake from ports of `AddPorts` frame, other ports are ignored like baseline
+/// clients do.
+fn decode_handshake(mut ports: BytesMut) -> Result<Option<TcpWarpHandshake>, TcpWarpProtoError> {
+    if ports.len() < 2 + 4 + 2 || ports[..2] != HANDSHAKE_MARKER.to_be_bytes() {
+        return Ok(None);
+    }
+    ports.advance(2);
+    let capabilities = ports.get_u32();
+    let count = ports.get_u16() as usize;
+    let mut services = Vec::with_capacity(count.min(ports.len() / 6));
+    for _ in 0..count {
+        if ports.len() < 6 {
+            return Err(TcpWarpProtoError::Malformed("handshake is truncated"));
+        }
+        let name_len = ports.get_u16() as usize;
+        let host_len = ports.get_u16() as usize;
+        let port = ports.get_u16();
+        if ports.len() < name_len + host_len {
+            return Err(TcpWarpProtoError::Malformed("handshake is truncated"));
+        }
+        let name = decode_text(&ports.split_to(name_len), "service name is not valid UTF-8")?;
+        let host = decode_host(&ports.split_to(host_len))?;
+        services.push(TcpWarpService { name, host, port });
+    }
+    Ok(Some(TcpWarpHandshake {
+        capabilities,
+        services,
+    }))
+}
+
+/// Decodes optional host, empty host means default connect address of server.
+fn decode_host(data: &[u8]) -> Result<Option<String>, TcpWarpProtoError> {
+    if data.is_empty() {
+        return Ok(None);
+    }
+    String::from_utf8(data.to_vec())
+        .map(Some)
+        .map_err(|_| TcpWarpProtoError::InvalidHost)
+}
+
+/// Decodes UTF-8 text of frame, `reason` describes malformed frame otherwise.
+fn decode_text(data: &[u8], reason: &'static str) -> Result<String, TcpWarpProtoError> {
+    String::from_utf8(data.to_vec()).map_err(|_| TcpWarpProtoError::Malformed(reason))
+}
+
+/// Length of plain frame, `None` until header with lengths is buffered.
+fn plain_frame_len(src: &[u8]) -> Result<Option<usize>, TcpWarpProtoError> {
+    let opcode = match src.first() {
+        Some(opcode) => *opcode,
+        None => return Ok(None),
+    };
+    let header_len = match opcode {
+        1 | 2 | 11 => 1 + 2,
+        3 | 4 | 10 => 1 + 16 + 4,
+        5..=8 => 1 + 16,
+        9 => 1 + 2 + 16 + 2 + 4,
+        13 => 1 + 4,
+        _ => return Err(TcpWarpProtoError::UnknownOpcode(opcode)),
+    };
+    if src.len() < header_len {
+        return Ok(None);
+    }
+    let u16_at = |pos: usize| u16::from_be_bytes(src[pos..pos + 2].try_into().unwrap()) as usize;
+    let u32_at = |pos: usize| u32::from_be_bytes(src[pos..pos + 4].try_into().unwrap()) as usize;
+    Ok(Some(
+        header_len
+            + match opcode {
+                1 => u16_at(1) * 2,
+                2 => 16 + 2 + u16_at(1),
+                3 | 4 | 10 => u32_at(17),
+                9 => u16_at(1) + u32_at(21),
+                11 => 16 + u16_at(1),
+                _ => 0,
+            },
+    ))
+}
+
+/// Mapping of connection ids to compact stream ids of one tunnel.
+#[derive(Default)]
+struct StreamTable {
+    next_id: u32,
+    ids: HashMap<u32, Uuid>,
+    streams: HashMap<Uuid, Stream>,
+}
+
+struct Stream {
+    id: u32,
+    datagram: bool,
+    compressed: bool,
+    host_done: bool,
+    client_done: bool,
+}
+
+impl StreamTable {
+    /// Stream id for outgoing frame and whether frame declares connection id.
+    ///
+    /// Opening frame of unknown connection allocates new id, other frames of unknown
+    /// connection are sent with id 0 and connection id inline.
+    fn outgoing(
+        &mut self,
+        connection_id: Uuid,
+        opening: bool,
+        datagram: bool,
+        compressed: bool,
+    ) -> (u32, bool) {
+        if let Some(stream) = self.streams.get(&connection_id) {
+            return (stream.id, false);
+        }
+        if !opening {
+            return (0, true);
+        }
+        loop {
+            self.next_id = if self.next_id >= MAX_STREAM_ID {
+                1
+            } else {
+                self.next_id + 1
+            };
+            if !self.ids.contains_key(&self.next_id) {
+                break;
+            }
+        }
+        self.insert(self.next_id, connection_id, datagram, compressed);
+        (self.next_id, true)
+    }
+
+    /// Whether data frames of connection are compressed.
+    fn compressed(&self, connection_id: &Uuid) -> bool {
+        self.streams
+            .get(connection_id)
+            .is_some_and(|stream| stream.compressed)
+    }
+
+    /// Connection id of incoming frame, remembering declared streams.
+    fn incoming(
+        &mut self,
+        id: u32,
+        declared: Option<Uuid>,
+        datagram: bool,
+        compressed: bool,
+    ) -> Option<Uuid> {
+        match declared {
+            Some(connection_id) => {
+                if id != 0 {
+                    self.insert(id, connection_id, datagram, compressed);
+                }
+                Some(connection_id)
+            }
+            None => self.ids.get(&id).copied(),
+        }
+    }
+
+    fn insert(&mut self, id: u32, connection_id: Uuid, datagram: bool, compressed: bool) {
+        if let Some(previous) = self.ids.insert(id, connection_id) {
+            self.streams.remove(&previous);
+        }
+        let stream = Stream {
+            id,
+            datagram,
+            compressed,
+            host_done: false,
+            client_done: false,
+        };
+        if let Some(previous) = self.streams.insert(connection_id, stream) {
+            if previous.id != id {
+                self.ids.remove(&previous.id);
+            }
+        }
+    }
+
+    /// Forgets stream once both sides disconnected, datagram association is closed by host only.
+    fn track(&mut self, message: &TcpWarpMessage) {
+        let (connection_id, finished) = match message {
+            TcpWarpMessage::ConnectFailure { connection_id, .. } => (connection_id, true),
+            TcpWarpMessage::DisconnectHost { connection_id } => {
+                match self.streams.get_mut(connection_id) {
+                    Some(stream) => {
+                        stream.host_done = true;
+                        (connection_id, stream.datagram || stream.client_done)
+                    }
+                    None => return,
+                }
+            }
+            TcpWarpMessage::DisconnectClient { connection_id } => {
+                match self.streams.get_mut(connection_id) {
+                    Some(stream) => {
+                        stream.client_done = true;
+                        (connection_id, stream.host_done)
+                    }
+                    None => return,
+                }
+            }
+            _ => return,
+        };
+        if finished {
+            if let Some(stream) = self.streams.remove(connection_id) {
+                self.ids.remove(&stream.id);
+            }
+        }
+    }
+}
+
+fn put_varint(dst: &mut BytesMut, mut value: u32) {
+    while value >= 0x80 {
+        dst.put_u8(value as u8 | 0x80);
+        value >>= 7;
+    }
+    dst.put_u8(value as u8);
+}
+
+/// Reads frame header without consuming buffer.
+struct Reader<'a> {
+    src: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, len: usize) -> Option<&[u8]> {
+        let data = self.src.get(self.pos..self.pos + len)?;
+        self.pos += len;
+        Some(data)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|x| x[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2)
+            .map(|x| u16::from_be_bytes(x.try_into().unwrap()))
+    }
+
+    fn u128(&mut self) -> Option<u128> {
+        self.take(16)
+            .map(|x| u128::from_be_bytes(x.try_into().unwrap()))
+    }
+
+    fn varint(&mut self) -> Result<Option<u32>, TcpWarpProtoError> {
+        let mut value = 0u32;
+        for shift in (0..35).step_by(7) {
+            let byte = match self.u8() {
+                Some(byte) => byte,
+                None => return Ok(None),
+            };
+            if shift == 28 && byte > 0x0f {
+                return Err(TcpWarpProtoError::Malformed("varint is too long"));
+            }
+            value |= u32::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(Some(value));
+            }
+        }
+        Err(TcpWarpProtoError::Malformed("varint is too long"))
+    }
+}
+
+/// Header of frame with compact stream reference.
+struct CompactFrame {
+    opcode: u8,
+    flags: u8,
+    id: u32,
+    declared: Option<Uuid>,
+    port: u16,
+    priority: TcpWarpPriority,
+    header_len: usize,
+    first_len: usize,
+    second_len: usize,
+}
+
+impl CompactFrame {
+    /// Reads header, returns `None` until whole frame is buffered.
+    ///
+    /// Connect frames carry priority only when priorities are negotiated.
+    fn read(
+        reader: &mut Reader,
+        max_frame_size: usize,
+        priorities: bool,
+    ) -> Result<Option<Self>, TcpWarpProtoError> {
+        macro_rules! get {
+            ($e:expr) => {
+                match $e {
+                    Some(value) => value,
+                    None => return Ok(None),
+                }
+            };
+        }
+
+        let byte = get!(reader.u8());
+        let flags = byte & (COMPACT_FLAG | COMPRESSED_FLAG);
+        let opcode = byte & !flags;
+        if !(2..=12).contains(&opcode) {
+            return Err(TcpWarpProtoError::UnknownOpcode(byte));
+        }
+        let reference = get!(reader.varint()?);
+        let declared = if reference & 1 == 1 {
+            Some(Uuid::from_u128(get!(reader.u128())))
+        } else {
+            None
+        };
+        let mut priority = TcpWarpPriority::Normal;
+        let (port, first_len, second_len) = match opcode {
+            2 | 9 => {
+                let port = get!(reader.u16());
+                if opcode == 2 && priorities {
+                    priority = TcpWarpPriority::from_code(get!(reader.u8()));
+                }
+                let first_len = get!(reader.varint()?);
+                let second_len = if opcode == 9 {
+                    get!(reader.varint()?)
+                } else {
+                    0
+                };
+                (port, first_len, second_len)
+            }
+            11 => {
+                if priorities {
+                    priority = TcpWarpPriority::from_code(get!(reader.u8()));
+                }
+                (0, get!(reader.varint()?), 0)
+            }
+            3 | 4 | 10 => (0, get!(reader.varint()?), 0),
+            12 => (0, 1, 0),
+            _ => (0, 0, 0),
+        };
+        let (first_len, second_len) = (first_len as usize, second_len as usize);
+        let size = reader.pos + first_len + second_len;
+        if size > max_frame_size {
+            return Err(TcpWarpProtoError::FrameTooLarge {
+                size,
+                max: max_frame_size,
+            });
+        }
+        if size > reader.src.len() {
+            return Ok(None);
+        }
+
+        Ok(Some(Self {
+            opcode,
+            flags,
+            id: reference >> 1,
+            declared,
+            port,
+            priority,
+            header_len: reader.pos,
+            first_len,
+            second_len,
+        }))
+    }
+}
 
 impl Encoder for TcpWarpProto {
     type Item = TcpWarpMessage;
     type Error = io::Error;
 
     fn encode(&mut self, item: Self::Item, dst: &mut BytesMut) -> io::Result<()> {
+        if self.compact && item.connection_id().is_some() {
+            return self.encode_compact(item, dst);
+        }
+        self.streams.track(&item);
+
         match item {
-            TcpWarpMessage::AddPorts(ports) => {
-                dst.reserve(1 + 2 + ports.len() * 2);
-                dst.put_u8(1);
-                dst.put_u16(ports.len() as u16);
-                for port in ports {
-                    dst.put_u16(port);
+            TcpWarpMessage::Hello { capabilities } => {
+                dst.reserve(1 + 4);
+                dst.put_u8(13);
+                dst.put_u32(capabilities);
+            }
+            TcpWarpMessage::AddPorts(handshake) => {
+                let ports = handshake.map(encode_handshake).unwrap_or_default();
+                if ports.len() / 2 > u16::MAX as usize {
+                    return Err(io::Error::other("handshake does not fit into frame"));
                 }
+                dst.reserve(1 + 2 + ports.len());
+                dst.put_u8(1);
+                dst.put_u16((ports.len() / 2) as u16);
+                dst.put_slice(&ports);
             }
             TcpWarpMessage::HostConnect {
                 connection_id,
                 host,
                 port,
+                ..
             } => {
                 let len = host.as_ref().map_or(0, |x| x.len());
                 dst.reserve(1 + 2 + 16 + 2 + len);
@@ -67,11 +902,53 @@ impl Encoder for TcpWarpProto {
                 dst.put_u8(7);
                 dst.put_u128(connection_id.as_u128());
             }
-            TcpWarpMessage::ConnectFailure { connection_id } => {
+            TcpWarpMessage::ConnectFailure { connection_id, .. } => {
                 dst.reserve(1 + 16);
                 dst.put_u8(8);
                 dst.put_u128(connection_id.as_u128());
             }
+            TcpWarpMessage::HostConnectPath {
+                connection_id,
+                path,
+                ..
+            } => {
+                if path.len() > u16::MAX as usize {
+                    return Err(io::Error::other("path does not fit into frame"));
+                }
+                dst.reserve(1 + 2 + 16 + path.len());
+                dst.put_u8(11);
+                dst.put_u16(path.len() as u16);
+                dst.put_u128(connection_id.as_u128());
+                dst.put_slice(path.as_bytes());
+            }
+            TcpWarpMessage::DatagramClient {
+                connection_id,
+                host,
+                port,
+                data,
+            } => {
+                let len = host.as_ref().map_or(0, |x| x.len());
+                dst.reserve(1 + 2 + 16 + 2 + 4 + len + data.len());
+                dst.put_u8(9);
+                dst.put_u16(len as u16);
+                dst.put_u128(connection_id.as_u128());
+                dst.put_u16(port);
+                dst.put_u32(data.len() as u32);
+                if let Some(host) = host {
+                    dst.put_slice(host.as_bytes());
+                }
+                dst.put_slice(&data);
+            }
+            TcpWarpMessage::DatagramHost {
+                connection_id,
+                data,
+            } => {
+                dst.reserve(1 + 16 + 4 + data.len());
+                dst.put_u8(10);
+                dst.put_u128(connection_id.as_u128());
+                dst.put_u32(data.len() as u32);
+                dst.put_slice(&data);
+            }
             other => {
                 error!("unknown message: {:?}", other);
             }
@@ -86,121 +963,166 @@ impl Decoder for TcpWarpProto {
     type Error = io::Error;
 
     fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<TcpWarpMessage>> {
-        Ok(match src.get(0) {
-            Some(1) if src.len() > 2 => {
-                let len = u16::from_be_bytes(src[1..3].try_into().unwrap());
-                if src.len() == 3 && len == 0 {
-                    src.advance(3);
-                    return Ok(Some(TcpWarpMessage::AddPorts(vec![])));
-                }
-                if len as usize * 2 + 3 <= src.len() {
-                    src.advance(3);
-                    let data = src.split_to(len as usize * 2);
-                    let ports = data
-                        .chunks_exact(2)
-                        .map(|x| u16::from_be_bytes(x.try_into().unwrap()))
-                        .collect();
-                    Some(TcpWarpMessage::AddPorts(ports))
-                } else {
-                    None
-                }
+        let message = loop {
+            match src.first() {
+                Some(opcode) if opcode & COMPACT_FLAG != 0 => match self.decode_compact(src)? {
+                    Some(None) => continue,
+                    Some(message) => break message,
+                    None => break None,
+                },
+                _ => break self.decode_plain(src)?,
             }
-            Some(2) if src.len() > (16 + 2) => {
-                let len = u16::from_be_bytes(src[1..3].try_into().unwrap()) as usize;
-                if 2 + 16 + 2 + len < src.len() {
-                    src.advance(3);
-                    let header = src.split_to(18);
-                    let connection_id = Uuid::from_slice(&header[0..16]).unwrap();
-                    let port = u16::from_be_bytes(header[16..18].try_into().unwrap());
-                    let host = if len > 0 {
-                        String::from_utf8(src.split_to(len).to_vec()).ok()
-                    } else {
-                        None
-                    };
-                    Some(TcpWarpMessage::HostConnect {
-                        connection_id,
-                        host,
-                        port,
-                    })
-                } else {
-                    None
+        };
+        match &message {
+            Some(TcpWarpMessage::Hello { capabilities }) => self.negotiate(*capabilities)?,
+            Some(TcpWarpMessage::AddPorts(Some(handshake))) => {
+                self.negotiate(handshake.capabilities)?
+            }
+            Some(message) => self.streams.track(message),
+            None => (),
+        }
+        Ok(message)
+    }
+}
+
+impl TcpWarpProto {
+    /// Decodes plain frame once it is buffered entirely, lengths come from [`plain_frame_len`].
+    fn decode_plain(&mut self, src: &mut BytesMut) -> io::Result<Option<TcpWarpMessage>> {
+        let size = match plain_frame_len(src)? {
+            Some(size) => size,
+            None => return Ok(None),
+        };
+        if size > self.max_frame_size {
+            return Err(TcpWarpProtoError::FrameTooLarge {
+                size,
+                max: self.max_frame_size,
+            }
+            .into());
+        }
+        if src.len() < size {
+            return Ok(None);
+        }
+
+        let mut frame = src.split_to(size);
+        let opcode = frame.get_u8();
+        let connection_id = |frame: &mut BytesMut| Uuid::from_u128(frame.get_u128());
+        Ok(Some(match opcode {
+            1 => {
+                frame.advance(2);
+                TcpWarpMessage::AddPorts(decode_handshake(frame)?)
+            }
+            2 => {
+                let len = frame.get_u16() as usize;
+                let connection_id = connection_id(&mut frame);
+                let port = frame.get_u16();
+                TcpWarpMessage::HostConnect {
+                    connection_id,
+                    host: decode_host(&frame[..len])?,
+                    port,
+                    compress: false,
+                    priority: TcpWarpPriority::Normal,
                 }
             }
-            Some(3) if src.len() > (16 + 4 + 1) => {
-                let len = u32::from_be_bytes(src[17..21].try_into().unwrap()) as usize;
-                if len as usize + 16 + 4 < src.len() {
-                    src.advance(1);
-                    let header = src.split_to(20);
-                    let connection_id = Uuid::from_slice(&header[0..16]).unwrap();
-                    let data = src.split_to(len);
-                    Some(TcpWarpMessage::BytesClient {
+            3 | 4 | 10 => {
+                let connection_id = connection_id(&mut frame);
+                frame.advance(4);
+                let data = frame;
+                match opcode {
+                    3 => TcpWarpMessage::BytesClient {
                         connection_id,
                         data,
-                    })
-                } else {
-                    None
-                }
-            }
-            Some(4) if src.len() > (16 + 4 + 1) => {
-                let len = u32::from_be_bytes(src[17..21].try_into().unwrap()) as usize;
-                if len as usize + 16 + 4 < src.len() {
-                    src.advance(1);
-                    let header = src.split_to(20);
-                    let connection_id = Uuid::from_slice(&header[0..16]).unwrap();
-                    let data = src.split_to(len);
-                    Some(TcpWarpMessage::BytesHost {
+                    },
+                    4 => TcpWarpMessage::BytesHost {
                         connection_id,
                         data,
-                    })
-                } else {
-                    None
+                    },
+                    _ => TcpWarpMessage::DatagramHost {
+                        connection_id,
+                        data,
+                    },
                 }
             }
-            Some(5) if src.len() > 16 => {
-                src.advance(1);
-                let header = src.split_to(16);
-                let connection_id = Uuid::from_slice(&header).unwrap();
-                Some(TcpWarpMessage::Connected { connection_id })
-            }
-            Some(6) if src.len() > 16 => {
-                src.advance(1);
-                let header = src.split_to(16);
-                let connection_id = Uuid::from_slice(&header).unwrap();
-                Some(TcpWarpMessage::DisconnectHost { connection_id })
-            }
-            Some(7) if src.len() > 16 => {
-                src.advance(1);
-                let header = src.split_to(16);
-                let connection_id = Uuid::from_slice(&header).unwrap();
-                Some(TcpWarpMessage::DisconnectClient { connection_id })
+            5 => TcpWarpMessage::Connected {
+                connection_id: connection_id(&mut frame),
+            },
+            6 => TcpWarpMessage::DisconnectHost {
+                connection_id: connection_id(&mut frame),
+            },
+            7 => TcpWarpMessage::DisconnectClient {
+                connection_id: connection_id(&mut frame),
+            },
+            8 => TcpWarpMessage::ConnectFailure {
+                connection_id: connection_id(&mut frame),
+                reason: TcpWarpConnectFailure::Other,
+            },
+            9 => {
+                let len = frame.get_u16() as usize;
+                let connection_id = connection_id(&mut frame);
+                let port = frame.get_u16();
+                frame.advance(4);
+                let host = decode_host(&frame.split_to(len))?;
+                TcpWarpMessage::DatagramClient {
+                    connection_id,
+                    host,
+                    port,
+                    data: frame,
+                }
             }
-            Some(8) if src.len() > 16 => {
-                src.advance(1);
-                let header = src.split_to(16);
-                let connection_id = Uuid::from_slice(&header).unwrap();
-                Some(TcpWarpMessage::ConnectFailure { connection_id })
+            11 => {
+                frame.advance(2);
+                let connection_id = connection_id(&mut frame);
+                TcpWarpMessage::HostConnectPath {
+                    connection_id,
+                    path: decode_text(&frame, "path is not valid UTF-8")?,
+                    compress: false,
+                    priority: TcpWarpPriority::Normal,
+                }
             }
-            _ => {
-                debug!("looks like data is wrong [{}] {:?}", src.len(), src);
-                None
-            } // _ => None,
-        })
+            _ => TcpWarpMessage::Hello {
+                capabilities: frame.get_u32(),
+            },
+        }))
     }
 }
 
 /// Command types.
 ///
 /// Serialization scheme:
-/// - 1 - add ports u16 len * u16
+/// - 1 - add ports u16=len len * u16, ports of newer server carry handshake
+///   u16=0x7477 u32=capabilities u16=count
+///   count * (u16=name_len u16=host_len u16 name_len * u8 host_len * u8), padded to whole u16
 /// - 2 - host connect u16=(len + 2) u128 u16 len * u8
 /// - 3 - bytes client u128 u32 len * u8
 /// - 4 - bytes host u128 u32 len * u8
 /// - 5 - connected u128
 /// - 6 - disconnect host u128
 /// - 7 - disconnect client u128
-#[derive(Debug)]
+/// - 8 - connect failure u128
+/// - 9 - datagram client u16=len u128 u16 u32=data_len len * u8 data_len * u8
+/// - 10 - datagram host u128 u32 len * u8
+/// - 11 - host connect path u16=len u128 len * u8
+/// - 13 - hello u32=capabilities, sent by client only in reply to handshake
+///
+/// With compact stream ids opcodes 2-11 are sent with flag 0x80 and 128-bit id is replaced
+/// by varint stream reference `id << 1 | declared`, declared reference is followed by u128.
+/// Lengths become varints placed before variable data:
+/// - 2 - host connect ref u16=port [u8=priority] varint=len len * u8
+/// - 3, 4, 10 - ref varint=len len * u8
+/// - 11 - host connect path ref [u8=priority] varint=len len * u8
+/// - 5, 6, 7, 8 - ref
+/// - 9 - datagram client ref u16=port varint=len varint=data_len len * u8 data_len * u8
+///
+/// With agreed compression flag 0x40 marks compressed data frames 3 and 4 and connect frames
+/// 2 and 11 of connections with compressed data frames. Priority byte of connect frames is
+/// present only with agreed priorities.
+///
+/// Debug output shows only length of payloads, so payloads never reach logs.
 pub enum TcpWarpMessage {
-    AddPorts(Vec<u16>),
+    Hello {
+        capabilities: u32,
+    },
+    /// Greeting of server, `None` for baseline servers without handshake.
+    AddPorts(Option<TcpWarpHandshake>),
     Connected {
         connection_id: Uuid,
     },
@@ -223,11 +1145,13 @@ pub enum TcpWarpMessage {
     },
     ConnectForward {
         connection_id: Uuid,
+        priority: TcpWarpPriority,
         sender: Sender<TcpWarpMessage>,
         connected_sender: oneshot::Sender<Result<(), io::Error>>,
     },
     ConnectFailure {
         connection_id: Uuid,
+        reason: TcpWarpConnectFailure,
     },
     Disconnect,
     Listener(AbortHandle),
@@ -235,6 +1159,14 @@ pub enum TcpWarpMessage {
         connection_id: Uuid,
         host: Option<String>,
         port: u16,
+        compress: bool,
+        priority: TcpWarpPriority,
+    },
+    HostConnectPath {
+        connection_id: Uuid,
+        path: String,
+        compress: bool,
+        priority: TcpWarpPriority,
     },
     DisconnectHost {
         connection_id: Uuid,
@@ -242,6 +1174,196 @@ pub enum TcpWarpMessage {
     DisconnectClient {
         connection_id: Uuid,
     },
+    DatagramConnect {
+        connection_id: Uuid,
+        priority: TcpWarpPriority,
+        sender: Sender<TcpWarpMessage>,
+    },
+    DatagramClient {
+        connection_id: Uuid,
+        host: Option<String>,
+        port: u16,
+        data: BytesMut,
+    },
+    DatagramHost {
+        connection_id: Uuid,
+        data: BytesMut,
+    },
+    TunnelConnected {
+        tunnel: usize,
+        sender: Sender<(TcpWarpMessage, TcpWarpPriority)>,
+    },
+    TunnelDisconnected {
+        tunnel: usize,
+    },
+}
+
+/// Payload in debug output of [`TcpWarpMessage`].
+struct Redacted(usize);
+
+impl fmt::Debug for Redacted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<{} bytes>", self.0)
+    }
+}
+
+impl fmt::Debug for TcpWarpMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TcpWarpMessage::Hello { capabilities } => f
+                .debug_struct("Hello")
+                .field("capabilities", capabilities)
+                .finish(),
+            TcpWarpMessage::AddPorts(handshake) => {
+                f.debug_tuple("AddPorts").field(handshake).finish()
+            }
+            TcpWarpMessage::Connected { connection_id } => f
+                .debug_struct("Connected")
+                .field("connection_id", connection_id)
+                .finish(),
+            TcpWarpMessage::BytesClient {
+                connection_id,
+                data,
+            } => f
+                .debug_struct("BytesClient")
+                .field("connection_id", connection_id)
+                .field("data", &Redacted(data.len()))
+                .finish(),
+            TcpWarpMessage::BytesServer { data } => f
+                .debug_struct("BytesServer")
+                .field("data", &Redacted(data.len()))
+                .finish(),
+            TcpWarpMessage::BytesHost {
+                connection_id,
+                data,
+            } => f
+                .debug_struct("BytesHost")
+                .field("connection_id", connection_id)
+                .field("data", &Redacted(data.len()))
+                .finish(),
+            TcpWarpMessage::Connect {
+                connection_id,
+                connection,
+                ..
+            } => f
+                .debug_struct("Connect")
+                .field("connection_id", connection_id)
+                .field("connection", connection)
+                .finish_non_exhaustive(),
+            TcpWarpMessage::ConnectForward {
+                connection_id,
+                priority,
+                ..
+            } => f
+                .debug_struct("ConnectForward")
+                .field("connection_id", connection_id)
+                .field("priority", priority)
+                .finish_non_exhaustive(),
+            TcpWarpMessage::ConnectFailure {
+                connection_id,
+                reason,
+            } => f
+                .debug_struct("ConnectFailure")
+                .field("connection_id", connection_id)
+                .field("reason", reason)
+                .finish(),
+            TcpWarpMessage::Disconnect => f.write_str("Disconnect"),
+            TcpWarpMessage::Listener(_) => f.debug_tuple("Listener").finish_non_exhaustive(),
+            TcpWarpMessage::HostConnect {
+                connection_id,
+                host,
+                port,
+                compress,
+                priority,
+            } => f
+                .debug_struct("HostConnect")
+                .field("connection_id", connection_id)
+                .field("host", host)
+                .field("port", port)
+                .field("compress", compress)
+                .field("priority", priority)
+                .finish(),
+            TcpWarpMessage::HostConnectPath {
+                connection_id,
+                path,
+                compress,
+                priority,
+            } => f
+                .debug_struct("HostConnectPath")
+                .field("connection_id", connection_id)
+                .field("path", path)
+                .field("compress", compress)
+                .field("priority", priority)
+                .finish(),
+            TcpWarpMessage::DisconnectHost { connection_id } => f
+                .debug_struct("DisconnectHost")
+                .field("connection_id", connection_id)
+                .finish(),
+            TcpWarpMessage::DisconnectClient { connection_id } => f
+                .debug_struct("DisconnectClient")
+                .field("connection_id", connection_id)
+                .finish(),
+            TcpWarpMessage::DatagramConnect {
+                connection_id,
+                priority,
+                ..
+            } => f
+                .debug_struct("DatagramConnect")
+                .field("connection_id", connection_id)
+                .field("priority", priority)
+                .finish_non_exhaustive(),
+            TcpWarpMessage::DatagramClient {
+                connection_id,
+                host,
+                port,
+                data,
+            } => f
+                .debug_struct("DatagramClient")
+                .field("connection_id", connection_id)
+                .field("host", host)
+                .field("port", port)
+                .field("data", &Redacted(data.len()))
+                .finish(),
+            TcpWarpMessage::DatagramHost {
+                connection_id,
+                data,
+            } => f
+                .debug_struct("DatagramHost")
+                .field("connection_id", connection_id)
+                .field("data", &Redacted(data.len()))
+                .finish(),
+            TcpWarpMessage::TunnelConnected { tunnel, .. } => f
+                .debug_struct("TunnelConnected")
+                .field("tunnel", tunnel)
+                .finish_non_exhaustive(),
+            TcpWarpMessage::TunnelDisconnected { tunnel } => f
+                .debug_struct("TunnelDisconnected")
+                .field("tunnel", tunnel)
+                .finish(),
+        }
+    }
+}
+
+impl TcpWarpMessage {
+    /// Connection this message belongs to, if any.
+    pub fn connection_id(&self) -> Option<Uuid> {
+        match self {
+            TcpWarpMessage::Connected { connection_id }
+            | TcpWarpMessage::BytesClient { connection_id, .. }
+            | TcpWarpMessage::BytesHost { connection_id, .. }
+            | TcpWarpMessage::Connect { connection_id, .. }
+            | TcpWarpMessage::ConnectForward { connection_id, .. }
+            | TcpWarpMessage::ConnectFailure { connection_id, .. }
+            | TcpWarpMessage::HostConnect { connection_id, .. }
+            | TcpWarpMessage::HostConnectPath { connection_id, .. }
+            | TcpWarpMessage::DisconnectHost { connection_id }
+            | TcpWarpMessage::DisconnectClient { connection_id }
+            | TcpWarpMessage::DatagramConnect { connection_id, .. }
+            | TcpWarpMessage::DatagramClient { connection_id, .. }
+            | TcpWarpMessage::DatagramHost { connection_id, .. } => Some(*connection_id),
+            _ => None,
+        }
+    }
 }
 
 pub struct TcpWarpProtoClient {
@@ -303,3 +1425,721 @@ impl Decoder for TcpWarpProtoHost {
         }))
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    #[test]
+    fn handshake_encode_decode() {
+        let handshake = TcpWarpHandshake {
+            capabilities: TcpWarpProto::CAPABILITY_COMPACT_IDS,
+            services: vec![
+                "whoami=whoami:80".parse().unwrap(),
+                "dns=53".parse().unwrap(),
+            ],
+        };
+        let mut buffer = BytesMut::new();
+        TcpWarpProto::new()
+            .encode(
+                TcpWarpMessage::AddPorts(Some(handshake.clone())),
+                &mut buffer,
+            )
+            .unwrap();
+        assert_eq!(buffer[0], 1);
+        assert_eq!(buffer.len() % 2, 1);
+        let mut partial = buffer.split_to(buffer.len() - 1);
+        assert!(TcpWarpProto::new().decode(&mut partial).unwrap().is_none());
+        partial.unsplit(buffer);
+        match TcpWarpProto::new().decode(&mut partial).unwrap() {
+            Some(TcpWarpMessage::AddPorts(Some(decoded))) => assert_eq!(handshake, decoded),
+            other => panic!("unexpected message: {:?}", other),
+        }
+        assert!(partial.is_empty());
+
+        let mut baseline = BytesMut::from(&[1, 0, 2, 0, 80, 0x1f, 0x90][..]);
+        match TcpWarpProto::new().decode(&mut baseline).unwrap() {
+            Some(TcpWarpMessage::AddPorts(None)) => {}
+            other => panic!("unexpected message: {:?}", other),
+        }
+    }
+
+    /// Encodes message and decodes it on the other side, returns frame length and message.
+    pub(crate) fn transfer(
+        encoder: &mut TcpWarpProto,
+        decoder: &mut TcpWarpProto,
+        message: TcpWarpMessage,
+    ) -> (usize, TcpWarpMessage) {
+        let mut buffer = BytesMut::new();
+        encoder.encode(message, &mut buffer).unwrap();
+        let len = buffer.len();
+        let message = decoder.decode(&mut buffer).unwrap().unwrap();
+        assert!(buffer.is_empty());
+        (len, message)
+    }
+
+    #[test]
+    fn compact_stream_ids() {
+        let mut client = TcpWarpProto::new();
+        let mut server = TcpWarpProto::new();
+        let capabilities = TcpWarpProto::CAPABILITIES;
+        transfer(
+            &mut server,
+            &mut client,
+            TcpWarpMessage::Hello { capabilities },
+        );
+        transfer(
+            &mut client,
+            &mut server,
+            TcpWarpMessage::Hello { capabilities },
+        );
+
+        let connection_id = Uuid::new_v4();
+        let (len, message) = transfer(
+            &mut client,
+            &mut server,
+            TcpWarpMessage::HostConnect {
+                connection_id,
+                host: Some("whoami".into()),
+                port: 80,
+                compress: false,
+                priority: TcpWarpPriority::Interactive,
+            },
+        );
+        assert_eq!(1 + 1 + 16 + 2 + 1 + 1 + 6, len);
+        match message {
+            TcpWarpMessage::HostConnect {
+                connection_id: id,
+                host,
+                port,
+                priority,
+                ..
+            } => assert_eq!(
+                (
+                    connection_id,
+                    Some("whoami".into()),
+                    80,
+                    TcpWarpPriority::Interactive
+                ),
+                (id, host, port, priority)
+            ),
+            other => panic!("unexpected message: {:?}", other),
+        }
+
+        let data = BytesMut::from(&b"ls\n"[..]);
+        let (len, message) = transfer(
+            &mut client,
+            &mut server,
+            TcpWarpMessage::BytesClient {
+                connection_id,
+                data: data.clone(),
+            },
+        );
+        assert_eq!(1 + 1 + 1 + 3, len);
+        match message {
+            TcpWarpMessage::BytesClient {
+                connection_id: id,
+                data: received,
+            } => assert_eq!((connection_id, data), (id, received)),
+            other => panic!("unexpected message: {:?}", other),
+        }
+
+        let (len, message) = transfer(
+            &mut server,
+            &mut client,
+            TcpWarpMessage::DisconnectHost { connection_id },
+        );
+        assert_eq!(2, len);
+        assert_eq!(Some(connection_id), message.connection_id());
+        transfer(
+            &mut client,
+            &mut server,
+            TcpWarpMessage::DisconnectClient { connection_id },
+        );
+
+        // stream is forgotten after both sides disconnected, late frames carry connection id
+        let (len, message) = transfer(
+            &mut server,
+            &mut client,
+            TcpWarpMessage::Connected { connection_id },
+        );
+        assert_eq!(1 + 1 + 16, len);
+        assert_eq!(Some(connection_id), message.connection_id());
+    }
+
+    #[test]
+    fn connect_failure_reasons() {
+        for (capabilities, expected) in &[
+            (TcpWarpProto::CAPABILITIES, TcpWarpConnectFailure::TimedOut),
+            (
+                TcpWarpProto::CAPABILITY_COMPACT_IDS,
+                TcpWarpConnectFailure::Other,
+            ),
+        ] {
+            let mut client = TcpWarpProto::with_capabilities(*capabilities);
+            let mut server = TcpWarpProto::new();
+            transfer(
+                &mut server,
+                &mut client,
+                TcpWarpMessage::Hello {
+                    capabilities: TcpWarpProto::CAPABILITIES,
+                },
+            );
+            transfer(
+                &mut client,
+                &mut server,
+                TcpWarpMessage::Hello {
+                    capabilities: *capabilities,
+                },
+            );
+
+            let connection_id = Uuid::new_v4();
+            let (_, message) = transfer(
+                &mut server,
+                &mut client,
+                TcpWarpMessage::ConnectFailure {
+                    connection_id,
+                    reason: TcpWarpConnectFailure::TimedOut,
+                },
+            );
+            match message {
+                TcpWarpMessage::ConnectFailure {
+                    connection_id: id,
+                    reason,
+                } => assert_eq!((connection_id, *expected), (id, reason)),
+                other => panic!("unexpected message: {:?}", other),
+            }
+        }
+
+        let err: io::Error = TcpWarpConnectFailure::TimedOut.into();
+        assert_eq!(io::ErrorKind::TimedOut, err.kind());
+        assert!(matches!(
+            TcpWarpError::from(err),
+            TcpWarpError::ConnectFailure(TcpWarpConnectFailure::TimedOut)
+        ));
+    }
+
+    #[test]
+    fn connect_priorities() {
+        for (capabilities, expected) in &[
+            (TcpWarpProto::CAPABILITIES, TcpWarpPriority::Bulk),
+            (
+                TcpWarpProto::CAPABILITY_COMPACT_IDS,
+                TcpWarpPriority::Normal,
+            ),
+        ] {
+            let mut client = TcpWarpProto::with_capabilities(*capabilities);
+            let mut server = TcpWarpProto::new();
+            transfer(
+                &mut server,
+                &mut client,
+                TcpWarpMessage::Hello {
+                    capabilities: TcpWarpProto::CAPABILITIES,
+                },
+            );
+            transfer(
+                &mut client,
+                &mut server,
+                TcpWarpMessage::Hello {
+                    capabilities: *capabilities,
+                },
+            );
+
+            let (_, message) = transfer(
+                &mut client,
+                &mut server,
+                TcpWarpMessage::HostConnectPath {
+                    connection_id: Uuid::new_v4(),
+                    path: "/var/run/docker.sock".into(),
+                    compress: false,
+                    priority: TcpWarpPriority::Bulk,
+                },
+            );
+            match message {
+                TcpWarpMessage::HostConnectPath { priority, .. } => assert_eq!(*expected, priority),
+                other => panic!("unexpected message: {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn compact_unknown_stream_is_dropped() {
+        let mut server = TcpWarpProto::new();
+        let mut buffer = BytesMut::new();
+        buffer.put_slice(&[0x80 | 5, 7 << 1, 0x80 | 6]);
+        assert!(server.decode(&mut buffer).unwrap().is_none());
+        assert_eq!(1, buffer.len());
+    }
+
+    fn decode_error(codec: &mut TcpWarpProto, data: &[u8]) -> TcpWarpProtoError {
+        let mut buffer = BytesMut::from(data);
+        let err = codec.decode(&mut buffer).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        err.into_inner()
+            .unwrap()
+            .downcast_ref::<TcpWarpProtoError>()
+            .cloned()
+            .unwrap()
+    }
+
+    #[test]
+    fn unknown_opcode_is_error() {
+        let mut codec = TcpWarpProto::new();
+        assert_eq!(
+            TcpWarpProtoError::UnknownOpcode(42),
+            decode_error(&mut codec, &[42])
+        );
+        assert_eq!(
+            TcpWarpProtoError::UnknownOpcode(0x80 | 13),
+            decode_error(&mut codec, &[0x80 | 13, 2])
+        );
+    }
+
+    #[test]
+    fn oversized_frame_is_error() {
+        let mut codec = TcpWarpProto::new();
+        let mut frame = vec![3];
+        frame.extend_from_slice(&[0; 16]);
+        frame.extend_from_slice(&u32::MAX.to_be_bytes());
+        assert_eq!(
+            TcpWarpProtoError::FrameTooLarge {
+                size: 21 + u32::MAX as usize,
+                max: TcpWarpProto::DEFAULT_MAX_FRAME_SIZE
+            },
+            decode_error(&mut codec, &frame)
+        );
+
+        let mut codec = TcpWarpProto::new().with_max_frame_size(100);
+        let mut buffer = BytesMut::new();
+        codec
+            .encode(
+                TcpWarpMessage::BytesClient {
+                    connection_id: Uuid::new_v4(),
+                    data: BytesMut::from(&[0; 79][..]),
+                },
+                &mut buffer,
+            )
+            .unwrap();
+        assert!(codec.decode(&mut buffer).unwrap().is_some());
+        assert_eq!(
+            TcpWarpProtoError::FrameTooLarge {
+                size: 102,
+                max: 100
+            },
+            decode_error(&mut codec, &[0x80 | 3, 1 << 1, 99])
+        );
+    }
+
+    #[test]
+    fn oversized_path_is_error() {
+        let message = |path: String| TcpWarpMessage::HostConnectPath {
+            connection_id: Uuid::new_v4(),
+            path,
+            compress: false,
+            priority: TcpWarpPriority::Normal,
+        };
+        let mut codec = TcpWarpProto::new();
+        let mut buffer = BytesMut::new();
+        codec
+            .encode(message("a".repeat(u16::MAX as usize)), &mut buffer)
+            .unwrap();
+        assert!(codec.decode(&mut buffer).unwrap().is_some());
+        assert!(codec
+            .encode(message("a".repeat(u16::MAX as usize + 1)), &mut buffer)
+            .is_err());
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn invalid_host_is_error() {
+        let mut codec = TcpWarpProto::new();
+        let mut buffer = BytesMut::new();
+        codec
+            .encode(
+                TcpWarpMessage::HostConnect {
+                    connection_id: Uuid::new_v4(),
+                    host: Some("\u{fffd}".into()),
+                    port: 80,
+                    compress: false,
+                    priority: TcpWarpPriority::Normal,
+                },
+                &mut buffer,
+            )
+            .unwrap();
+        let len = buffer.len();
+        buffer[len - 3..].copy_from_slice(&[0xff, 0xfe, 0xfd]);
+        assert_eq!(
+            TcpWarpProtoError::InvalidHost,
+            decode_error(&mut codec, &buffer)
+        );
+    }
+
+    #[test]
+    fn invalid_text_is_error() {
+        let mut codec = TcpWarpProto::new();
+        let mut buffer = BytesMut::new();
+        codec
+            .encode(
+                TcpWarpMessage::HostConnectPath {
+                    connection_id: Uuid::new_v4(),
+                    path: "\u{fffd}".into(),
+                    compress: false,
+                    priority: TcpWarpPriority::Normal,
+                },
+                &mut buffer,
+            )
+            .unwrap();
+        let len = buffer.len();
+        buffer[len - 3..].copy_from_slice(&[0xff, 0xfe, 0xfd]);
+        assert_eq!(
+            TcpWarpProtoError::Malformed("path is not valid UTF-8"),
+            decode_error(&mut codec, &buffer)
+        );
+
+        let mut buffer = BytesMut::new();
+        codec
+            .encode(
+                TcpWarpMessage::AddPorts(Some(TcpWarpHandshake {
+                    capabilities: 0,
+                    services: vec![TcpWarpService {
+                        name: "\u{fffd}".into(),
+                        host: None,
+                        port: 80,
+                    }],
+                })),
+                &mut buffer,
+            )
+            .unwrap();
+        buffer[17..20].copy_from_slice(&[0xff, 0xfe, 0xfd]);
+        assert_eq!(
+            TcpWarpProtoError::Malformed("service name is not valid UTF-8"),
+            decode_error(&mut codec, &buffer)
+        );
+
+        let mut buffer = BytesMut::new();
+        codec
+            .encode(
+                TcpWarpMessage::AddPorts(Some(TcpWarpHandshake {
+                    capabilities: 0,
+                    services: vec![TcpWarpService {
+                        name: "web".into(),
+                        host: Some("\u{fffd}".into()),
+                        port: 80,
+                    }],
+                })),
+                &mut buffer,
+            )
+            .unwrap();
+        buffer[20..23].copy_from_slice(&[0xff, 0xfe, 0xfd]);
+        assert_eq!(
+            TcpWarpProtoError::InvalidHost,
+            decode_error(&mut codec, &buffer)
+        );
+
+        let mut frame = vec![1, 0, 6, 0x74, 0x77, 0, 0, 0, 0, 0xff, 0xff];
+        frame.extend_from_slice(&[0; 4]);
+        assert_eq!(
+            TcpWarpProtoError::Malformed("handshake is truncated"),
+            decode_error(&mut codec, &frame)
+        );
+    }
+
+    #[test]
+    fn message_debug_redacts_payload() {
+        let connection_id = Uuid::nil();
+        let message = TcpWarpMessage::BytesClient {
+            connection_id,
+            data: b"password"[..].into(),
+        };
+        assert_eq!(
+            format!(
+                "BytesClient {{ connection_id: {:?}, data: <8 bytes> }}",
+                connection_id
+            ),
+            format!("{:?}", message)
+        );
+        let message = TcpWarpMessage::DatagramClient {
+            connection_id,
+            host: None,
+            port: 53,
+            data: b"secret"[..].into(),
+        };
+        assert!(!format!("{:?}", message).contains("secret"));
+    }
+
+    mod wire {
+        use super::*;
+        use proptest::prelude::*;
+
+        /// Codecs of one tunnel direction after `Hello` handshake.
+        fn handshake(capabilities: u32) -> (TcpWarpProto, TcpWarpProto) {
+            let mut encoder = TcpWarpProto::with_capabilities(capabilities);
+            let mut decoder = TcpWarpProto::with_capabilities(capabilities);
+            let (_, hello) = transfer(
+                &mut decoder,
+                &mut encoder,
+                TcpWarpMessage::Hello { capabilities },
+            );
+            assert_eq!(
+                format!("{:?}", TcpWarpMessage::Hello { capabilities }),
+                format!("{:?}", hello)
+            );
+            transfer(
+                &mut encoder,
+                &mut decoder,
+                TcpWarpMessage::Hello { capabilities },
+            );
+            (encoder, decoder)
+        }
+
+        fn decode_all(
+            decoder: &mut TcpWarpProto,
+            buffer: &mut BytesMut,
+            decoded: &mut Vec<String>,
+        ) {
+            while let Some(message) = decoder.decode(buffer).unwrap() {
+                decoded.push(describe(&message));
+            }
+        }
+
+        /// Debug output of message together with payload, which debug output leaves out.
+        fn describe(message: &TcpWarpMessage) -> String {
+            match message {
+                TcpWarpMessage::BytesClient { data, .. }
+                | TcpWarpMessage::BytesHost { data, .. }
+                | TcpWarpMessage::DatagramClient { data, .. }
+                | TcpWarpMessage::DatagramHost { data, .. } => {
+                    format!("{:?} {:?}", message, data)
+                }
+                _ => format!("{:?}", message),
+            }
+        }
+
+        fn host() -> impl Strategy<Value = Option<String>> {
+            proptest::option::of("\\PC{1,20}")
+        }
+
+        fn data() -> impl Strategy<Value = BytesMut> {
+            proptest::collection::vec(any::<u8>(), 0..100).prop_map(|x| BytesMut::from(&x[..]))
+        }
+
+        /// Every wire message except handshakes, which renegotiate codec.
+        ///
+        /// Connection ids are drawn from small sets, so frames refer to open streams. Connection
+        /// keeps compression of its first opening frame, so it is derived from id, and UDP
+        /// associations never share ids with TCP connections. Failure reasons other than
+        /// `Other` and priorities other than `Normal` are only sent when negotiated.
+        fn message(capabilities: u32) -> impl Strategy<Value = TcpWarpMessage> {
+            let compress = capabilities
+                & (TcpWarpProto::CAPABILITY_DEFLATE | TcpWarpProto::CAPABILITY_ZSTD)
+                != 0;
+            let reasons = capabilities & TcpWarpProto::CAPABILITY_FAILURE_REASONS != 0;
+            let priorities = capabilities & TcpWarpProto::CAPABILITY_PRIORITIES != 0;
+            let priority = (0..3u8).prop_map(move |code| {
+                if priorities {
+                    TcpWarpPriority::from_code(code)
+                } else {
+                    TcpWarpPriority::Normal
+                }
+            });
+            let reason = (0..5u8).prop_map(move |code| {
+                if reasons {
+                    TcpWarpConnectFailure::from_code(code)
+                } else {
+                    TcpWarpConnectFailure::Other
+                }
+            });
+            let id = (0..4u128).prop_map(Uuid::from_u128);
+            let datagram_id = (4..6u128).prop_map(Uuid::from_u128);
+            let any_id = (0..6u128).prop_map(Uuid::from_u128);
+            let compress = move |connection_id: Uuid| compress && connection_id.as_u128() % 2 == 1;
+            prop_oneof![
+                Just(()).prop_map(|_| TcpWarpMessage::AddPorts(None)),
+                (id.clone(), host(), any::<u16>(), priority.clone()).prop_map(
+                    move |(connection_id, host, port, priority)| TcpWarpMessage::HostConnect {
+                        connection_id,
+                        host,
+                        port,
+                        compress: compress(connection_id),
+                        priority,
+                    }
+                ),
+                (id.clone(), data()).prop_map(|(connection_id, data)| {
+                    TcpWarpMessage::BytesClient {
+                        connection_id,
+                        data,
+                    }
+                }),
+                (id.clone(), data()).prop_map(|(connection_id, data)| {
+                    TcpWarpMessage::BytesHost {
+                        connection_id,
+                        data,
+                    }
+                }),
+                any_id
+                    .clone()
+                    .prop_map(|connection_id| TcpWarpMessage::Connected { connection_id }),
+                any_id
+                    .clone()
+                    .prop_map(|connection_id| TcpWarpMessage::DisconnectHost { connection_id }),
+                any_id
+                    .clone()
+                    .prop_map(|connection_id| TcpWarpMessage::DisconnectClient { connection_id }),
+                (any_id.clone(), reason).prop_map(|(connection_id, reason)| {
+                    TcpWarpMessage::ConnectFailure {
+                        connection_id,
+                        reason,
+                    }
+                }),
+                (datagram_id.clone(), host(), any::<u16>(), data()).prop_map(
+                    |(connection_id, host, port, data)| TcpWarpMessage::DatagramClient {
+                        connection_id,
+                        host,
+                        port,
+                        data,
+                    }
+                ),
+                (datagram_id, data()).prop_map(|(connection_id, data)| {
+                    TcpWarpMessage::DatagramHost {
+                        connection_id,
+                        data,
+                    }
+                }),
+                (id, "\\PC{0,30}", priority).prop_map(move |(connection_id, path, priority)| {
+                    TcpWarpMessage::HostConnectPath {
+                        connection_id,
+                        path,
+                        compress: compress(connection_id),
+                        priority,
+                    }
+                }),
+            ]
+        }
+
+        /// Handshake of server or client with given capabilities.
+        ///
+        /// Service names and hosts of any length make handshake odd, so padding is covered.
+        fn negotiation(capabilities: u32) -> impl Strategy<Value = TcpWarpMessage> {
+            let service = ("\\PC{0,12}", host(), any::<u16>())
+                .prop_map(|(name, host, port)| TcpWarpService { name, host, port });
+            prop_oneof![
+                proptest::collection::vec(service, 0..4).prop_map(move |services| {
+                    TcpWarpMessage::AddPorts(Some(TcpWarpHandshake {
+                        capabilities,
+                        services,
+                    }))
+                }),
+                Just(()).prop_map(move |_| TcpWarpMessage::Hello { capabilities }),
+            ]
+        }
+
+        /// Handshake followed by messages sent once capabilities are negotiated.
+        fn negotiated_messages() -> impl Strategy<Value = (u32, Vec<TcpWarpMessage>)> {
+            prop_oneof![
+                Just(0),
+                Just(TcpWarpProto::CAPABILITY_COMPACT_IDS),
+                Just(TcpWarpProto::CAPABILITIES),
+            ]
+            .prop_flat_map(|capabilities| {
+                (
+                    Just(capabilities),
+                    negotiation(capabilities),
+                    proptest::collection::vec(message(capabilities), 0..4),
+                )
+            })
+            .prop_map(|(capabilities, handshake, mut messages)| {
+                messages.insert(0, handshake);
+                (capabilities, messages)
+            })
+        }
+
+        /// Encodes messages and decodes them from stream split at every byte boundary.
+        ///
+        /// Stream starting with handshake is decoded by codec which did not negotiate yet.
+        fn round_trip(capabilities: u32, messages: Vec<TcpWarpMessage>) {
+            let expected: Vec<_> = messages.iter().map(describe).collect();
+            let negotiates = matches!(
+                messages.first(),
+                Some(TcpWarpMessage::Hello { .. }) | Some(TcpWarpMessage::AddPorts(Some(_)))
+            );
+            let handshake = |capabilities| {
+                let (encoder, decoder) = handshake(capabilities);
+                if negotiates {
+                    (encoder, TcpWarpProto::with_capabilities(capabilities))
+                } else {
+                    (encoder, decoder)
+                }
+            };
+            let (mut encoder, mut decoder) = handshake(capabilities);
+            let mut stream = BytesMut::new();
+            for message in messages {
+                encoder.encode(message, &mut stream).unwrap();
+            }
+
+            let mut buffer = stream.clone();
+            let mut decoded = vec![];
+            decode_all(&mut decoder, &mut buffer, &mut decoded);
+            assert!(buffer.is_empty());
+            assert_eq!(expected, decoded);
+
+            for split in 0..stream.len() {
+                let (_, mut decoder) = handshake(capabilities);
+                let mut buffer = BytesMut::from(&stream[..split]);
+                let mut decoded = vec![];
+                decode_all(&mut decoder, &mut buffer, &mut decoded);
+                buffer.extend_from_slice(&stream[split..]);
+                decode_all(&mut decoder, &mut buffer, &mut decoded);
+                assert!(buffer.is_empty(), "split at {}", split);
+                assert_eq!(expected, decoded, "split at {}", split);
+            }
+
+            let (_, mut decoder) = handshake(capabilities);
+            let mut buffer = BytesMut::new();
+            let mut decoded = vec![];
+            for byte in stream.iter() {
+                buffer.put_u8(*byte);
+                decode_all(&mut decoder, &mut buffer, &mut decoded);
+            }
+            assert_eq!(expected, decoded);
+        }
+
+        proptest! {
+            #[test]
+            fn plain_round_trip(messages in proptest::collection::vec(message(0), 1..6)) {
+                round_trip(0, messages);
+            }
+
+            #[test]
+            fn compact_round_trip(
+                messages in proptest::collection::vec(
+                    message(TcpWarpProto::CAPABILITY_COMPACT_IDS),
+                    1..6,
+                )
+            ) {
+                round_trip(TcpWarpProto::CAPABILITY_COMPACT_IDS, messages);
+            }
+
+            #[test]
+            fn compressed_round_trip(
+                messages in proptest::collection::vec(
+                    message(TcpWarpProto::CAPABILITIES),
+                    1..6,
+                )
+            ) {
+                round_trip(TcpWarpProto::CAPABILITIES, messages);
+            }
+
+            #[test]
+            fn handshake_round_trip((capabilities, messages) in negotiated_messages()) {
+                round_trip(capabilities, messages);
+            }
+
+            #[test]
+            fn decode_never_panics(data in proptest::collection::vec(any::<u8>(), 0..200)) {
+                let (_, mut decoder) = handshake(TcpWarpProto::CAPABILITIES);
+                let mut buffer = BytesMut::from(&data[..]);
+                while let Ok(Some(_)) = decoder.decode(&mut buffer) {}
+            }
+        }
+    }
+}
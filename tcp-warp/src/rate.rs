@@ -0,0 +1,158 @@
+use super::*;
+use std::{sync::Weak, time::Instant};
+
+/// Token bucket of bytes per second, bursts up to one second of traffic.
+///
+/// Chunks larger than available tokens are let through after waiting for the debt.
+struct TokenBucket {
+    rate: f64,
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    fn new(rate: u64) -> Self {
+        let rate = rate.max(1) as f64;
+        Self {
+            rate,
+            state: Mutex::new((rate, Instant::now())),
+        }
+    }
+
+    /// Waits until bytes fit into rate.
+    async fn take(&self, bytes: usize) {
+        let wait = {
+            let mut state = match self.state.lock() {
+                Ok(state) => state,
+                Err(err) => err.into_inner(),
+            };
+            let (tokens, last) = &mut *state;
+            let now = Instant::now();
+            *tokens =
+                (*tokens + now.duration_since(*last).as_secs_f64() * self.rate).min(self.rate);
+            *last = now;
+            *tokens -= bytes as f64;
+            if *tokens >= 0.0 {
+                return;
+            }
+            Duration::from_secs_f64(-*tokens / self.rate)
+        };
+        delay_for(wait).await;
+    }
+}
+
+/// Buckets of both directions, upload is traffic from client to host.
+pub(crate) struct RateLimit {
+    upload: TokenBucket,
+    download: TokenBucket,
+}
+
+impl RateLimit {
+    pub(crate) fn new(rate: u64) -> Self {
+        Self {
+            upload: TokenBucket::new(rate),
+            download: TokenBucket::new(rate),
+        }
+    }
+}
+
+/// Rate limits applied together, traffic waits for each of them.
+#[derive(Clone, Default)]
+pub(crate) struct RateLimits(Vec<Arc<RateLimit>>);
+
+impl RateLimits {
+    /// Rate limit of mapping shared by its connections.
+    pub(crate) fn mapping(address: &TcpWarpPortConnection) -> Self {
+        Self(
+            address
+                .rate
+                .map(|rate| Arc::new(RateLimit::new(rate)))
+                .into_iter()
+                .collect(),
+        )
+    }
+
+    pub(crate) fn push(&mut self, limit: Arc<RateLimit>) {
+        self.0.push(limit);
+    }
+
+    pub(crate) async fn upload(&self, bytes: usize) {
+        for limit in &self.0 {
+            limit.upload.take(bytes).await;
+        }
+    }
+
+    pub(crate) async fn download(&self, bytes: usize) {
+        for limit in &self.0 {
+            limit.download.take(bytes).await;
+        }
+    }
+}
+
+/// Rate limits keyed by client address, shared while any tunnel of client is open.
+pub(crate) struct ClientRates {
+    rate: u64,
+    clients: Mutex<HashMap<IpAddr, Weak<RateLimit>>>,
+}
+
+impl ClientRates {
+    pub(crate) fn new(rate: u64) -> Self {
+        Self {
+            rate,
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub(crate) fn get(&self, client: IpAddr) -> Arc<RateLimit> {
+        let mut clients = match self.clients.lock() {
+            Ok(clients) => clients,
+            Err(err) => err.into_inner(),
+        };
+        if let Some(limit) = clients.get(&client).and_then(Weak::upgrade) {
+            return limit;
+        }
+        clients.retain(|_, limit| limit.strong_count() > 0);
+        let limit = Arc::new(RateLimit::new(self.rate));
+        clients.insert(client, Arc::downgrade(&limit));
+        limit
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn bucket_waits_for_debt() {
+        let bucket = TokenBucket::new(10_000);
+        let started = Instant::now();
+        bucket.take(10_000).await;
+        assert!(started.elapsed() < Duration::from_millis(200));
+        bucket.take(5_000).await;
+        assert!(started.elapsed() >= Duration::from_millis(400));
+    }
+
+    #[test]
+    fn client_rate_is_shared_while_used() {
+        let rates = ClientRates::new(1000);
+        let (first, second) = (Ipv4Addr::new(192, 0, 2, 1), Ipv4Addr::new(192, 0, 2, 2));
+        let limit = rates.get(first.into());
+        assert!(Arc::ptr_eq(&limit, &rates.get(first.into())));
+        assert!(!Arc::ptr_eq(&limit, &rates.get(second.into())));
+
+        // limits of closed clients are forgotten
+        drop(limit);
+        let _limit = rates.get(second.into());
+        assert_eq!(1, rates.clients.lock().unwrap().len());
+    }
+
+    #[test]
+    fn mapping_rate() {
+        assert!(RateLimits::mapping(&"8080".parse().unwrap()).0.is_empty());
+        assert_eq!(
+            1,
+            RateLimits::mapping(&"8080:whoami:80,rate=1000".parse().unwrap())
+                .0
+                .len()
+        );
+    }
+}
@@ -0,0 +1,273 @@
+use super::*;
+use std::collections::VecDeque;
+
+/// Scheduling class of connections of mapping, tunnel is shared by weight of the class.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum TcpWarpPriority {
+    /// Latency sensitive traffic like keystrokes of terminal sessions.
+    Interactive = 0,
+    #[default]
+    Normal = 1,
+    /// Transfers which give way to everything else.
+    Bulk = 2,
+}
+
+impl TcpWarpPriority {
+    /// Priority of wire code, codes of newer peers are normal priority.
+    pub(crate) fn from_code(code: u8) -> Self {
+        match code {
+            0 => TcpWarpPriority::Interactive,
+            2 => TcpWarpPriority::Bulk,
+            _ => TcpWarpPriority::Normal,
+        }
+    }
+
+    /// Share of tunnel throughput relative to other priorities.
+    pub fn weight(self) -> u64 {
+        match self {
+            TcpWarpPriority::Interactive => 16,
+            TcpWarpPriority::Normal => 4,
+            TcpWarpPriority::Bulk => 1,
+        }
+    }
+}
+
+impl FromStr for TcpWarpPriority {
+    type Err = TcpWarpError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "interactive" => Ok(TcpWarpPriority::Interactive),
+            "normal" => Ok(TcpWarpPriority::Normal),
+            "bulk" => Ok(TcpWarpPriority::Bulk),
+            _ => Err(TcpWarpError::parse(
+                s,
+                "unknown priority, expected interactive, normal or bulk",
+            )),
+        }
+    }
+}
+
+impl fmt::Display for TcpWarpPriority {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TcpWarpPriority::Interactive => write!(f, "interactive"),
+            TcpWarpPriority::Normal => write!(f, "normal"),
+            TcpWarpPriority::Bulk => write!(f, "bulk"),
+        }
+    }
+}
+
+/// Largest payload of data frame written to tunnel, larger payloads are split.
+const CHUNK_SIZE: usize = 16 * 1024;
+
+/// Payload bytes held by scheduler before tunnel writer stops receiving.
+const MAX_BUFFERED: usize = 1024 * 1024;
+
+/// Scheduling cost of frame besides its payload.
+const FRAME_COST: u64 = 64;
+
+/// Virtual time units of one byte, divisible by every priority weight.
+const BYTE_COST: u64 = 16;
+
+/// Message of tunnel writer loop.
+///
+/// Received item is a message as well, so variants are of similar size.
+#[allow(clippy::large_enum_variant)]
+pub(crate) enum Scheduled<T> {
+    /// Message received from channel, to be pushed to scheduler or handled otherwise.
+    Received(T),
+    /// Frame to be written to tunnel now.
+    Write(TcpWarpMessage),
+}
+
+/// Weighted fair queuing of frames written to tunnel.
+///
+/// Frames of one connection keep their order, connections get share of tunnel by weight of
+/// their priority, so small frames of interactive connection are not stuck behind bulk
+/// transfer. Each frame is tagged with virtual finish time and the frame with the earliest
+/// tag goes first. Frames without connection, like handshake, go before all of them.
+#[derive(Default)]
+pub(crate) struct FrameScheduler {
+    control: VecDeque<TcpWarpMessage>,
+    /// Frames of connections tagged with finish time.
+    queues: HashMap<Uuid, VecDeque<(u64, TcpWarpMessage)>>,
+    /// Finish time of the last written frame.
+    virtual_time: u64,
+    buffered: usize,
+}
+
+impl FrameScheduler {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.control.is_empty() && self.queues.is_empty()
+    }
+
+    /// Queues message, splitting large data frames into chunks.
+    pub(crate) fn push(&mut self, message: TcpWarpMessage, priority: TcpWarpPriority) {
+        let connection_id = match message.connection_id() {
+            Some(connection_id) => connection_id,
+            None => {
+                self.control.push_back(message);
+                return;
+            }
+        };
+        let queue = self.queues.entry(connection_id).or_default();
+        let mut finish = queue.back().map_or(0, |(finish, _)| *finish);
+        for frame in chunks(message) {
+            let len = payload_len(&frame);
+            finish = finish.max(self.virtual_time)
+                + (FRAME_COST + len as u64) * BYTE_COST / priority.weight();
+            self.buffered += len;
+            queue.push_back((finish, frame));
+        }
+    }
+
+    /// Takes frame with the earliest finish time.
+    pub(crate) fn pop(&mut self) -> Option<TcpWarpMessage> {
+        if let Some(message) = self.control.pop_front() {
+            return Some(message);
+        }
+        let (_, connection_id) = self
+            .queues
+            .iter()
+            .filter_map(|(connection_id, queue)| {
+                queue.front().map(|(finish, _)| (*finish, *connection_id))
+            })
+            .min()?;
+        let queue = self.queues.get_mut(&connection_id)?;
+        let (finish, message) = queue.pop_front()?;
+        if queue.is_empty() {
+            self.queues.remove(&connection_id);
+        }
+        self.virtual_time = finish;
+        self.buffered -= payload_len(&message);
+        Some(message)
+    }
+
+    /// Receives from channel while there is room, otherwise gives the next frame to write.
+    ///
+    /// Waits for channel only when nothing is queued, `None` once channel is closed and
+    /// every queued frame is written.
+    pub(crate) async fn next<T>(&mut self, receiver: &mut Receiver<T>) -> Option<Scheduled<T>> {
+        if self.is_empty() {
+            return receiver.next().await.map(Scheduled::Received);
+        }
+        if self.buffered < MAX_BUFFERED {
+            if let Ok(message) = receiver.try_recv() {
+                return Some(Scheduled::Received(message));
+            }
+        }
+        self.pop().map(Scheduled::Write)
+    }
+}
+
+fn payload_len(message: &TcpWarpMessage) -> usize {
+    match message {
+        TcpWarpMessage::BytesClient { data, .. }
+        | TcpWarpMessage::BytesHost { data, .. }
+        | TcpWarpMessage::DatagramClient { data, .. }
+        | TcpWarpMessage::DatagramHost { data, .. } => data.len(),
+        _ => 0,
+    }
+}
+
+fn chunks(message: TcpWarpMessage) -> Vec<TcpWarpMessage> {
+    match message {
+        TcpWarpMessage::BytesClient {
+            connection_id,
+            data,
+        } => split(data)
+            .map(|data| TcpWarpMessage::BytesClient {
+                connection_id,
+                data,
+            })
+            .collect(),
+        TcpWarpMessage::BytesHost {
+            connection_id,
+            data,
+        } => split(data)
+            .map(|data| TcpWarpMessage::BytesHost {
+                connection_id,
+                data,
+            })
+            .collect(),
+        message => vec![message],
+    }
+}
+
+fn split(mut data: BytesMut) -> impl Iterator<Item = BytesMut> {
+    let mut chunks = vec![];
+    while data.len() > CHUNK_SIZE {
+        chunks.push(data.split_to(CHUNK_SIZE));
+    }
+    chunks.push(data);
+    chunks.into_iter()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scheduler_interleaves_connections() {
+        let (bulk, interactive) = (Uuid::new_v4(), Uuid::new_v4());
+        let data: Vec<u8> = (0..200_000).map(|x| x as u8).collect();
+        let mut scheduler = FrameScheduler::default();
+        scheduler.push(
+            TcpWarpMessage::BytesClient {
+                connection_id: bulk,
+                data: data.as_slice().into(),
+            },
+            TcpWarpPriority::Bulk,
+        );
+        scheduler.push(
+            TcpWarpMessage::BytesClient {
+                connection_id: interactive,
+                data: b"ls\n"[..].into(),
+            },
+            TcpWarpPriority::Interactive,
+        );
+        scheduler.push(TcpWarpMessage::AddPorts(None), TcpWarpPriority::Bulk);
+
+        assert!(matches!(scheduler.pop(), Some(TcpWarpMessage::AddPorts(_))));
+        assert!(matches!(
+            scheduler.pop(),
+            Some(TcpWarpMessage::BytesClient { connection_id, .. }) if connection_id == interactive
+        ));
+        let mut received = vec![];
+        while let Some(message) = scheduler.pop() {
+            match message {
+                TcpWarpMessage::BytesClient {
+                    connection_id,
+                    data,
+                } if connection_id == bulk => {
+                    assert!(data.len() <= 16 * 1024);
+                    received.extend_from_slice(&data);
+                }
+                other => panic!("unexpected message {:?}", other),
+            }
+        }
+        assert_eq!(data, received);
+        assert!(scheduler.is_empty());
+
+        // connections share tunnel by weight of priority
+        let (normal, bulk) = (Uuid::new_v4(), Uuid::new_v4());
+        for (connection_id, priority) in &[
+            (bulk, TcpWarpPriority::Bulk),
+            (normal, TcpWarpPriority::Normal),
+        ] {
+            scheduler.push(
+                TcpWarpMessage::BytesHost {
+                    connection_id: *connection_id,
+                    data: vec![0; 1_000_000].as_slice().into(),
+                },
+                *priority,
+            );
+        }
+        let normal_frames = (0..50)
+            .filter_map(|_| scheduler.pop())
+            .filter(|message| message.connection_id() == Some(normal))
+            .count();
+        assert_eq!(40, normal_frames);
+    }
+}
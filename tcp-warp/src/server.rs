@@ -3,50 +3,393 @@ use super::*;
 pub struct TcpWarpServer {
     listen_address: SocketAddr,
     connect_address: IpAddr,
+    udp_idle_timeout: Duration,
+    connect_timeout: Duration,
+    idle_timeout: Option<Duration>,
+    max_lifetime: Option<Duration>,
+    max_connections: Option<usize>,
+    max_tunnel_connections: Option<usize>,
+    max_client_connections: Option<usize>,
+    tunnel_rate: Option<u64>,
+    client_rate: Option<u64>,
+    audit_log: Option<TcpWarpAuditLog>,
+    unix_socket_paths: Arc<Vec<PathBuf>>,
+    services: Arc<Vec<TcpWarpService>>,
+    compression: bool,
+    max_frame_size: usize,
+    metrics: Arc<TcpWarpMetrics>,
+    events: TcpWarpEvents,
 }
 
-impl TcpWarpServer {
-    pub fn new(listen_address: SocketAddr, connect_address: IpAddr) -> Self {
+/// Configuration of [`TcpWarpServer`].
+///
+/// Defaults are the same as defaults of `tcp-warp server` command line.
+#[derive(Debug, Clone)]
+pub struct TcpWarpServerBuilder {
+    listen_address: SocketAddr,
+    connect_address: IpAddr,
+    udp_idle_timeout: Duration,
+    connect_timeout: Duration,
+    idle_timeout: Option<Duration>,
+    max_lifetime: Option<Duration>,
+    max_connections: Option<usize>,
+    max_tunnel_connections: Option<usize>,
+    max_client_connections: Option<usize>,
+    tunnel_rate: Option<u64>,
+    client_rate: Option<u64>,
+    audit_log: Option<TcpWarpAuditLog>,
+    unix_socket_paths: Vec<PathBuf>,
+    services: Vec<TcpWarpService>,
+    compression: bool,
+    max_frame_size: usize,
+}
+
+impl TcpWarpServerBuilder {
+    pub const DEFAULT_LISTEN_ADDRESS: SocketAddr =
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 18000);
+    pub const DEFAULT_CONNECT_ADDRESS: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+    pub const DEFAULT_UDP_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+    pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets address on which tunnels are accepted.
+    pub fn with_listen_address(mut self, listen_address: SocketAddr) -> Self {
+        self.listen_address = listen_address;
+        self
+    }
+
+    /// Sets local address from which connections to hosts are made.
+    pub fn with_connect_address(mut self, connect_address: IpAddr) -> Self {
+        self.connect_address = connect_address;
+        self
+    }
+
+    /// Sets how long UDP association of a client address lives without datagrams.
+    pub fn with_udp_idle_timeout(mut self, udp_idle_timeout: Duration) -> Self {
+        self.udp_idle_timeout = udp_idle_timeout;
+        self
+    }
+
+    /// Sets how long connect to target may take before client gets timeout failure.
+    pub fn with_connect_timeout(mut self, connect_timeout: Duration) -> Self {
+        self.connect_timeout = connect_timeout;
+        self
+    }
+
+    /// Sets how long connection may stay without bytes in either direction, unlimited by default.
+    pub fn with_idle_timeout(mut self, idle_timeout: Option<Duration>) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Sets how long connection may stay open, unlimited by default.
+    pub fn with_max_lifetime(mut self, max_lifetime: Option<Duration>) -> Self {
+        self.max_lifetime = max_lifetime;
+        self
+    }
+
+    /// Sets limit of concurrent connections of all clients, unlimited by default.
+    ///
+    /// Connects over limit fail with [`TcpWarpConnectFailure::LimitExceeded`].
+    pub fn with_max_connections(mut self, max_connections: Option<usize>) -> Self {
+        self.max_connections = max_connections;
+        self
+    }
+
+    /// Sets limit of concurrent connections of single tunnel, unlimited by default.
+    pub fn with_max_tunnel_connections(mut self, max_tunnel_connections: Option<usize>) -> Self {
+        self.max_tunnel_connections = max_tunnel_connections;
+        self
+    }
+
+    /// Sets limit of concurrent connections of all tunnels from one client IP address,
+    /// unlimited by default.
+    pub fn with_max_client_connections(mut self, max_client_connections: Option<usize>) -> Self {
+        self.max_client_connections = max_client_connections;
+        self
+    }
+
+    /// Sets limit of bytes per second of single tunnel in each direction, unlimited by default.
+    pub fn with_tunnel_rate(mut self, tunnel_rate: Option<u64>) -> Self {
+        self.tunnel_rate = tunnel_rate;
+        self
+    }
+
+    /// Sets limit of bytes per second of all tunnels from one client IP address in each
+    /// direction, unlimited by default.
+    pub fn with_client_rate(mut self, client_rate: Option<u64>) -> Self {
+        self.client_rate = client_rate;
+        self
+    }
+
+    /// Sets where audit record of every connect request is written, disabled by default.
+    ///
+    /// Record is a JSON object written once connection is over, it is opened when server
+    /// starts listening.
+    pub fn with_audit_log(mut self, audit_log: Option<TcpWarpAuditLog>) -> Self {
+        self.audit_log = audit_log;
+        self
+    }
+
+    /// Sets unix sockets clients may connect to, none by default.
+    ///
+    /// Path is allowed if it is one of the paths or is inside one of them, paths with `..`
+    /// are never allowed. Other connects fail with [`TcpWarpConnectFailure::Forbidden`].
+    pub fn with_unix_socket_paths(mut self, unix_socket_paths: Vec<PathBuf>) -> Self {
+        self.unix_socket_paths = unix_socket_paths;
+        self
+    }
+
+    /// Sets catalog of named services advertised to clients on connect.
+    ///
+    /// Clients refer to services by name instead of host and port.
+    pub fn with_services(mut self, services: Vec<TcpWarpService>) -> Self {
+        self.services = services;
+        self
+    }
+
+    /// Sets whether compression requested by clients is accepted, enabled by default.
+    pub fn with_compression(mut self, compression: bool) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Sets maximal size of frame accepted from clients, larger frame closes the tunnel.
+    pub fn with_max_frame_size(mut self, max_frame_size: usize) -> Self {
+        self.max_frame_size = max_frame_size;
+        self
+    }
+
+    pub fn build(self) -> TcpWarpServer {
+        TcpWarpServer {
+            listen_address: self.listen_address,
+            connect_address: self.connect_address,
+            udp_idle_timeout: self.udp_idle_timeout,
+            connect_timeout: self.connect_timeout,
+            idle_timeout: self.idle_timeout,
+            max_lifetime: self.max_lifetime,
+            max_connections: self.max_connections,
+            max_tunnel_connections: self.max_tunnel_connections,
+            max_client_connections: self.max_client_connections,
+            tunnel_rate: self.tunnel_rate,
+            client_rate: self.client_rate,
+            audit_log: self.audit_log,
+            unix_socket_paths: Arc::new(self.unix_socket_paths),
+            services: Arc::new(self.services),
+            compression: self.compression,
+            max_frame_size: self.max_frame_size,
+            metrics: Arc::new(TcpWarpMetrics::default()),
+            events: TcpWarpEvents::new(),
+        }
+    }
+}
+
+impl Default for TcpWarpServerBuilder {
+    fn default() -> Self {
         Self {
-            listen_address,
-            connect_address,
+            listen_address: Self::DEFAULT_LISTEN_ADDRESS,
+            connect_address: Self::DEFAULT_CONNECT_ADDRESS,
+            udp_idle_timeout: Self::DEFAULT_UDP_IDLE_TIMEOUT,
+            connect_timeout: Self::DEFAULT_CONNECT_TIMEOUT,
+            idle_timeout: None,
+            max_lifetime: None,
+            max_connections: None,
+            max_tunnel_connections: None,
+            max_client_connections: None,
+            tunnel_rate: None,
+            client_rate: None,
+            audit_log: None,
+            unix_socket_paths: vec![],
+            services: vec![],
+            compression: true,
+            max_frame_size: TcpWarpProto::DEFAULT_MAX_FRAME_SIZE,
         }
     }
+}
 
-    pub async fn listen(&self) -> Result<(), Box<dyn Error>> {
-        let mut listener = TcpListener::bind(&self.listen_address).await?;
+impl TcpWarpServer {
+    pub fn new(listen_address: SocketAddr, connect_address: IpAddr) -> Self {
+        Self::builder()
+            .with_listen_address(listen_address)
+            .with_connect_address(connect_address)
+            .build()
+    }
+
+    pub fn builder() -> TcpWarpServerBuilder {
+        TcpWarpServerBuilder::default()
+    }
+
+    /// Metrics of all tunnels of this server.
+    pub fn metrics(&self) -> Arc<TcpWarpMetrics> {
+        self.metrics.clone()
+    }
+
+    /// Subscribes to events of tunnels and connections of this server.
+    ///
+    /// Only events published after subscription are received.
+    pub fn events(&self) -> broadcast::Receiver<TcpWarpEvent> {
+        self.events.subscribe()
+    }
+
+    pub async fn listen(&self) -> Result<(), TcpWarpError> {
+        let listener = TcpListener::bind(&self.listen_address)
+            .await
+            .map_err(|source| TcpWarpError::Bind {
+                address: self.listen_address.to_string(),
+                source,
+            })?;
+        self.listen_on(listener).await
+    }
+
+    /// Accepts tunnels on already bound listener, listen address is ignored.
+    ///
+    /// Useful with listener bound to port 0, which lets OS choose free port.
+    pub async fn listen_on(&self, mut listener: TcpListener) -> Result<(), TcpWarpError> {
+        let audit = match &self.audit_log {
+            Some(audit_log) => Some(Arc::new(AuditWriter::open(audit_log)?)),
+            None => None,
+        };
         let mut incoming = listener.incoming();
-        let connect_address = self.connect_address;
+        let context = ServerContext {
+            connect_address: self.connect_address,
+            udp_idle_timeout: self.udp_idle_timeout,
+            connect_timeout: self.connect_timeout,
+            idle_timeout: self.idle_timeout,
+            max_lifetime: self.max_lifetime,
+            unix_socket_paths: self.unix_socket_paths.clone(),
+            services: self.services.clone(),
+            limits: Arc::new(ServerLimits::new(
+                self.max_connections,
+                self.max_tunnel_connections,
+                self.max_client_connections,
+            )),
+            tunnel_rate: self.tunnel_rate,
+            client_rates: self
+                .client_rate
+                .map(|rate| Arc::new(ClientRates::new(rate))),
+            audit,
+            metrics: self.metrics.clone(),
+            events: self.events.clone(),
+        };
+        let capabilities = if self.compression {
+            TcpWarpProto::CAPABILITIES
+        } else {
+            TcpWarpProto::CAPABILITIES
+                & !(TcpWarpProto::CAPABILITY_DEFLATE | TcpWarpProto::CAPABILITY_ZSTD)
+        };
+
+        let mut tunnel = 0usize;
 
         while let Some(Ok(stream)) = incoming.next().await {
+            let context = context.clone();
+            let metrics = self.metrics.clone();
+            let codec = TcpWarpProto::with_capabilities(capabilities)
+                .with_max_frame_size(self.max_frame_size)
+                .with_metrics(metrics.clone());
+            tunnel = tunnel.wrapping_add(1);
             spawn(async move {
-                if let Err(e) = process(stream, connect_address).await {
+                if let Err(e) = process(tunnel, stream, codec, context).await {
                     println!("failed to process connection; error = {}", e);
                 }
+                metrics.log_compression();
             });
         }
         Ok(())
     }
 }
 
-async fn process(stream: TcpStream, connect_address: IpAddr) -> Result<(), Box<dyn Error>> {
-    let mut transport = Framed::new(stream, TcpWarpProto);
+/// Settings, metrics and event publisher shared by all tunnels of server.
+#[derive(Clone)]
+struct ServerContext {
+    connect_address: IpAddr,
+    udp_idle_timeout: Duration,
+    connect_timeout: Duration,
+    idle_timeout: Option<Duration>,
+    max_lifetime: Option<Duration>,
+    unix_socket_paths: Arc<Vec<PathBuf>>,
+    services: Arc<Vec<TcpWarpService>>,
+    limits: Arc<ServerLimits>,
+    tunnel_rate: Option<u64>,
+    client_rates: Option<Arc<ClientRates>>,
+    audit: Option<Arc<AuditWriter>>,
+    metrics: Arc<TcpWarpMetrics>,
+    events: TcpWarpEvents,
+}
+
+impl ServerContext {
+    fn audit(&self, record: &AuditRecord) {
+        if let Some(audit) = &self.audit {
+            audit.write(record);
+        }
+    }
+
+    /// Whether unix socket is one of allowed paths or inside of one of them.
+    fn allows_unix_socket(&self, path: &str) -> bool {
+        let path = std::path::Path::new(path);
+        path.components()
+            .all(|component| component != std::path::Component::ParentDir)
+            && self
+                .unix_socket_paths
+                .iter()
+                .any(|allowed| path.starts_with(allowed))
+    }
+}
+
+async fn process(
+    tunnel: usize,
+    stream: TcpStream,
+    codec: TcpWarpProto,
+    context: ServerContext,
+) -> Result<(), TcpWarpError> {
+    let peer = stream.peer_addr()?;
+    let capabilities = codec.capabilities();
+    let mut transport = Framed::new(stream, codec);
+
+    transport
+        .send(TcpWarpMessage::AddPorts(Some(TcpWarpHandshake {
+            capabilities,
+            services: context.services.to_vec(),
+        })))
+        .await?;
 
-    transport.send(TcpWarpMessage::AddPorts(vec![])).await?;
+    let events = context.events.clone();
+    events.emit(TcpWarpEvent::TunnelConnected { tunnel, peer });
 
     let (mut wtransport, mut rtransport) = transport.split();
 
     let (sender, mut receiver) = channel(100);
 
     let mut connections = HashMap::new();
+    let mut priorities = HashMap::new();
+
+    let mut rates = RateLimits::default();
+    if let Some(tunnel_rate) = context.tunnel_rate {
+        rates.push(Arc::new(RateLimit::new(tunnel_rate)));
+    }
+    if let Some(client_rates) = &context.client_rates {
+        rates.push(client_rates.get(peer.ip()));
+    }
 
     let forward_task = async move {
         debug!("in receiver task process");
-        while let Some(message) = receiver.next().await {
+        let mut scheduler = FrameScheduler::default();
+        while let Some(next) = scheduler.next(&mut receiver).await {
+            let message = match next {
+                Scheduled::Received(message) => message,
+                Scheduled::Write(message) => {
+                    debug!("sending message {:?} from server to tunnel client", message);
+                    wtransport.send(message).await?;
+                    continue;
+                }
+            };
             debug!("received in fw message: {:?}", message);
             let message = match message {
                 TcpWarpMessage::ConnectForward {
                     connection_id,
+                    priority,
                     sender,
                     connected_sender,
                 } => {
@@ -54,7 +397,8 @@ async fn process(stream: TcpStream, connect_address: IpAddr) -> Result<(), Box<d
                     if let Err(err) = connected_sender.send(Ok(())) {
                         error!("connected sender errored: {:?}", err);
                     }
-                    connections.insert(connection_id.clone(), sender.clone());
+                    connections.insert(connection_id, sender.clone());
+                    priorities.insert(connection_id, priority);
                     TcpWarpMessage::Connected { connection_id }
                 }
                 TcpWarpMessage::DisconnectClient { ref connection_id } => {
@@ -91,8 +435,15 @@ async fn process(stream: TcpStream, connect_address: IpAddr) -> Result<(), Box<d
                 }
                 regular_message => regular_message,
             };
-            debug!("sending message {:?} from server to tunnel client", message);
-            wtransport.send(message).await?
+            let priority = match &message {
+                TcpWarpMessage::DisconnectHost { connection_id } => {
+                    priorities.remove(connection_id)
+                }
+                message => message
+                    .connection_id()
+                    .and_then(|connection_id| priorities.get(&connection_id).copied()),
+            };
+            scheduler.push(message, priority.unwrap_or_default());
         }
 
         debug!("no more messages, closing forward to tunnel client task");
@@ -103,10 +454,36 @@ async fn process(stream: TcpStream, connect_address: IpAddr) -> Result<(), Box<d
     };
 
     let processing_task = async move {
-        while let Some(Ok(message)) = rtransport.next().await {
+        let (closed_sender, mut closed_receiver) = channel(100);
+        let mut associations = DatagramAssociations {
+            senders: HashMap::new(),
+            closed_sender,
+        };
+
+        loop {
+            let message = select! {
+                message = rtransport.next() => match message {
+                    Some(message) => message?,
+                    None => break,
+                },
+                connection_id = closed_receiver.next() => {
+                    if let Some(connection_id) = connection_id {
+                        associations.senders.remove(&connection_id);
+                    }
+                    continue;
+                }
+            };
             debug!("server received from tunnel client {:?}", message);
-            if let Err(err) =
-                process_client_to_host_message(message, sender.clone(), connect_address).await
+            if let Err(err) = process_client_to_host_message(
+                message,
+                sender.clone(),
+                &mut associations,
+                &context,
+                &rates,
+                tunnel,
+                peer,
+            )
+            .await
             {
                 error!("error in processing: {}", err);
             }
@@ -117,35 +494,72 @@ async fn process(stream: TcpStream, connect_address: IpAddr) -> Result<(), Box<d
         Ok::<(), io::Error>(())
     };
 
-    let (_, _) = try_join!(forward_task, processing_task)?;
+    let result = try_join!(forward_task, processing_task);
+
+    events.emit(TcpWarpEvent::TunnelDisconnected { tunnel, peer });
+    result?;
 
     debug!("finished process of tunnel connection");
 
     Ok(())
 }
 
+/// Datagram associations of one tunnel keyed by connection id.
+struct DatagramAssociations {
+    senders: HashMap<Uuid, Sender<TcpWarpMessage>>,
+    /// Association task sends its id once it is over, so its entry is removed.
+    ///
+    /// Datagram of client may be in flight at the same time and reopen the association,
+    /// the reopened one is removed as well and client uses new id for its next datagram.
+    closed_sender: Sender<Uuid>,
+}
+
 async fn process_client_to_host_message(
     message: TcpWarpMessage,
     mut client_sender: Sender<TcpWarpMessage>,
-    connect_address: IpAddr,
+    associations: &mut DatagramAssociations,
+    context: &ServerContext,
+    rates: &RateLimits,
+    tunnel: usize,
+    client: SocketAddr,
 ) -> Result<(), io::Error> {
+    let connect_address = context.connect_address;
     match message {
         TcpWarpMessage::HostConnect {
             connection_id,
             host,
             port,
+            priority,
+            ..
         } => {
+            let connect_address = connect_address.to_string();
+            let socket_address = (host.unwrap_or_else(|| connect_address.to_string()), port);
+            let record = AuditRecord::new(
+                connection_id,
+                tunnel,
+                client,
+                format!("{}:{}", socket_address.0, socket_address.1),
+            );
+            let permit = match context.limits.acquire(tunnel, client.ip()) {
+                Some(permit) => permit,
+                None => return reject_over_limit(client_sender, record, context).await,
+            };
             let client_sender_ = client_sender.clone();
+            let context = context.clone();
+            let rates = rates.clone();
             spawn(async move {
-                let connect_address = connect_address.to_string();
-                let socket_address = format!(
-                    "{}:{}",
-                    host.unwrap_or_else(|| connect_address.to_string()),
-                    port
-                );
-                debug!("host connection to {}", socket_address);
-                if let Err(err) =
-                    process_host_connection(client_sender_, connection_id, socket_address).await
+                let _permit = permit;
+                debug!("host connection to {:?}", socket_address);
+                if let Err(err) = process_host_connection(
+                    client_sender_,
+                    connection_id,
+                    priority,
+                    socket_address,
+                    context,
+                    rates,
+                    record,
+                )
+                .await
                 {
                     error!(
                         "failed connection {} {}: {}",
@@ -154,6 +568,41 @@ async fn process_client_to_host_message(
                 }
             });
         }
+        TcpWarpMessage::HostConnectPath {
+            connection_id,
+            path,
+            priority,
+            ..
+        } => {
+            let record = AuditRecord::new(connection_id, tunnel, client, path.clone());
+            let permit = match context.limits.acquire(tunnel, client.ip()) {
+                Some(permit) => permit,
+                None => return reject_over_limit(client_sender, record, context).await,
+            };
+            let client_sender_ = client_sender.clone();
+            let context = context.clone();
+            let rates = rates.clone();
+            spawn(async move {
+                let _permit = permit;
+                debug!("host connection to unix socket {}", path);
+                if let Err(err) = process_host_path_connection(
+                    client_sender_,
+                    connection_id,
+                    priority,
+                    path.clone(),
+                    context,
+                    rates,
+                    record,
+                )
+                .await
+                {
+                    error!("failed connection {} {}: {}", path, connection_id, err);
+                }
+            });
+        }
+        TcpWarpMessage::Hello { capabilities } => {
+            debug!("client hello, capabilities: {}", capabilities);
+        }
         TcpWarpMessage::DisconnectClient { .. } => {
             if let Err(err) = client_sender.send(message).await {
                 error!(
@@ -162,6 +611,44 @@ async fn process_client_to_host_message(
                 );
             }
         }
+        TcpWarpMessage::DatagramClient {
+            connection_id,
+            host,
+            port,
+            data,
+        } => {
+            let mut message = TcpWarpMessage::BytesServer { data };
+            if let Some(association_sender) = associations.senders.get_mut(&connection_id) {
+                match association_sender.send(message).await {
+                    Ok(()) => return Ok(()),
+                    Err(SendError(returned)) => {
+                        debug!("{} datagram association expired", connection_id);
+                        associations.senders.remove(&connection_id);
+                        message = returned;
+                    }
+                }
+            }
+            let (mut association_sender, association_receiver) = channel(100);
+            let socket_address = (host.unwrap_or_else(|| connect_address.to_string()), port);
+            debug!(
+                "{} datagram association to {:?}",
+                connection_id, socket_address
+            );
+            spawn(process_datagram_association(
+                client_sender,
+                connection_id,
+                socket_address,
+                association_receiver,
+                associations.closed_sender.clone(),
+                context.udp_idle_timeout,
+            ));
+            if let Err(err) = association_sender.send(message).await {
+                error!("cannot send to channel: {}", err);
+            }
+            associations
+                .senders
+                .insert(connection_id, association_sender);
+        }
         TcpWarpMessage::BytesClient { .. } => {
             if let Err(err) = client_sender.send(message).await {
                 error!(
@@ -175,22 +662,178 @@ async fn process_client_to_host_message(
     Ok(())
 }
 
-async fn process_host_connection<S: ToSocketAddrs>(
+/// Fails connect over connection limit without contacting target.
+async fn reject_over_limit(
+    mut client_sender: Sender<TcpWarpMessage>,
+    mut record: AuditRecord,
+    context: &ServerContext,
+) -> Result<(), io::Error> {
+    let connection_id = record.connection_id;
+    let reason = TcpWarpConnectFailure::LimitExceeded;
+    warn!("{} {}", connection_id, reason);
+    record.failure = Some(reason);
+    context.audit(&record);
+    context.events.emit(TcpWarpEvent::ConnectionFailed {
+        connection_id,
+        error: reason.to_string(),
+    });
+    client_sender
+        .send(TcpWarpMessage::ConnectFailure {
+            connection_id,
+            reason,
+        })
+        .await
+        .map_err(io::Error::other)
+}
+
+async fn process_host_connection(
     mut client_sender: Sender<TcpWarpMessage>,
     connection_id: Uuid,
-    socket_address: S,
-) -> Result<(), Box<dyn Error>> {
+    priority: TcpWarpPriority,
+    socket_address: (String, u16),
+    context: ServerContext,
+    rates: RateLimits,
+    mut record: AuditRecord,
+) -> Result<(), TcpWarpError> {
+    let events = &context.events;
     debug!("{} new connection", connection_id);
 
-    let stream = match TcpStream::connect(socket_address).await {
+    events.emit(TcpWarpEvent::ConnectionOpened {
+        connection_id,
+        target: format!("{}:{}", socket_address.0, socket_address.1),
+    });
+
+    let connected = timeout(context.connect_timeout, TcpStream::connect(socket_address))
+        .await
+        .unwrap_or_else(|_| Err(connect_timed_out()));
+
+    let stream = match connected {
         Ok(stream) => stream,
         Err(err) => {
+            events.emit(TcpWarpEvent::ConnectionFailed {
+                connection_id,
+                error: err.to_string(),
+            });
+            let reason = TcpWarpConnectFailure::from_error(&err);
+            record.failure = Some(reason);
+            context.audit(&record);
             client_sender
-                .send(TcpWarpMessage::ConnectFailure { connection_id })
+                .send(TcpWarpMessage::ConnectFailure {
+                    connection_id,
+                    reason,
+                })
                 .await?;
             return Err(err.into());
         }
     };
+    record.resolved = stream.peer_addr().ok().map(|address| address.ip());
+
+    process_host_stream(
+        client_sender,
+        connection_id,
+        priority,
+        stream,
+        &context,
+        &rates,
+        record,
+    )
+    .await
+}
+
+async fn process_host_path_connection(
+    mut client_sender: Sender<TcpWarpMessage>,
+    connection_id: Uuid,
+    priority: TcpWarpPriority,
+    path: String,
+    context: ServerContext,
+    rates: RateLimits,
+    mut record: AuditRecord,
+) -> Result<(), TcpWarpError> {
+    let events = &context.events;
+    debug!("{} new unix socket connection", connection_id);
+
+    events.emit(TcpWarpEvent::ConnectionOpened {
+        connection_id,
+        target: path.clone(),
+    });
+
+    let connected = if context.allows_unix_socket(&path) {
+        connect_unix_socket(&path, context.connect_timeout).await
+    } else {
+        warn!("{} unix socket {} is not allowed", connection_id, path);
+        Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "unix socket is not allowed",
+        ))
+    };
+
+    let stream = match connected {
+        Ok(stream) => stream,
+        Err(err) => {
+            events.emit(TcpWarpEvent::ConnectionFailed {
+                connection_id,
+                error: err.to_string(),
+            });
+            let reason = TcpWarpConnectFailure::from_error(&err);
+            record.failure = Some(reason);
+            context.audit(&record);
+            client_sender
+                .send(TcpWarpMessage::ConnectFailure {
+                    connection_id,
+                    reason,
+                })
+                .await?;
+            return Err(err.into());
+        }
+    };
+
+    process_host_stream(
+        client_sender,
+        connection_id,
+        priority,
+        stream,
+        &context,
+        &rates,
+        record,
+    )
+    .await
+}
+
+#[cfg(unix)]
+async fn connect_unix_socket(path: &str, connect_timeout: Duration) -> io::Result<UnixStream> {
+    timeout(connect_timeout, UnixStream::connect(path))
+        .await
+        .unwrap_or_else(|_| Err(connect_timed_out()))
+}
+
+#[cfg(not(unix))]
+async fn connect_unix_socket(_path: &str, _connect_timeout: Duration) -> io::Result<TcpStream> {
+    Err(io::Error::other("unix domain sockets are not supported"))
+}
+
+fn connect_timed_out() -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, "connect timed out")
+}
+
+async fn process_host_stream<S>(
+    mut client_sender: Sender<TcpWarpMessage>,
+    connection_id: Uuid,
+    priority: TcpWarpPriority,
+    stream: S,
+    context: &ServerContext,
+    rates: &RateLimits,
+    mut record: AuditRecord,
+) -> Result<(), TcpWarpError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let events = &context.events;
+    events.emit(TcpWarpEvent::ConnectionConnected { connection_id });
+    let bytes_sent = AtomicU64::new(0);
+    let bytes_received = AtomicU64::new(0);
+    let (bytes_sent, bytes_received) = (&bytes_sent, &bytes_received);
+    let expiry = ConnectionExpiry::new(context.idle_timeout, context.max_lifetime);
+    let expiry = &expiry;
 
     let (mut wtransport, mut rtransport) =
         Framed::new(stream, TcpWarpProtoHost { connection_id }).split();
@@ -204,7 +847,12 @@ async fn process_host_connection<S: ToSocketAddrs>(
             debug!("{} just received a message: {:?}", connection_id, message);
             match message {
                 TcpWarpMessage::DisconnectClient { .. } => break,
-                TcpWarpMessage::BytesServer { data } => wtransport.send(data).await?,
+                TcpWarpMessage::BytesServer { data } => {
+                    bytes_received.fetch_add(data.len() as u64, Ordering::Relaxed);
+                    expiry.touch();
+                    rates.upload(data.len()).await;
+                    wtransport.send(data).await?
+                }
                 _ => (),
             }
         }
@@ -225,6 +873,7 @@ async fn process_host_connection<S: ToSocketAddrs>(
     client_sender
         .send(TcpWarpMessage::ConnectForward {
             connection_id,
+            priority,
             sender: host_sender,
             connected_sender,
         })
@@ -239,6 +888,11 @@ async fn process_host_connection<S: ToSocketAddrs>(
             error!("{} connection error: {}", connection_id, err);
         }
         while let Some(Ok(message)) = rtransport.next().await {
+            if let TcpWarpMessage::BytesHost { data, .. } = &message {
+                bytes_sent.fetch_add(data.len() as u64, Ordering::Relaxed);
+                expiry.touch();
+                rates.download(data.len()).await;
+            }
             if let Err(err) = client_sender_.send(message).await {
                 error!("{} {}", connection_id, err);
             }
@@ -257,9 +911,137 @@ async fn process_host_connection<S: ToSocketAddrs>(
         Ok::<(), io::Error>(())
     };
 
-    try_join!(forward_task, processing_task)?;
+    let result = select! {
+        result = async { try_join!(forward_task, processing_task) } => result.map(|_| ()),
+        reason = expiry.expired() => {
+            info!("{} closing host connection after {}", connection_id, reason);
+            context.metrics.add_expired(reason);
+            client_sender
+                .send(TcpWarpMessage::DisconnectHost { connection_id })
+                .await
+                .map_err(io::Error::other)
+        }
+    };
+
+    events.emit(TcpWarpEvent::ConnectionClosed {
+        connection_id,
+        bytes_sent: bytes_sent.load(Ordering::Relaxed),
+        bytes_received: bytes_received.load(Ordering::Relaxed),
+    });
+    record.bytes_client_to_host = bytes_received.load(Ordering::Relaxed);
+    record.bytes_host_to_client = bytes_sent.load(Ordering::Relaxed);
+    context.audit(&record);
+    result?;
 
     debug!("{} disconnect, processing task done", connection_id);
 
     Ok(())
 }
+
+async fn process_datagram_association(
+    mut client_sender: Sender<TcpWarpMessage>,
+    connection_id: Uuid,
+    socket_address: (String, u16),
+    host_receiver: Receiver<TcpWarpMessage>,
+    mut closed_sender: Sender<Uuid>,
+    idle_timeout: Duration,
+) {
+    if let Err(err) = forward_datagrams(
+        client_sender.clone(),
+        connection_id,
+        socket_address,
+        host_receiver,
+        idle_timeout,
+    )
+    .await
+    {
+        error!("{} datagram association failed: {}", connection_id, err);
+    }
+
+    debug!("{} closing datagram association", connection_id);
+
+    if let Err(err) = closed_sender.send(connection_id).await {
+        debug!("{} tunnel is closed: {}", connection_id, err);
+    }
+
+    if let Err(err) = client_sender
+        .send(TcpWarpMessage::DisconnectHost { connection_id })
+        .await
+    {
+        error!("{} err: {}", connection_id, err);
+    }
+}
+
+async fn forward_datagrams(
+    mut client_sender: Sender<TcpWarpMessage>,
+    connection_id: Uuid,
+    socket_address: (String, u16),
+    mut host_receiver: Receiver<TcpWarpMessage>,
+    idle_timeout: Duration,
+) -> Result<(), TcpWarpError> {
+    let target = lookup_host(socket_address)
+        .await?
+        .next()
+        .ok_or_else(|| io::Error::other("cannot resolve datagram target"))?;
+    let bind_address: SocketAddr = if target.is_ipv4() {
+        (Ipv4Addr::UNSPECIFIED, 0).into()
+    } else {
+        (Ipv6Addr::UNSPECIFIED, 0).into()
+    };
+    let mut socket = UdpSocket::bind(bind_address).await?;
+    socket.connect(target).await?;
+
+    let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+
+    loop {
+        select! {
+            message = host_receiver.next() => match message {
+                Some(TcpWarpMessage::BytesServer { data }) => {
+                    if let Err(err) = socket.send(&data).await {
+                        debug!("{} cannot send datagram: {}", connection_id, err);
+                    }
+                }
+                Some(_) => (),
+                None => break,
+            },
+            received = socket.recv(&mut buf) => match received {
+                Ok(len) => {
+                    client_sender
+                        .send(TcpWarpMessage::DatagramHost {
+                            connection_id,
+                            data: BytesMut::from(&buf[..len]),
+                        })
+                        .await?;
+                }
+                Err(err) => debug!("{} cannot receive datagram: {}", connection_id, err),
+            },
+            _ = delay_for(idle_timeout) => {
+                debug!("{} datagram association idle", connection_id);
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builder_defaults() {
+        assert_eq!(
+            "0.0.0.0:18000".parse::<SocketAddr>().unwrap(),
+            TcpWarpServerBuilder::DEFAULT_LISTEN_ADDRESS
+        );
+        assert_eq!(
+            "127.0.0.1".parse::<IpAddr>().unwrap(),
+            TcpWarpServerBuilder::DEFAULT_CONNECT_ADDRESS
+        );
+        assert_eq!(
+            Duration::from_secs(60),
+            TcpWarpServerBuilder::DEFAULT_UDP_IDLE_TIMEOUT
+        );
+    }
+}
@@ -0,0 +1,154 @@
+use super::*;
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::sync::mpsc::error::TrySendError;
+
+/// Connection opened through tunnel with [`TcpWarpClient::open_stream`].
+///
+/// Shutdown of write half is propagated to target, read half ends once target closes.
+/// Dropping stream closes connection.
+pub struct TcpWarpStream {
+    connection_id: Uuid,
+    host_sender: Sender<TcpWarpMessage>,
+    client_receiver: Receiver<TcpWarpMessage>,
+    buffer: BytesMut,
+    read_closed: bool,
+    write_closed: bool,
+    bytes_sent: u64,
+    bytes_received: u64,
+    events: TcpWarpEvents,
+}
+
+impl TcpWarpStream {
+    pub(crate) fn new(
+        connection_id: Uuid,
+        host_sender: Sender<TcpWarpMessage>,
+        client_receiver: Receiver<TcpWarpMessage>,
+        events: TcpWarpEvents,
+    ) -> Self {
+        Self {
+            connection_id,
+            host_sender,
+            client_receiver,
+            buffer: BytesMut::new(),
+            read_closed: false,
+            write_closed: false,
+            bytes_sent: 0,
+            bytes_received: 0,
+            events,
+        }
+    }
+
+    /// Identifier of connection, the same as in events of client and server.
+    pub fn connection_id(&self) -> Uuid {
+        self.connection_id
+    }
+}
+
+fn closed() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "tunnel session is closed")
+}
+
+impl AsyncRead for TcpWarpStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        while self.buffer.is_empty() && !self.read_closed {
+            match self.client_receiver.poll_recv(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Some(TcpWarpMessage::BytesServer { data })) => {
+                    self.bytes_received += data.len() as u64;
+                    self.buffer = data;
+                }
+                Poll::Ready(Some(TcpWarpMessage::DisconnectHost { .. }))
+                | Poll::Ready(Some(TcpWarpMessage::ConnectFailure { .. }))
+                | Poll::Ready(None) => self.read_closed = true,
+                Poll::Ready(Some(_)) => (),
+            }
+        }
+        let len = buf.len().min(self.buffer.len());
+        buf[..len].copy_from_slice(&self.buffer.split_to(len));
+        Poll::Ready(Ok(len))
+    }
+}
+
+impl AsyncWrite for TcpWarpStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.write_closed {
+            return Poll::Ready(Err(closed()));
+        }
+        match self.host_sender.poll_ready(cx) {
+            Poll::Pending => return Poll::Pending,
+            Poll::Ready(Err(_)) => return Poll::Ready(Err(closed())),
+            Poll::Ready(Ok(())) => (),
+        }
+        let message = TcpWarpMessage::BytesClient {
+            connection_id: self.connection_id,
+            data: BytesMut::from(buf),
+        };
+        if self.host_sender.try_send(message).is_err() {
+            return Poll::Ready(Err(closed()));
+        }
+        self.bytes_sent += buf.len() as u64;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.write_closed {
+            return Poll::Ready(Ok(()));
+        }
+        match self.host_sender.poll_ready(cx) {
+            Poll::Pending => return Poll::Pending,
+            Poll::Ready(Err(_)) => return Poll::Ready(Err(closed())),
+            Poll::Ready(Ok(())) => (),
+        }
+        let message = TcpWarpMessage::DisconnectClient {
+            connection_id: self.connection_id,
+        };
+        self.write_closed = true;
+        if self.host_sender.try_send(message).is_err() {
+            return Poll::Ready(Err(closed()));
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for TcpWarpStream {
+    fn drop(&mut self) {
+        if !self.write_closed {
+            let message = TcpWarpMessage::DisconnectClient {
+                connection_id: self.connection_id,
+            };
+            match self.host_sender.try_send(message) {
+                Err(TrySendError::Full(message)) => {
+                    let mut host_sender = self.host_sender.clone();
+                    spawn(async move {
+                        if let Err(err) = host_sender.send(message).await {
+                            debug!("cannot send disconnect of stream: {}", err);
+                        }
+                    });
+                }
+                Err(err) => debug!("cannot send disconnect of stream: {}", err),
+                Ok(()) => (),
+            }
+        }
+        self.events.emit(TcpWarpEvent::ConnectionClosed {
+            connection_id: self.connection_id,
+            bytes_sent: self.bytes_sent,
+            bytes_received: self.bytes_received,
+        });
+    }
+}
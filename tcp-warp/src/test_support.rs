@@ -0,0 +1,397 @@
+//! Helpers for in-process end-to-end tests of tunnel, enabled with `test-support` feature.
+//!
+//! Everything listens on ephemeral ports of localhost, so tests can run in parallel.
+
+use super::*;
+use futures::future::{abortable, AbortHandle};
+use std::io;
+use tokio::time::timeout;
+
+/// Time limit of waiting operations of helpers.
+pub const TIMEOUT: Duration = Duration::from_secs(10);
+
+fn timed_out<E>(_: E) -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, "test operation timed out")
+}
+
+/// Starts TCP echo server.
+pub async fn echo_server() -> io::Result<SocketAddr> {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await?;
+    let address = listener.local_addr()?;
+    spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            spawn(async move {
+                let (mut reader, mut writer) = stream.split();
+                if let Err(err) = tokio::io::copy(&mut reader, &mut writer).await {
+                    debug!("echo connection error: {}", err);
+                }
+            });
+        }
+    });
+    Ok(address)
+}
+
+/// Starts echo server on unix socket.
+#[cfg(unix)]
+pub fn unix_echo_server(path: &std::path::Path) -> io::Result<()> {
+    let mut listener = UnixListener::bind(path)?;
+    spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            spawn(async move {
+                let (mut reader, mut writer) = tokio::io::split(stream);
+                if let Err(err) = tokio::io::copy(&mut reader, &mut writer).await {
+                    debug!("echo connection error: {}", err);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+/// Starts UDP echo server.
+pub async fn udp_echo_server() -> io::Result<SocketAddr> {
+    let mut socket = UdpSocket::bind("127.0.0.1:0").await?;
+    let address = socket.local_addr()?;
+    spawn(async move {
+        let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+        while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+            if let Err(err) = socket.send_to(&buf[..len], &peer).await {
+                debug!("udp echo error: {}", err);
+            }
+        }
+    });
+    Ok(address)
+}
+
+/// Starts TCP server closing every accepted connection right away.
+pub async fn closing_server() -> io::Result<SocketAddr> {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await?;
+    let address = listener.local_addr()?;
+    spawn(async move { while let Ok((_stream, _)) = listener.accept().await {} });
+    Ok(address)
+}
+
+/// Address without listener, connections to it are refused.
+pub async fn unused_address() -> io::Result<SocketAddr> {
+    TcpListener::bind("127.0.0.1:0").await?.local_addr()
+}
+
+/// Starts server on ephemeral port, handle stops accepting new tunnels.
+pub async fn start_server(server: TcpWarpServer) -> io::Result<(SocketAddr, AbortHandle)> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let address = listener.local_addr()?;
+    let (task, handle) = abortable(async move {
+        if let Err(err) = server.listen_on(listener).await {
+            error!("test server failed: {}", err);
+        }
+    });
+    spawn(task);
+    Ok((address, handle))
+}
+
+/// Waits until client binds listener of mapping.
+pub async fn mapped_address(
+    client: &TcpWarpClient,
+    mapping: &TcpWarpPortConnection,
+) -> io::Result<SocketAddr> {
+    let mut addresses = client.addresses();
+    timeout(TIMEOUT, async {
+        loop {
+            if let Some(address) = addresses.borrow().get(mapping) {
+                return Ok(*address);
+            }
+            if addresses.recv().await.is_none() {
+                return Err(io::Error::other("client is dropped"));
+            }
+        }
+    })
+    .await
+    .map_err(timed_out)?
+}
+
+/// Sends data over new connection and reads the same amount of data back.
+pub async fn echo(address: SocketAddr, data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut stream = TcpStream::connect(address).await?;
+    let (mut reader, mut writer) = stream.split();
+    let mut received = vec![0; data.len()];
+    let (written, read) = timeout(
+        TIMEOUT,
+        future::join(writer.write_all(data), reader.read_exact(&mut received)),
+    )
+    .await
+    .map_err(timed_out)?;
+    written?;
+    read?;
+    Ok(received)
+}
+
+/// Reads until connection is closed by peer, returns data read before close.
+pub async fn read_to_close(stream: &mut TcpStream) -> io::Result<Vec<u8>> {
+    let mut received = vec![];
+    match timeout(TIMEOUT, stream.read_to_end(&mut received))
+        .await
+        .map_err(timed_out)?
+    {
+        Ok(_) => Ok(received),
+        Err(err) if err.kind() == io::ErrorKind::ConnectionReset => Ok(received),
+        Err(err) => Err(err),
+    }
+}
+
+/// Deterministic payload of given length.
+pub fn payload(len: usize) -> Vec<u8> {
+    (0..len).map(|x| (x * 31 % 251) as u8).collect()
+}
+
+/// Server and client session running in background.
+pub struct TestTunnel {
+    server: SocketAddr,
+    client: Arc<TcpWarpClient>,
+    tasks: Vec<AbortHandle>,
+}
+
+impl TestTunnel {
+    /// Starts default server and client session with given mappings.
+    pub async fn start(mappings: Vec<TcpWarpPortConnection>) -> io::Result<Self> {
+        Self::start_with(
+            TcpWarpServer::new("127.0.0.1:0".parse().unwrap(), Ipv4Addr::LOCALHOST.into()),
+            |server| TcpWarpClient::new(Ipv4Addr::LOCALHOST.into(), server),
+            mappings,
+        )
+        .await
+    }
+
+    /// Starts server and client created for server address.
+    pub async fn start_with<F>(
+        server: TcpWarpServer,
+        client: F,
+        mappings: Vec<TcpWarpPortConnection>,
+    ) -> io::Result<Self>
+    where
+        F: FnOnce(SocketAddr) -> TcpWarpClient,
+    {
+        let (server, server_task) = start_server(server).await?;
+        let client = Arc::new(client(server));
+        let session_client = client.clone();
+        let (session, session_task) = abortable(async move {
+            if let Err(err) = session_client.connect(mappings).await {
+                error!("test client failed: {}", err);
+            }
+        });
+        spawn(session);
+        Ok(Self {
+            server,
+            client,
+            tasks: vec![server_task, session_task],
+        })
+    }
+
+    /// Address of tunnel server.
+    pub fn server(&self) -> SocketAddr {
+        self.server
+    }
+
+    pub fn client(&self) -> &TcpWarpClient {
+        &self.client
+    }
+
+    /// Waits until client binds listener of mapping.
+    pub async fn mapped_address(&self, mapping: &TcpWarpPortConnection) -> io::Result<SocketAddr> {
+        mapped_address(&self.client, mapping).await
+    }
+}
+
+impl Drop for TestTunnel {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+/// Impairments applied by [`TestRelay`] to every relayed direction.
+///
+/// Data is never reordered, impairments only delay and fragment it.
+#[derive(Debug, Clone, Default)]
+pub struct TestImpairment {
+    /// Delay of data from read on one side to write on the other side.
+    pub latency: Duration,
+    /// Limit of bytes per second.
+    pub bandwidth: Option<u64>,
+    /// Largest single write, data is written in parts to fragment frames.
+    pub max_write: Option<usize>,
+}
+
+/// TCP relay simulating bad network between tunnel client and server.
+///
+/// Relay applies [`TestImpairment`], can stall traffic without dropping it and can reset
+/// relayed connections abruptly.
+pub struct TestRelay {
+    address: SocketAddr,
+    impairment: Arc<Mutex<TestImpairment>>,
+    stalled: watch::Sender<bool>,
+    resets: Arc<Mutex<Vec<oneshot::Sender<()>>>>,
+    accept_task: AbortHandle,
+}
+
+impl TestRelay {
+    /// Starts relay of connections to target address without impairments.
+    pub async fn start(target: SocketAddr) -> io::Result<Self> {
+        Self::start_with(target, TestImpairment::default()).await
+    }
+
+    /// Starts relay of connections to target address with given impairments.
+    pub async fn start_with(target: SocketAddr, impairment: TestImpairment) -> io::Result<Self> {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = listener.local_addr()?;
+        let impairment = Arc::new(Mutex::new(impairment));
+        let (stalled, stalled_receiver) = watch::channel(false);
+        let resets = Arc::new(Mutex::new(vec![]));
+        let relay_impairment = impairment.clone();
+        let relay_resets = resets.clone();
+        let (accept, accept_task) = abortable(async move {
+            while let Ok((inbound, _)) = listener.accept().await {
+                let (reset, reset_receiver) = oneshot::channel();
+                relay_resets.lock().unwrap().push(reset);
+                spawn(relay(
+                    inbound,
+                    target,
+                    relay_impairment.clone(),
+                    stalled_receiver.clone(),
+                    reset_receiver,
+                ));
+            }
+        });
+        spawn(accept);
+        Ok(Self {
+            address,
+            impairment,
+            stalled,
+            resets,
+            accept_task,
+        })
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Changes impairments of relayed connections, applied to data read afterwards.
+    pub fn set_impairment(&self, impairment: TestImpairment) {
+        *self.impairment.lock().unwrap() = impairment;
+    }
+
+    /// Stops forwarding of data, data is kept until [`TestRelay::resume`].
+    pub fn stall(&self) {
+        let _ = self.stalled.broadcast(true);
+    }
+
+    /// Forwards data held by [`TestRelay::stall`].
+    pub fn resume(&self) {
+        let _ = self.stalled.broadcast(false);
+    }
+
+    /// Resets all relayed connections with RST, new connections are still accepted.
+    pub fn reset(&self) {
+        for reset in self.resets.lock().unwrap().drain(..) {
+            let _ = reset.send(());
+        }
+    }
+}
+
+impl Drop for TestRelay {
+    fn drop(&mut self) {
+        self.accept_task.abort();
+        self.reset();
+    }
+}
+
+async fn relay(
+    mut inbound: TcpStream,
+    target: SocketAddr,
+    impairment: Arc<Mutex<TestImpairment>>,
+    stalled: watch::Receiver<bool>,
+    reset: oneshot::Receiver<()>,
+) {
+    let mut outbound = match TcpStream::connect(target).await {
+        Ok(outbound) => outbound,
+        Err(err) => {
+            debug!("relay cannot connect {}: {}", target, err);
+            return;
+        }
+    };
+    let (inbound_reader, inbound_writer) = inbound.split();
+    let (outbound_reader, outbound_writer) = outbound.split();
+    let forward = future::join(
+        forward(
+            inbound_reader,
+            outbound_writer,
+            &impairment,
+            stalled.clone(),
+        ),
+        forward(outbound_reader, inbound_writer, &impairment, stalled),
+    );
+    let reset = select! {
+        _ = forward => false,
+        _ = reset => true,
+    };
+    if reset {
+        for stream in &[&inbound, &outbound] {
+            if let Err(err) = stream.set_linger(Some(Duration::from_secs(0))) {
+                debug!("relay cannot reset connection: {}", err);
+            }
+        }
+    }
+}
+
+/// Forwards one direction until EOF, which is forwarded as write shutdown.
+async fn forward<R, W>(
+    mut reader: R,
+    mut writer: W,
+    impairment: &Mutex<TestImpairment>,
+    mut stalled: watch::Receiver<bool>,
+) where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+
+    let read = async move {
+        let mut buffer = vec![0; 64 * 1024];
+        loop {
+            match reader.read(&mut buffer).await {
+                Ok(0) | Err(_) => break,
+                Ok(len) => {
+                    let read_at = tokio::time::Instant::now();
+                    if sender.send((read_at, buffer[..len].to_vec())).is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    };
+
+    let write = async move {
+        while let Some((read_at, data)) = receiver.recv().await {
+            let impairment = impairment.lock().unwrap().clone();
+            tokio::time::delay_until(read_at + impairment.latency).await;
+            for part in data.chunks(impairment.max_write.unwrap_or(data.len()).max(1)) {
+                while *stalled.borrow() {
+                    if stalled.recv().await.is_none() {
+                        return;
+                    }
+                }
+                if writer.write_all(part).await.is_err() {
+                    return;
+                }
+                if let Some(bandwidth) = impairment.bandwidth {
+                    let micros = part.len() as u64 * 1_000_000 / bandwidth.max(1);
+                    delay_for(Duration::from_micros(micros)).await;
+                }
+            }
+        }
+        let _ = writer.shutdown().await;
+    };
+
+    future::join(read, write).await;
+}
@@ -0,0 +1,984 @@
+use bytes::BytesMut;
+use std::{net::Ipv4Addr, sync::Arc, time::Duration};
+use tcpwarp::{
+    test_support::*, TcpWarpAuditLog, TcpWarpClient, TcpWarpConnectFailure, TcpWarpError,
+    TcpWarpEvent, TcpWarpHandshake, TcpWarpMappingState, TcpWarpMessage, TcpWarpMetrics,
+    TcpWarpMetricsSnapshot, TcpWarpPortConnection, TcpWarpPriority, TcpWarpProto, TcpWarpServer,
+};
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio::{
+    net::{TcpListener, TcpStream, UdpSocket},
+    prelude::*,
+    spawn,
+    sync::broadcast,
+    time::{delay_for, timeout},
+};
+use tokio_util::codec::{Decoder, Encoder};
+
+fn mapping(target: std::net::SocketAddr) -> TcpWarpPortConnection {
+    format!("0:{}:{}", target.ip(), target.port())
+        .parse()
+        .unwrap()
+}
+
+#[tokio::test]
+async fn echo_through_tunnel() {
+    let target = echo_server().await.unwrap();
+    let mapping = mapping(target);
+    let tunnel = TestTunnel::start(vec![mapping.clone()]).await.unwrap();
+    let address = tunnel.mapped_address(&mapping).await.unwrap();
+
+    for len in &[1, 1000, 100_000, 1_000_000] {
+        let data = payload(*len);
+        assert_eq!(data, echo(address, &data).await.unwrap());
+    }
+
+    let echoes: Vec<_> = (0..10)
+        .map(|x| spawn(async move { echo(address, &payload(10_000 + x)).await }))
+        .collect();
+    for (x, echoed) in echoes.into_iter().enumerate() {
+        assert_eq!(payload(10_000 + x), echoed.await.unwrap().unwrap());
+    }
+}
+
+#[tokio::test]
+async fn disconnect_propagates() {
+    let echo_target = echo_server().await.unwrap();
+    let closing_target = closing_server().await.unwrap();
+    let echo_mapping = mapping(echo_target);
+    let closing_mapping = mapping(closing_target);
+    let tunnel = TestTunnel::start(vec![echo_mapping.clone(), closing_mapping.clone()])
+        .await
+        .unwrap();
+
+    // client closes its side, target closes in response and close comes back
+    let address = tunnel.mapped_address(&echo_mapping).await.unwrap();
+    let mut stream = TcpStream::connect(address).await.unwrap();
+    stream.write_all(b"ping").await.unwrap();
+    let mut received = [0; 4];
+    stream.read_exact(&mut received).await.unwrap();
+    assert_eq!(b"ping", &received);
+    stream.shutdown(std::net::Shutdown::Write).unwrap();
+    assert!(read_to_close(&mut stream).await.unwrap().is_empty());
+
+    // target closes connection first
+    let address = tunnel.mapped_address(&closing_mapping).await.unwrap();
+    let mut stream = TcpStream::connect(address).await.unwrap();
+    assert!(read_to_close(&mut stream).await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn connect_failure_closes_connection() {
+    let echo_target = echo_server().await.unwrap();
+    let unused = unused_address().await.unwrap();
+    let echo_mapping = mapping(echo_target);
+    let unused_mapping = mapping(unused);
+    let tunnel = TestTunnel::start(vec![echo_mapping.clone(), unused_mapping.clone()])
+        .await
+        .unwrap();
+
+    let address = tunnel.mapped_address(&unused_mapping).await.unwrap();
+    for _ in 0..3 {
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        assert!(read_to_close(&mut stream).await.unwrap().is_empty());
+    }
+
+    // failed connections do not affect tunnel
+    let address = tunnel.mapped_address(&echo_mapping).await.unwrap();
+    assert_eq!(payload(1000), echo(address, &payload(1000)).await.unwrap());
+}
+
+/// Reads frame of baseline protocol, returns opcode and body.
+async fn read_baseline_frame(stream: &mut TcpStream) -> std::io::Result<(u8, Vec<u8>)> {
+    let mut opcode = [0; 1];
+    stream.read_exact(&mut opcode).await?;
+    let mut body = match opcode[0] {
+        1 => vec![0; 2],
+        2 => vec![0; 2 + 16 + 2],
+        3 | 4 => vec![0; 16 + 4],
+        5..=8 => vec![0; 16],
+        opcode => panic!("opcode {} is unknown to baseline peers", opcode),
+    };
+    stream.read_exact(&mut body).await?;
+    let len = match opcode[0] {
+        1 => u16::from_be_bytes([body[0], body[1]]) as usize * 2,
+        2 => u16::from_be_bytes([body[0], body[1]]) as usize,
+        3 | 4 => u32::from_be_bytes([body[16], body[17], body[18], body[19]]) as usize,
+        _ => 0,
+    };
+    let mut data = vec![0; len];
+    stream.read_exact(&mut data).await?;
+    body.extend(data);
+    Ok((opcode[0], body))
+}
+
+#[tokio::test]
+async fn baseline_client_is_served() {
+    let target = echo_server().await.unwrap();
+    let server = TcpWarpServer::builder()
+        .with_listen_address("127.0.0.1:0".parse().unwrap())
+        .with_services(vec!["echo=8080".parse().unwrap()])
+        .build();
+    let (server, _server_task) = start_server(server).await.unwrap();
+    let mut stream = TcpStream::connect(server).await.unwrap();
+
+    let (opcode, _) = timeout(TIMEOUT, read_baseline_frame(&mut stream))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(1, opcode);
+
+    let id = 42u128.to_be_bytes();
+    let host = b"127.0.0.1";
+    let mut connect = vec![2];
+    connect.extend(&(host.len() as u16).to_be_bytes());
+    connect.extend(&id);
+    connect.extend(&target.port().to_be_bytes());
+    connect.extend(host);
+    stream.write_all(&connect).await.unwrap();
+    let (opcode, body) = timeout(TIMEOUT, read_baseline_frame(&mut stream))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!((5, &id[..]), (opcode, &body[..]));
+
+    let mut bytes = vec![3];
+    bytes.extend(&id);
+    bytes.extend(&4u32.to_be_bytes());
+    bytes.extend(b"ping");
+    stream.write_all(&bytes).await.unwrap();
+    let (opcode, body) = timeout(TIMEOUT, read_baseline_frame(&mut stream))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(4, opcode);
+    assert_eq!((&id[..], &b"ping"[..]), (&body[..16], &body[20..]));
+
+    let mut disconnect = vec![7];
+    disconnect.extend(&id);
+    stream.write_all(&disconnect).await.unwrap();
+    let (opcode, body) = timeout(TIMEOUT, read_baseline_frame(&mut stream))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!((6, &id[..]), (opcode, &body[..]));
+}
+
+#[tokio::test]
+async fn baseline_server_is_used() {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server = listener.local_addr().unwrap();
+    let mapping: TcpWarpPortConnection = "0:127.0.0.1:8080".parse().unwrap();
+    let client = Arc::new(TcpWarpClient::new(Ipv4Addr::LOCALHOST.into(), server));
+    let session_client = client.clone();
+    let session_mappings = vec![mapping.clone()];
+    spawn(async move {
+        session_client
+            .connect(session_mappings)
+            .await
+            .map_err(|err| err.to_string())
+    });
+
+    let (mut tunnel, _) = listener.accept().await.unwrap();
+    tunnel.write_all(&[1, 0, 0]).await.unwrap();
+    let address = mapped_address(&client, &mapping).await.unwrap();
+    let _stream = TcpStream::connect(address).await.unwrap();
+
+    let (opcode, body) = timeout(TIMEOUT, read_baseline_frame(&mut tunnel))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(2, opcode);
+    assert_eq!(8080, u16::from_be_bytes([body[18], body[19]]));
+    assert_eq!(b"127.0.0.1", &body[20..]);
+}
+
+#[tokio::test]
+async fn mapping_priority_reaches_server() {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server = listener.local_addr().unwrap();
+    let mapping: TcpWarpPortConnection = "0:127.0.0.1:8080,priority=bulk".parse().unwrap();
+    let client = Arc::new(TcpWarpClient::new(Ipv4Addr::LOCALHOST.into(), server));
+    let session_client = client.clone();
+    let session_mappings = vec![mapping.clone()];
+    spawn(async move {
+        session_client
+            .connect(session_mappings)
+            .await
+            .map_err(|err| err.to_string())
+    });
+
+    let (mut tunnel, _) = listener.accept().await.unwrap();
+    let mut codec = TcpWarpProto::new();
+    let mut buffer = BytesMut::new();
+    codec
+        .encode(
+            TcpWarpMessage::AddPorts(Some(TcpWarpHandshake {
+                capabilities: TcpWarpProto::CAPABILITIES,
+                services: vec![],
+            })),
+            &mut buffer,
+        )
+        .unwrap();
+    tunnel.write_all(&buffer).await.unwrap();
+    buffer.clear();
+    let address = mapped_address(&client, &mapping).await.unwrap();
+    let _stream = TcpStream::connect(address).await.unwrap();
+
+    let priority = timeout(TIMEOUT, async {
+        loop {
+            match codec.decode(&mut buffer).unwrap() {
+                Some(TcpWarpMessage::HostConnect { priority, .. }) => return priority,
+                Some(_) => continue,
+                None => {
+                    let mut received = [0; 1024];
+                    let len = tunnel.read(&mut received).await.unwrap();
+                    assert!(len > 0, "tunnel is closed");
+                    buffer.extend_from_slice(&received[..len]);
+                }
+            }
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(TcpWarpPriority::Bulk, priority);
+}
+
+async fn udp_echo(socket: &mut UdpSocket, data: &[u8]) -> std::io::Result<Vec<u8>> {
+    socket.send(data).await?;
+    let mut received = vec![0; data.len() + 1];
+    let len = timeout(TIMEOUT, socket.recv(&mut received)).await??;
+    received.truncate(len);
+    Ok(received)
+}
+
+#[tokio::test]
+async fn datagrams_through_tunnel() {
+    let target = udp_echo_server().await.unwrap();
+    let mapping: TcpWarpPortConnection = format!("udp:0:{}:{}", target.ip(), target.port())
+        .parse()
+        .unwrap();
+    let server = TcpWarpServer::builder()
+        .with_listen_address("127.0.0.1:0".parse().unwrap())
+        .with_udp_idle_timeout(Duration::from_millis(200))
+        .build();
+    let tunnel = TestTunnel::start_with(
+        server,
+        |server| TcpWarpClient::new(Ipv4Addr::LOCALHOST.into(), server),
+        vec![mapping.clone()],
+    )
+    .await
+    .unwrap();
+    let address = tunnel.mapped_address(&mapping).await.unwrap();
+
+    // every client address gets its own association
+    let mut first = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let mut second = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    first.connect(address).await.unwrap();
+    second.connect(address).await.unwrap();
+    for len in &[1, 1000, 10_000] {
+        assert_eq!(
+            payload(*len),
+            udp_echo(&mut first, &payload(*len)).await.unwrap()
+        );
+        assert_eq!(
+            b"second",
+            &udp_echo(&mut second, b"second").await.unwrap()[..]
+        );
+    }
+
+    // idle association is closed on both sides and opened again by the next datagram
+    delay_for(Duration::from_millis(500)).await;
+    assert_eq!(b"again", &udp_echo(&mut first, b"again").await.unwrap()[..]);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn unix_sockets_through_tunnel() {
+    let dir = std::env::temp_dir().join(format!("tcp-warp-unix-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let allowed = dir.join("allowed");
+    std::fs::create_dir_all(&allowed).unwrap();
+    let (target, forbidden) = (allowed.join("echo.sock"), dir.join("echo.sock"));
+    unix_echo_server(&target).unwrap();
+    unix_echo_server(&forbidden).unwrap();
+    let client_path = dir.join("client.sock");
+    let parse = |mapping: String| mapping.parse::<TcpWarpPortConnection>().unwrap();
+    let tcp_mapping = parse(format!("0:{}", target.display()));
+    let unix_mapping = parse(format!("{}:{}", client_path.display(), target.display()));
+    let forbidden_mapping = parse(format!("0:{}", forbidden.display()));
+    let server = TcpWarpServer::builder()
+        .with_listen_address("127.0.0.1:0".parse().unwrap())
+        .with_unix_socket_paths(vec![allowed.clone()])
+        .build();
+    let tunnel = TestTunnel::start_with(
+        server,
+        |server| TcpWarpClient::new(Ipv4Addr::LOCALHOST.into(), server),
+        vec![tcp_mapping.clone(), unix_mapping, forbidden_mapping.clone()],
+    )
+    .await
+    .unwrap();
+
+    // TCP listener forwards to unix socket target
+    let address = tunnel.mapped_address(&tcp_mapping).await.unwrap();
+    assert_eq!(payload(1000), echo(address, &payload(1000)).await.unwrap());
+
+    // unix socket listener forwards to unix socket target
+    let mut stream = None;
+    for _ in 0..50 {
+        if let Ok(connected) = UnixStream::connect(&client_path).await {
+            stream = Some(connected);
+            break;
+        }
+        delay_for(Duration::from_millis(100)).await;
+    }
+    let mut stream = stream.unwrap();
+    stream.write_all(b"ping").await.unwrap();
+    let mut received = [0; 4];
+    timeout(TIMEOUT, stream.read_exact(&mut received))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(b"ping", &received);
+
+    // sockets outside of allowed paths are refused
+    let mut events = tunnel.client().events();
+    let address = tunnel.mapped_address(&forbidden_mapping).await.unwrap();
+    let mut stream = TcpStream::connect(address).await.unwrap();
+    assert!(read_to_close(&mut stream).await.unwrap().is_empty());
+    loop {
+        if let TcpWarpEvent::ConnectionFailed { error, .. } = next_event(&mut events).await {
+            assert_eq!(TcpWarpConnectFailure::Forbidden.to_string(), error);
+            break;
+        }
+    }
+
+    drop(tunnel);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn failed_listener_is_retried() {
+    let target = echo_server().await.unwrap();
+    let blocker = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = blocker.local_addr().unwrap().port();
+    let blocked: TcpWarpPortConnection = format!("{}:{}:{}", port, target.ip(), target.port())
+        .parse()
+        .unwrap();
+    let serving = mapping(target);
+    let tunnel = TestTunnel::start_with(
+        TcpWarpServer::new("127.0.0.1:0".parse().unwrap(), Ipv4Addr::LOCALHOST.into()),
+        |server| {
+            TcpWarpClient::builder()
+                .with_bind_address(Ipv4Addr::LOCALHOST.into())
+                .with_tunnel_address(server)
+                .with_listener_retry_delay(Duration::from_millis(100))
+                .build()
+        },
+        vec![blocked.clone(), serving.clone()],
+    )
+    .await
+    .unwrap();
+
+    // bind keeps failing in background while other mapping serves
+    let mut mappings = tunnel.client().mappings();
+    timeout(TIMEOUT, async {
+        loop {
+            if let Some(TcpWarpMappingState::Failed { attempts, .. }) =
+                mappings.borrow().get(&blocked)
+            {
+                if *attempts > 1 {
+                    break;
+                }
+            }
+            mappings.recv().await.unwrap();
+        }
+    })
+    .await
+    .unwrap();
+    let address = tunnel.mapped_address(&serving).await.unwrap();
+    assert_eq!(payload(1000), echo(address, &payload(1000)).await.unwrap());
+
+    // port is bound once it is free
+    drop(blocker);
+    let address = tunnel.mapped_address(&blocked).await.unwrap();
+    assert_eq!(port, address.port());
+    assert_eq!(payload(1000), echo(address, &payload(1000)).await.unwrap());
+}
+
+fn spawn_client(client: &Arc<TcpWarpClient>, mapping: &TcpWarpPortConnection) {
+    let client = client.clone();
+    let mappings = Arc::new(vec![mapping.clone()]);
+    spawn(async move {
+        client
+            .connect_loop(Duration::from_millis(100), false, mappings)
+            .await
+            .map_err(|err| err.to_string())
+    });
+}
+
+#[tokio::test]
+async fn connect_loop_waits_for_server() {
+    let target = echo_server().await.unwrap();
+    let mapping = mapping(target);
+    let server = unused_address().await.unwrap();
+    let client = Arc::new(TcpWarpClient::new(Ipv4Addr::LOCALHOST.into(), server));
+    spawn_client(&client, &mapping);
+
+    delay_for(Duration::from_millis(300)).await;
+    let listener = TcpListener::bind(server).await.unwrap();
+    spawn(async move {
+        TcpWarpServer::new(server, Ipv4Addr::LOCALHOST.into())
+            .listen_on(listener)
+            .await
+            .map_err(|err| err.to_string())
+    });
+
+    let address = mapped_address(&client, &mapping).await.unwrap();
+    assert_eq!(payload(1000), echo(address, &payload(1000)).await.unwrap());
+}
+
+#[tokio::test]
+async fn lost_tunnel_is_reopened() {
+    let target = echo_server().await.unwrap();
+    let mapping = mapping(target);
+    let (server, _server_task) = start_server(TcpWarpServer::new(
+        "127.0.0.1:0".parse().unwrap(),
+        Ipv4Addr::LOCALHOST.into(),
+    ))
+    .await
+    .unwrap();
+    let relay = TestRelay::start(server).await.unwrap();
+    let client = Arc::new(TcpWarpClient::new(
+        Ipv4Addr::LOCALHOST.into(),
+        relay.address(),
+    ));
+    spawn_client(&client, &mapping);
+
+    let address = mapped_address(&client, &mapping).await.unwrap();
+    let mut stream = TcpStream::connect(address).await.unwrap();
+    stream.write_all(b"ping").await.unwrap();
+    let mut received = [0; 4];
+    stream.read_exact(&mut received).await.unwrap();
+    assert_eq!(b"ping", &received);
+
+    // connections of lost tunnel are closed
+    relay.reset();
+    read_to_close(&mut stream).await.unwrap();
+
+    let mut echoed = None;
+    for _ in 0..50 {
+        if let Ok(data) = echo(address, &payload(1000)).await {
+            echoed = Some(data);
+            break;
+        }
+        delay_for(Duration::from_millis(100)).await;
+    }
+    assert_eq!(Some(payload(1000)), echoed);
+}
+
+#[tokio::test]
+async fn connections_are_striped_across_tunnels() {
+    let target = echo_server().await.unwrap();
+    let mapping = mapping(target);
+    let server = TcpWarpServer::builder()
+        .with_listen_address("127.0.0.1:0".parse().unwrap())
+        .with_max_tunnel_connections(Some(1))
+        .build();
+    let tunnel = TestTunnel::start_with(
+        server,
+        |server| {
+            TcpWarpClient::builder()
+                .with_bind_address(Ipv4Addr::LOCALHOST.into())
+                .with_tunnel_address(server)
+                .with_tunnels(3)
+                .build()
+        },
+        vec![mapping.clone()],
+    )
+    .await
+    .unwrap();
+    let mut events = tunnel.client().events();
+    let mut connected = 0;
+    while connected < 3 {
+        if let TcpWarpEvent::TunnelConnected { .. } = next_event(&mut events).await {
+            connected += 1;
+        }
+    }
+    let address = tunnel.mapped_address(&mapping).await.unwrap();
+
+    // each tunnel takes one connection, so all of them are used
+    let mut streams = vec![];
+    for _ in 0..3 {
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        ping(&mut stream).await.unwrap();
+        streams.push(stream);
+    }
+    let mut rejected = TcpStream::connect(address).await.unwrap();
+    assert!(read_to_close(&mut rejected).await.unwrap().is_empty());
+}
+
+async fn next_event(events: &mut broadcast::Receiver<TcpWarpEvent>) -> TcpWarpEvent {
+    timeout(TIMEOUT, events.recv()).await.unwrap().unwrap()
+}
+
+#[tokio::test]
+async fn events_are_published() {
+    let target = echo_server().await.unwrap();
+    let mapping = mapping(target);
+    let server = TcpWarpServer::new("127.0.0.1:0".parse().unwrap(), Ipv4Addr::LOCALHOST.into());
+    let mut server_events = server.events();
+    let (server, _server_task) = start_server(server).await.unwrap();
+    let client = Arc::new(TcpWarpClient::new(Ipv4Addr::LOCALHOST.into(), server));
+    let mut client_events = client.events();
+    spawn_client(&client, &mapping);
+
+    assert_eq!(
+        TcpWarpEvent::TunnelConnected {
+            tunnel: 0,
+            peer: server
+        },
+        next_event(&mut client_events).await
+    );
+    let address = mapped_address(&client, &mapping).await.unwrap();
+    assert_eq!(
+        TcpWarpEvent::ListenerBound {
+            mapping: mapping.clone(),
+            address: Some(address)
+        },
+        next_event(&mut client_events).await
+    );
+    match next_event(&mut server_events).await {
+        TcpWarpEvent::TunnelConnected { peer, .. } => assert!(peer.ip().is_loopback()),
+        other => panic!("unexpected event: {:?}", other),
+    }
+
+    assert_eq!(payload(1000), echo(address, &payload(1000)).await.unwrap());
+
+    let connection_id = match next_event(&mut client_events).await {
+        TcpWarpEvent::ConnectionOpened {
+            connection_id,
+            target: opened,
+        } => {
+            assert_eq!(mapping.to_string(), opened);
+            connection_id
+        }
+        other => panic!("unexpected event: {:?}", other),
+    };
+    assert_eq!(
+        TcpWarpEvent::ConnectionConnected { connection_id },
+        next_event(&mut client_events).await
+    );
+    assert_eq!(
+        TcpWarpEvent::ConnectionClosed {
+            connection_id,
+            bytes_sent: 1000,
+            bytes_received: 1000
+        },
+        next_event(&mut client_events).await
+    );
+
+    assert_eq!(
+        TcpWarpEvent::ConnectionOpened {
+            connection_id,
+            target: target.to_string()
+        },
+        next_event(&mut server_events).await
+    );
+    assert_eq!(
+        TcpWarpEvent::ConnectionConnected { connection_id },
+        next_event(&mut server_events).await
+    );
+    assert_eq!(
+        TcpWarpEvent::ConnectionClosed {
+            connection_id,
+            bytes_sent: 1000,
+            bytes_received: 1000
+        },
+        next_event(&mut server_events).await
+    );
+}
+
+#[tokio::test]
+async fn open_stream_through_tunnel() {
+    let target = echo_server().await.unwrap();
+    let unused = unused_address().await.unwrap();
+    let tunnel = TestTunnel::start(vec![]).await.unwrap();
+
+    let mut stream = None;
+    for _ in 0..50 {
+        if let Ok(opened) = tunnel
+            .client()
+            .open_stream("127.0.0.1", target.port())
+            .await
+        {
+            stream = Some(opened);
+            break;
+        }
+        delay_for(Duration::from_millis(100)).await;
+    }
+    let mut stream = stream.expect("session is not established");
+
+    let data = payload(100_000);
+    let mut received = vec![0; data.len()];
+    let (mut reader, mut writer) = tokio::io::split(&mut stream);
+    let (written, read) = futures::join!(writer.write_all(&data), reader.read_exact(&mut received));
+    written.unwrap();
+    read.unwrap();
+    assert_eq!(data, received);
+
+    // shutdown is propagated to target, which closes connection in response
+    stream.shutdown().await.unwrap();
+    let mut rest = vec![];
+    timeout(TIMEOUT, stream.read_to_end(&mut rest))
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(rest.is_empty());
+
+    assert!(tunnel
+        .client()
+        .open_stream("127.0.0.1", unused.port())
+        .await
+        .is_err());
+
+    let client = TcpWarpClient::new(Ipv4Addr::LOCALHOST.into(), tunnel.server());
+    match client.open_stream("127.0.0.1", target.port()).await {
+        Err(TcpWarpError::SessionClosed) => (),
+        other => panic!("unexpected result: {:?}", other.map(|x| x.connection_id())),
+    }
+}
+
+#[tokio::test]
+async fn connect_deadline_closes_connection() {
+    let target = echo_server().await.unwrap();
+    let mapping = mapping(target);
+    let (server, _server_task) = start_server(TcpWarpServer::new(
+        "127.0.0.1:0".parse().unwrap(),
+        Ipv4Addr::LOCALHOST.into(),
+    ))
+    .await
+    .unwrap();
+    let relay = TestRelay::start(server).await.unwrap();
+    let client = Arc::new(
+        TcpWarpClient::builder()
+            .with_bind_address(Ipv4Addr::LOCALHOST.into())
+            .with_tunnel_address(relay.address())
+            .with_connect_timeout(Duration::from_millis(300))
+            .build(),
+    );
+    spawn_client(&client, &mapping);
+
+    let address = mapped_address(&client, &mapping).await.unwrap();
+    assert_eq!(payload(1000), echo(address, &payload(1000)).await.unwrap());
+
+    // server does not answer in time, local connection is closed by client
+    let mut events = client.events();
+    relay.stall();
+    let mut stream = TcpStream::connect(address).await.unwrap();
+    assert!(read_to_close(&mut stream).await.unwrap().is_empty());
+    loop {
+        if let TcpWarpEvent::ConnectionFailed { error, .. } = next_event(&mut events).await {
+            assert_eq!(TcpWarpConnectFailure::TimedOut.to_string(), error);
+            break;
+        }
+    }
+
+    // late answer of server is dropped and tunnel keeps serving
+    relay.resume();
+    assert_eq!(payload(1000), echo(address, &payload(1000)).await.unwrap());
+}
+
+/// Waits until counter of metrics reaches expected value.
+async fn wait_for_metric<F>(metrics: &TcpWarpMetrics, expected: u64, counter: F)
+where
+    F: Fn(TcpWarpMetricsSnapshot) -> u64,
+{
+    for _ in 0..50 {
+        if counter(metrics.snapshot()) == expected {
+            return;
+        }
+        delay_for(Duration::from_millis(100)).await;
+    }
+    assert_eq!(expected, counter(metrics.snapshot()));
+}
+
+#[tokio::test]
+async fn expired_connections_are_closed() {
+    let target = echo_server().await.unwrap();
+    let idle_mapping: TcpWarpPortConnection = format!("0:{}:{},idle=1", target.ip(), target.port())
+        .parse()
+        .unwrap();
+    let server = TcpWarpServer::builder()
+        .with_listen_address("127.0.0.1:0".parse().unwrap())
+        .with_max_lifetime(Some(Duration::from_millis(300)))
+        .build();
+    let server_metrics = server.metrics();
+    let tunnel = TestTunnel::start_with(
+        server,
+        |server| TcpWarpClient::new(Ipv4Addr::LOCALHOST.into(), server),
+        vec![idle_mapping.clone()],
+    )
+    .await
+    .unwrap();
+    let address = tunnel.mapped_address(&idle_mapping).await.unwrap();
+
+    // server closes connection after max lifetime even while bytes flow
+    let mut stream = TcpStream::connect(address).await.unwrap();
+    let mut received = [0; 4];
+    for _ in 0..10 {
+        if stream.write_all(b"ping").await.is_err()
+            || stream.read(&mut received).await.unwrap() == 0
+        {
+            break;
+        }
+        delay_for(Duration::from_millis(100)).await;
+    }
+    read_to_close(&mut stream).await.unwrap();
+    wait_for_metric(&server_metrics, 1, |x| x.expired_lifetime).await;
+    drop(stream);
+
+    // client closes idle connection of mapping with idle option
+    let tunnel = TestTunnel::start(vec![idle_mapping.clone()]).await.unwrap();
+    let client_metrics = tunnel.client().metrics();
+    let address = tunnel.mapped_address(&idle_mapping).await.unwrap();
+    let mut stream = TcpStream::connect(address).await.unwrap();
+    stream.write_all(b"ping").await.unwrap();
+    stream.read_exact(&mut received).await.unwrap();
+    assert_eq!(b"ping", &received);
+    assert!(read_to_close(&mut stream).await.unwrap().is_empty());
+    wait_for_metric(&client_metrics, 1, |x| x.expired_idle).await;
+}
+
+async fn ping(stream: &mut TcpStream) -> std::io::Result<()> {
+    stream.write_all(b"ping").await?;
+    let mut received = [0; 4];
+    stream.read_exact(&mut received).await?;
+    assert_eq!(b"ping", &received);
+    Ok(())
+}
+
+#[tokio::test]
+async fn connection_limits_are_enforced() {
+    let target = echo_server().await.unwrap();
+    let mapping = mapping(target);
+    let server = TcpWarpServer::builder()
+        .with_listen_address("127.0.0.1:0".parse().unwrap())
+        .with_max_client_connections(Some(1))
+        .build();
+    let tunnel = TestTunnel::start_with(
+        server,
+        |server| TcpWarpClient::new(Ipv4Addr::LOCALHOST.into(), server),
+        vec![mapping.clone()],
+    )
+    .await
+    .unwrap();
+    let address = tunnel.mapped_address(&mapping).await.unwrap();
+
+    // server refuses connection over limit of client address
+    let mut events = tunnel.client().events();
+    let mut stream = TcpStream::connect(address).await.unwrap();
+    ping(&mut stream).await.unwrap();
+    let mut rejected = TcpStream::connect(address).await.unwrap();
+    assert!(read_to_close(&mut rejected).await.unwrap().is_empty());
+    loop {
+        if let TcpWarpEvent::ConnectionFailed { error, .. } = next_event(&mut events).await {
+            assert_eq!(TcpWarpConnectFailure::LimitExceeded.to_string(), error);
+            break;
+        }
+    }
+
+    // slot is released once connection is closed
+    drop(stream);
+    let mut echoed = None;
+    for _ in 0..50 {
+        if let Ok(data) = echo(address, &payload(1000)).await {
+            echoed = Some(data);
+            break;
+        }
+        delay_for(Duration::from_millis(100)).await;
+    }
+    assert_eq!(Some(payload(1000)), echoed);
+
+    // client rejects or queues connections over limit of mapping
+    let rejecting: TcpWarpPortConnection = format!("0:{}:{},max=1", target.ip(), target.port())
+        .parse()
+        .unwrap();
+    let queueing: TcpWarpPortConnection =
+        format!("0:{}:{},max=1,queue", target.ip(), target.port())
+            .parse()
+            .unwrap();
+    let tunnel = TestTunnel::start(vec![rejecting.clone(), queueing.clone()])
+        .await
+        .unwrap();
+
+    let address = tunnel.mapped_address(&rejecting).await.unwrap();
+    let mut stream = TcpStream::connect(address).await.unwrap();
+    ping(&mut stream).await.unwrap();
+    let mut rejected = TcpStream::connect(address).await.unwrap();
+    assert!(read_to_close(&mut rejected).await.unwrap().is_empty());
+    drop(stream);
+
+    let address = tunnel.mapped_address(&queueing).await.unwrap();
+    let mut stream = TcpStream::connect(address).await.unwrap();
+    ping(&mut stream).await.unwrap();
+    let mut queued = TcpStream::connect(address).await.unwrap();
+    assert!(timeout(Duration::from_millis(300), ping(&mut queued))
+        .await
+        .is_err());
+    drop(stream);
+    timeout(TIMEOUT, ping(&mut queued)).await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn target_close_releases_connection_slot() {
+    let echo_target = echo_server().await.unwrap();
+    let closing_target = closing_server().await.unwrap();
+    let (echo_mapping, closing_mapping) = (mapping(echo_target), mapping(closing_target));
+    let server = TcpWarpServer::builder()
+        .with_listen_address("127.0.0.1:0".parse().unwrap())
+        .with_max_client_connections(Some(1))
+        .build();
+    let tunnel = TestTunnel::start_with(
+        server,
+        |server| TcpWarpClient::new(Ipv4Addr::LOCALHOST.into(), server),
+        vec![echo_mapping.clone(), closing_mapping.clone()],
+    )
+    .await
+    .unwrap();
+
+    // target closes first, client close still reaches server and frees the slot
+    let address = tunnel.mapped_address(&closing_mapping).await.unwrap();
+    for _ in 0..3 {
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        assert!(read_to_close(&mut stream).await.unwrap().is_empty());
+    }
+
+    let address = tunnel.mapped_address(&echo_mapping).await.unwrap();
+    let mut echoed = None;
+    for _ in 0..50 {
+        if let Ok(data) = echo(address, &payload(1000)).await {
+            echoed = Some(data);
+            break;
+        }
+        delay_for(Duration::from_millis(100)).await;
+    }
+    assert_eq!(Some(payload(1000)), echoed);
+}
+
+#[tokio::test]
+async fn rate_limits_throttle_throughput() {
+    let target = echo_server().await.unwrap();
+
+    // mapping limits upload, burst of one second passes at once
+    let limited: TcpWarpPortConnection = format!("0:{}:{},rate=200000", target.ip(), target.port())
+        .parse()
+        .unwrap();
+    let tunnel = TestTunnel::start(vec![limited.clone()]).await.unwrap();
+    let address = tunnel.mapped_address(&limited).await.unwrap();
+    let started = std::time::Instant::now();
+    assert_eq!(
+        payload(400_000),
+        echo(address, &payload(400_000)).await.unwrap()
+    );
+    assert!(started.elapsed() >= Duration::from_millis(700));
+
+    // mapping limits download as well
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let sending: TcpWarpPortConnection = format!(
+        "0:127.0.0.1:{},rate=200000",
+        listener.local_addr().unwrap().port()
+    )
+    .parse()
+    .unwrap();
+    spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        stream.write_all(&payload(400_000)).await.unwrap();
+    });
+    let tunnel = TestTunnel::start(vec![sending.clone()]).await.unwrap();
+    let address = tunnel.mapped_address(&sending).await.unwrap();
+    let started = std::time::Instant::now();
+    let mut stream = TcpStream::connect(address).await.unwrap();
+    assert_eq!(payload(400_000), read_to_close(&mut stream).await.unwrap());
+    assert!(started.elapsed() >= Duration::from_millis(700));
+
+    // server limits tunnel in both directions
+    let mapping = mapping(target);
+    let server = TcpWarpServer::builder()
+        .with_listen_address("127.0.0.1:0".parse().unwrap())
+        .with_tunnel_rate(Some(200_000))
+        .build();
+    let tunnel = TestTunnel::start_with(
+        server,
+        |server| TcpWarpClient::new(Ipv4Addr::LOCALHOST.into(), server),
+        vec![mapping.clone()],
+    )
+    .await
+    .unwrap();
+    let address = tunnel.mapped_address(&mapping).await.unwrap();
+    let started = std::time::Instant::now();
+    assert_eq!(
+        payload(400_000),
+        echo(address, &payload(400_000)).await.unwrap()
+    );
+    assert!(started.elapsed() >= Duration::from_millis(700));
+}
+
+#[tokio::test]
+async fn audit_log_records_connections() {
+    let target = echo_server().await.unwrap();
+    let unused = unused_address().await.unwrap();
+    let (echo_mapping, unused_mapping) = (mapping(target), mapping(unused));
+    let path = std::env::temp_dir().join(format!("tcp-warp-audit-{}.jsonl", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let server = TcpWarpServer::builder()
+        .with_listen_address("127.0.0.1:0".parse().unwrap())
+        .with_audit_log(Some(TcpWarpAuditLog::File(path.clone())))
+        .build();
+    let tunnel = TestTunnel::start_with(
+        server,
+        |server| TcpWarpClient::new(Ipv4Addr::LOCALHOST.into(), server),
+        vec![echo_mapping.clone(), unused_mapping.clone()],
+    )
+    .await
+    .unwrap();
+
+    let address = tunnel.mapped_address(&echo_mapping).await.unwrap();
+    assert_eq!(payload(1000), echo(address, &payload(1000)).await.unwrap());
+    let address = tunnel.mapped_address(&unused_mapping).await.unwrap();
+    let mut stream = TcpStream::connect(address).await.unwrap();
+    assert!(read_to_close(&mut stream).await.unwrap().is_empty());
+
+    let mut records = vec![];
+    for _ in 0..50 {
+        records = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(str::to_owned)
+            .collect();
+        if records.len() == 2 {
+            break;
+        }
+        delay_for(Duration::from_millis(100)).await;
+    }
+    std::fs::remove_file(&path).unwrap();
+    let record = |target: std::net::SocketAddr| {
+        records
+            .iter()
+            .find(|record| record.contains(&format!(r#""target":"{}""#, target)))
+            .unwrap()
+    };
+    let connected = record(target);
+    assert!(connected.contains(&format!(
+        r#""resolved":"{}","outcome":"connected","reason":null"#,
+        target.ip()
+    )));
+    assert!(connected.contains(r#""bytes_client_to_host":1000,"bytes_host_to_client":1000}"#));
+    let failed = record(unused);
+    assert!(failed.contains(&format!(
+        r#""resolved":null,"outcome":"failed","reason":"{}""#,
+        TcpWarpConnectFailure::Refused
+    )));
+    assert_eq!(2, records.len());
+}
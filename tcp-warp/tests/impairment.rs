@@ -0,0 +1,170 @@
+use std::{net::Ipv4Addr, sync::Arc, time::Duration};
+use tcpwarp::{
+    test_support::*, TcpWarpClient, TcpWarpCompression, TcpWarpPortConnection, TcpWarpServer,
+};
+use tokio::{
+    net::TcpStream,
+    prelude::*,
+    spawn,
+    time::{delay_for, timeout, Instant},
+};
+
+fn mapping(target: std::net::SocketAddr) -> TcpWarpPortConnection {
+    format!("0:{}:{}", target.ip(), target.port())
+        .parse()
+        .unwrap()
+}
+
+/// Echo target reachable through client, server and relay with given impairments.
+struct Setup {
+    relay: TestRelay,
+    client: Arc<TcpWarpClient>,
+    mapping: TcpWarpPortConnection,
+}
+
+impl Setup {
+    async fn start(
+        impairment: TestImpairment,
+        compression: TcpWarpCompression,
+        keep_connections: bool,
+    ) -> Self {
+        let mapping = mapping(echo_server().await.unwrap());
+        let (server, _) = start_server(TcpWarpServer::new(
+            "127.0.0.1:0".parse().unwrap(),
+            Ipv4Addr::LOCALHOST.into(),
+        ))
+        .await
+        .unwrap();
+        let relay = TestRelay::start_with(server, impairment).await.unwrap();
+        let client = Arc::new(
+            TcpWarpClient::builder()
+                .with_bind_address(Ipv4Addr::LOCALHOST.into())
+                .with_tunnel_address(relay.address())
+                .with_compression(compression)
+                .build(),
+        );
+        let session_client = client.clone();
+        let mappings = Arc::new(vec![mapping.clone()]);
+        spawn(async move {
+            session_client
+                .connect_loop(Duration::from_millis(100), keep_connections, mappings)
+                .await
+                .map_err(|err| err.to_string())
+        });
+        Self {
+            relay,
+            client,
+            mapping,
+        }
+    }
+
+    async fn address(&self) -> std::net::SocketAddr {
+        mapped_address(&self.client, &self.mapping).await.unwrap()
+    }
+
+    /// Echoes data, retrying while tunnel is being reopened.
+    async fn echo_eventually(&self, data: &[u8]) -> Vec<u8> {
+        for _ in 0..50 {
+            if let Ok(echoed) = echo(self.address().await, data).await {
+                return echoed;
+            }
+            delay_for(Duration::from_millis(100)).await;
+        }
+        panic!("tunnel is not restored");
+    }
+}
+
+#[tokio::test]
+async fn fragmented_frames() {
+    for compression in &[
+        TcpWarpCompression::None,
+        TcpWarpCompression::Deflate,
+        TcpWarpCompression::Zstd,
+    ] {
+        let setup = Setup::start(
+            TestImpairment {
+                max_write: Some(3),
+                ..Default::default()
+            },
+            *compression,
+            false,
+        )
+        .await;
+        let address = setup.address().await;
+        for len in &[1, 7, 20_000] {
+            let data = payload(*len);
+            assert_eq!(data, echo(address, &data).await.unwrap(), "{}", compression);
+        }
+    }
+}
+
+#[tokio::test]
+async fn latency_and_bandwidth() {
+    let latency = Duration::from_millis(50);
+    let setup = Setup::start(
+        TestImpairment {
+            latency,
+            bandwidth: Some(1024 * 1024),
+            max_write: Some(1400),
+        },
+        TcpWarpCompression::None,
+        false,
+    )
+    .await;
+    let address = setup.address().await;
+
+    let started = Instant::now();
+    let data = payload(200_000);
+    assert_eq!(data, echo(address, &data).await.unwrap());
+    // connect and data round trips, sending 200KB at 1MB/s takes about 190ms
+    let elapsed = started.elapsed();
+    assert!(
+        elapsed >= latency * 4 + Duration::from_millis(150),
+        "{:?}",
+        elapsed
+    );
+}
+
+#[tokio::test]
+async fn stalled_traffic_resumes() {
+    let setup = Setup::start(TestImpairment::default(), TcpWarpCompression::None, false).await;
+    let address = setup.address().await;
+    assert_eq!(payload(100), echo(address, &payload(100)).await.unwrap());
+
+    setup.relay.stall();
+    let mut echoed = spawn(async move { echo(address, &payload(50_000)).await });
+    assert!(timeout(Duration::from_millis(300), &mut echoed)
+        .await
+        .is_err());
+
+    setup.relay.resume();
+    assert_eq!(payload(50_000), echoed.await.unwrap().unwrap());
+}
+
+#[tokio::test]
+async fn reset_with_keep_connections() {
+    let setup = Setup::start(
+        TestImpairment {
+            latency: Duration::from_millis(20),
+            ..Default::default()
+        },
+        TcpWarpCompression::None,
+        true,
+    )
+    .await;
+    let address = setup.address().await;
+
+    for _ in 0..3 {
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream.write_all(b"ping").await.unwrap();
+        let mut received = [0; 4];
+        stream.read_exact(&mut received).await.unwrap();
+
+        setup.relay.reset();
+        read_to_close(&mut stream).await.unwrap();
+
+        // client port chosen by OS is kept after reconnect
+        assert_eq!(payload(1000), setup.echo_eventually(&payload(1000)).await);
+        assert_eq!(address, setup.address().await);
+    }
+}